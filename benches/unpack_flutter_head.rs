@@ -1,34 +1,49 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, Criterion};
 use anni_fetch::{Client, Pack};
 use anni_fetch::client::Message::PackData;
+use anni_fetch::client::RequestBuilder;
+use anni_fetch::packfile::{PackFile, PackFileOptions};
 use std::io::Cursor;
 
-fn unpack() {
+fn fetch() -> Vec<u8> {
     let client = Client::new("https://github.com/flutter/flutter.git");
-    let iter = client.request("fetch", None, &[
-        "thin-pack",
-        "ofs-delta",
-        "deepen 1",
-        &client.want_ref("HEAD").expect("failed to get sha1 of HEAD"),
-        "done"
-    ]).unwrap();
+    let mut iter = client.request(
+        RequestBuilder::new(true)
+            .command("fetch")
+            .argument("thin-pack")
+            .argument("ofs-delta")
+            .argument("deepen 1")
+            .want(&client.ls_ref("HEAD").expect("failed to get sha1 of HEAD"))
+            .argument("done")
+            .build()
+    ).unwrap();
     let mut pack = Vec::new();
-    for msg in iter {
-        match msg {
-            PackData(mut d) => pack.append(&mut d),
-            _ => {}
+    for msg in &mut iter {
+        if let PackData(mut d) = msg {
+            pack.append(&mut d);
         }
     }
-    let mut cursor = Cursor::new(pack);
+    iter.finish().expect("response cut off");
+    pack
+}
+
+fn unpack() {
+    let mut cursor = Cursor::new(fetch());
     Pack::from_reader(&mut cursor).expect("invalid pack file");
 }
 
+fn unpack_bounded() {
+    let options = PackFileOptions { cache_size: 16 * 1024 * 1024, ..Default::default() };
+    PackFile::from_stream(&mut fetch().as_slice(), options).expect("invalid pack file");
+}
+
 fn bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("unpack");
     group.significance_level(0.1).sample_size(10);
-    group.bench_function("unpack", |b| b.iter(|| unpack()));
+    group.bench_function("unpack", |b| b.iter(unpack));
+    group.bench_function("unpack_bounded", |b| b.iter(unpack_bounded));
     group.finish();
 }
 
 criterion_group!(benches, bench);
-criterion_main!(benches);
\ No newline at end of file
+criterion_main!(benches);
@@ -0,0 +1,413 @@
+//! Read and write bundle files, which carry refs and a pack for offline transfer.
+//!
+//! https://git-scm.com/docs/gitformat-bundle
+
+use std::io::{BufReader, Read, Seek, Write};
+use std::path::Path;
+use thiserror::Error;
+use crate::io::u8;
+use crate::oid::{HashAlgorithm, ObjectId};
+use crate::pack::{PackWriter, UnpackError};
+use crate::utils::{hex, unhex_with};
+use crate::Pack;
+
+#[derive(Debug, Error)]
+pub enum BundleError {
+    #[error("invalid bundle signature")]
+    InvalidSignature,
+    #[error("invalid bundle header line: {0}")]
+    InvalidHeader(String),
+    #[error("unsupported bundle capability {0}")]
+    UnsupportedCapability(String),
+    #[error("invalid bundle list: {0}")]
+    InvalidList(String),
+
+    #[error(transparent)]
+    UnpackError(#[from] UnpackError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// Header of a bundle, before the pack
+///
+/// ```text
+/// bundle       = signature *capability *prerequisite *reference LF pack
+/// signature    = "# v2 git bundle" LF / "# v3 git bundle" LF
+/// capability   = "@" key ["=" value] LF
+/// prerequisite = "-" obj-id SP comment LF
+/// reference    = obj-id SP refname LF
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleHeader {
+    /// 2 or 3, only v3 has capabilities
+    pub version: u8,
+    /// `(key, value)` of capabilities, like `object-format=sha1`
+    pub capabilities: Vec<(String, Option<String>)>,
+    /// `(id, comment)` of commits the receiver must already have
+    pub prerequisites: Vec<(ObjectId, String)>,
+    /// `(name, id)` of refs in the bundle
+    pub refs: Vec<(String, ObjectId)>,
+}
+
+impl Default for BundleHeader {
+    fn default() -> Self {
+        Self {
+            version: 2,
+            capabilities: Vec::new(),
+            prerequisites: Vec::new(),
+            refs: Vec::new(),
+        }
+    }
+}
+
+impl BundleHeader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add ref `name` pointing to `id`
+    pub fn reference(mut self, name: &str, id: ObjectId) -> Self {
+        self.refs.push((name.to_owned(), id));
+        self
+    }
+
+    /// Require the receiver to have commit `id`, usually the subject of it as `comment`
+    pub fn prerequisite(mut self, id: ObjectId, comment: &str) -> Self {
+        self.prerequisites.push((id, comment.to_owned()));
+        self
+    }
+
+    /// Add a capability, which makes it a v3 bundle
+    pub fn capability(mut self, key: &str, value: Option<&str>) -> Self {
+        self.version = 3;
+        self.capabilities.push((key.to_owned(), value.map(|v| v.to_owned())));
+        self
+    }
+
+    /// Value of capability `object-format`, which defaults to `sha1`
+    pub fn object_format(&self) -> &str {
+        self.capabilities.iter()
+            .find(|(k, _)| k == "object-format")
+            .and_then(|(_, v)| v.as_deref())
+            .unwrap_or("sha1")
+    }
+
+    /// Hash algorithm of the pack, if all capabilities are supported
+    pub fn algorithm(&self) -> Result<HashAlgorithm, BundleError> {
+        for (key, value) in self.capabilities.iter() {
+            match (key.as_str(), value.as_deref()) {
+                ("object-format", Some(format)) if HashAlgorithm::from_name(format).is_some() => {}
+                ("filter", Some(_)) => {}
+                (key, Some(value)) => return Err(BundleError::UnsupportedCapability(format!("{}={}", key, value))),
+                (key, None) => return Err(BundleError::UnsupportedCapability(key.to_owned())),
+            }
+        }
+        Ok(HashAlgorithm::from_name(self.object_format()).unwrap())
+    }
+
+    /// Parse hex object id `id` of the object format of the bundle
+    fn parse_id(&self, id: &str) -> Option<ObjectId> {
+        HashAlgorithm::from_name(self.object_format()).and_then(|algorithm| unhex_with(id, algorithm))
+    }
+
+    /// Read a header, leaving `reader` at the start of the pack
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, BundleError> {
+        let version = match read_line(reader)?.as_deref() {
+            Some("# v2 git bundle") => 2,
+            Some("# v3 git bundle") => 3,
+            _ => return Err(BundleError::InvalidSignature),
+        };
+
+        let mut header = Self { version, ..Default::default() };
+        loop {
+            let line = read_line(reader)?.ok_or_else(|| BundleError::InvalidHeader("unexpected end of header".to_owned()))?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some(capability) = line.strip_prefix('@') {
+                if version == 2 || !header.prerequisites.is_empty() || !header.refs.is_empty() {
+                    return Err(BundleError::InvalidHeader(line));
+                }
+                let (key, value) = match capability.split_once('=') {
+                    Some((key, value)) => (key, Some(value.to_owned())),
+                    None => (capability, None),
+                };
+                header.capabilities.push((key.to_owned(), value));
+            } else if let Some(prerequisite) = line.strip_prefix('-') {
+                let (id, comment) = prerequisite.split_once(' ').unwrap_or((prerequisite, ""));
+                match header.parse_id(id) {
+                    Some(id) if header.refs.is_empty() => header.prerequisites.push((id, comment.to_owned())),
+                    _ => return Err(BundleError::InvalidHeader(line)),
+                }
+            } else {
+                match line.split_once(' ').and_then(|(id, name)| Some((header.parse_id(id)?, name))) {
+                    Some((id, name)) => header.refs.push((name.to_owned(), id)),
+                    _ => return Err(BundleError::InvalidHeader(line)),
+                }
+            }
+        }
+        Ok(header)
+    }
+
+    /// Write the header, including the empty line before the pack
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let version = if self.capabilities.is_empty() { self.version } else { 3 };
+        writeln!(writer, "# v{} git bundle", version)?;
+        for (key, value) in self.capabilities.iter() {
+            match value {
+                Some(value) => writeln!(writer, "@{}={}", key, value)?,
+                None => writeln!(writer, "@{}", key)?,
+            }
+        }
+        for (id, comment) in self.prerequisites.iter() {
+            writeln!(writer, "-{} {}", hex(id), comment)?;
+        }
+        for (name, id) in self.refs.iter() {
+            writeln!(writer, "{} {}", hex(id), name)?;
+        }
+        writeln!(writer)
+    }
+}
+
+/// Read a line without LF, or `None` at EOF
+fn read_line<R: Read>(reader: &mut R) -> std::io::Result<Option<String>> {
+    let mut line = Vec::new();
+    loop {
+        match u8(reader) {
+            Ok(b'\n') => break,
+            Ok(b) => line.push(b),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && line.is_empty() => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// A bundle read into memory
+///
+/// With prerequisites, the pack is usually thin: deltas against objects
+/// of the prerequisites stay in [Pack::unresolved] until [Pack::resolve_deltas].
+pub struct Bundle {
+    pub header: BundleHeader,
+    pub pack: Pack,
+}
+
+impl Bundle {
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self, BundleError> {
+        let header = BundleHeader::read(reader)?;
+        let algorithm = header.algorithm()?;
+        let pack = Pack::from_reader_with(reader, algorithm)?;
+        Ok(Self { header, pack })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, BundleError> {
+        let mut reader = BufReader::new(std::fs::File::open(path)?);
+        Self::from_reader(&mut reader)
+    }
+}
+
+/// Write a bundle of `header` followed by objects of `pack`, and return the pack checksum
+pub fn write_bundle<W: Write>(writer: &mut W, header: &BundleHeader, pack: &PackWriter) -> std::io::Result<ObjectId> {
+    header.write(writer)?;
+    pack.write(writer)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleMode {
+    /// All bundles are needed, applied in order
+    All,
+    /// Any single bundle is enough
+    Any,
+}
+
+/// A bundle of a [BundleList]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleUri {
+    pub id: String,
+    pub uri: String,
+    /// Bundles with smaller tokens are applied first
+    pub creation_token: Option<u64>,
+}
+
+impl BundleUri {
+    /// Absolute uri of the bundle, with a relative [BundleUri::uri] resolved against `base`,
+    /// the url the list was received from
+    pub fn resolve(&self, base: &str) -> Option<String> {
+        let base = url::Url::parse(base).ok()?;
+        base.join(&self.uri).ok().map(String::from)
+    }
+}
+
+/// List of bundles advertised by `bundle-uri`
+///
+/// ```text
+/// bundle.version=1
+/// bundle.mode=(all|any)
+/// bundle.<id>.uri=<uri>
+/// bundle.<id>.creationToken=<token>
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleList {
+    pub mode: BundleMode,
+    /// Bundles with a uri, sorted by creation token
+    pub bundles: Vec<BundleUri>,
+}
+
+impl BundleList {
+    /// Parse `(key, value)` pairs of a bundle list, ignoring unknown keys
+    pub fn parse(pairs: &[(String, String)]) -> Result<Self, BundleError> {
+        let mut version = None;
+        let mut mode = None;
+        let mut bundles: Vec<BundleUri> = Vec::new();
+        for (key, value) in pairs {
+            let key = match key.strip_prefix("bundle.") {
+                Some(key) => key,
+                None => continue,
+            };
+            match key {
+                "version" => version = Some(value.as_str()),
+                "mode" => mode = Some(match value.as_str() {
+                    "all" => BundleMode::All,
+                    "any" => BundleMode::Any,
+                    _ => return Err(BundleError::InvalidList(format!("unknown mode {}", value))),
+                }),
+                _ => {
+                    let (id, key) = match key.rsplit_once('.') {
+                        Some(pair) => pair,
+                        None => continue,
+                    };
+                    let index = match bundles.iter().position(|b| b.id == id) {
+                        Some(index) => index,
+                        None => {
+                            bundles.push(BundleUri { id: id.to_owned(), uri: String::new(), creation_token: None });
+                            bundles.len() - 1
+                        }
+                    };
+                    match key {
+                        "uri" => bundles[index].uri = value.clone(),
+                        "creationtoken" | "creationToken" => {
+                            let token = value.parse().map_err(|_| BundleError::InvalidList(format!("invalid creation token {}", value)))?;
+                            bundles[index].creation_token = Some(token);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        if version != Some("1") {
+            return Err(BundleError::InvalidList(format!("unsupported version {}", version.unwrap_or("none"))));
+        }
+        bundles.retain(|b| !b.uri.is_empty());
+        bundles.sort_by_key(|b| b.creation_token);
+        Ok(Self {
+            mode: mode.ok_or_else(|| BundleError::InvalidList("missing mode".to_owned()))?,
+            bundles,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::bundle::{write_bundle, Bundle, BundleError, BundleHeader, BundleList, BundleMode, BundleUri};
+    use crate::pack::{ObjectStore, PackWriter};
+    use crate::testing::{commit, fixture, git};
+    use crate::oid::ObjectId;
+
+    #[test]
+    fn test_read_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let work = dir.path().join("work");
+        let head: ObjectId = git(&work, &["rev-parse", "HEAD"]).trim().parse().unwrap();
+
+        git(&work, &["bundle", "create", "-q", "full.bundle", "master", "v1"]);
+        let bundle = Bundle::open(work.join("full.bundle")).unwrap();
+        assert_eq!(bundle.header.version, 2);
+        assert_eq!(bundle.header.refs, vec![
+            ("refs/heads/master".to_owned(), head),
+            ("refs/tags/v1".to_owned(), git(&work, &["rev-parse", "v1"]).trim().parse().unwrap()),
+        ]);
+        assert!(bundle.header.prerequisites.is_empty());
+        assert!(bundle.pack.object(&head).is_some());
+
+        // incremental bundle with a thin pack
+        let third: ObjectId = commit(&work, &[("README.md", "# Test\n\nMore\n\nAnd more\n")], "Third").parse().unwrap();
+        git(&work, &["bundle", "create", "-q", "--version=3", "inc.bundle", &format!("{}..master", head)]);
+        let mut inc = Bundle::open(work.join("inc.bundle")).unwrap();
+        assert_eq!(inc.header.version, 3);
+        assert_eq!(inc.header.object_format(), "sha1");
+        assert_eq!(inc.header.prerequisites, vec![(head, "Add b".to_owned())]);
+        assert_eq!(inc.header.refs, vec![("refs/heads/master".to_owned(), third)]);
+        let base = bundle.pack;
+        inc.pack.resolve_deltas(|id| base.object(id).map(|o| (o.object_type.clone(), o.data.clone()))).unwrap();
+        assert_eq!(inc.pack.unresolved().count(), 0);
+        assert!(inc.pack.object(&third).is_some());
+
+        let unknown = b"# v3 git bundle\n@object-format=sha512\n\nPACK";
+        assert!(matches!(Bundle::from_reader(&mut Cursor::new(&unknown[..])), Err(BundleError::UnsupportedCapability(c)) if c == "object-format=sha512"));
+        assert!(matches!(Bundle::from_reader(&mut Cursor::new(&b"# v4 git bundle\n"[..])), Err(BundleError::InvalidSignature)));
+        assert!(matches!(BundleHeader::read(&mut Cursor::new(&b"# v2 git bundle\n@filter=blob:none\n\n"[..])), Err(BundleError::InvalidHeader(_))));
+        let sha256_ref = format!("# v2 git bundle\n{} refs/heads/master\n\n", "0".repeat(64));
+        assert!(matches!(BundleHeader::read(&mut Cursor::new(sha256_ref.as_bytes())), Err(BundleError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn test_write_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let work = dir.path().join("work");
+        let head: ObjectId = git(&work, &["rev-parse", "HEAD"]).trim().parse().unwrap();
+        git(&work, &["bundle", "create", "-q", "full.bundle", "master"]);
+        let bundle = Bundle::open(work.join("full.bundle")).unwrap();
+
+        let mut writer = PackWriter::new();
+        for object in bundle.pack.objects.values() {
+            writer.add(object.object_type.clone(), object.data.clone());
+        }
+        let header = BundleHeader::new()
+            .capability("object-format", Some("sha1"))
+            .reference("refs/heads/main", head);
+        let path = dir.path().join("out.bundle");
+        let mut out = Vec::new();
+        write_bundle(&mut out, &header, &writer).unwrap();
+        std::fs::write(&path, &out).unwrap();
+
+        let read = Bundle::from_reader(&mut Cursor::new(out)).unwrap();
+        assert_eq!(read.header, header);
+        assert_eq!(read.pack.objects.len(), bundle.pack.objects.len());
+
+        git(&work, &["bundle", "verify", "-q", path.to_str().unwrap()]);
+        git(dir.path(), &["clone", "-q", "-b", "main", path.to_str().unwrap(), "cloned"]);
+        assert_eq!(git(&dir.path().join("cloned"), &["rev-parse", "HEAD"]).trim(), head.to_string());
+    }
+
+    #[test]
+    fn test_bundle_list() {
+        let pairs: Vec<(String, String)> = [
+            ("bundle.version", "1"),
+            ("bundle.mode", "all"),
+            ("bundle.daily.uri", "https://cdn.example.com/daily.bundle"),
+            ("bundle.daily.creationtoken", "2"),
+            ("bundle.base.uri", "https://cdn.example.com/base.bundle"),
+            ("bundle.base.creationtoken", "1"),
+            ("bundle.nouri.creationtoken", "3"),
+        ].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let list = BundleList::parse(&pairs).unwrap();
+        assert_eq!(list.mode, BundleMode::All);
+        assert_eq!(list.bundles, vec![
+            BundleUri { id: "base".to_owned(), uri: "https://cdn.example.com/base.bundle".to_owned(), creation_token: Some(1) },
+            BundleUri { id: "daily".to_owned(), uri: "https://cdn.example.com/daily.bundle".to_owned(), creation_token: Some(2) },
+        ]);
+        assert!(matches!(BundleList::parse(&pairs[1..]), Err(BundleError::InvalidList(_))));
+
+        let base = "https://example.com/group/repo.git";
+        assert_eq!(list.bundles[0].resolve(base).as_deref(), Some("https://cdn.example.com/base.bundle"));
+        let relative = BundleUri { id: "base".to_owned(), uri: "bundles/base.bundle".to_owned(), creation_token: None };
+        assert_eq!(relative.resolve(base).as_deref(), Some("https://example.com/group/bundles/base.bundle"));
+        let rooted = BundleUri { uri: "/base.bundle".to_owned(), ..relative };
+        assert_eq!(rooted.resolve(base).as_deref(), Some("https://example.com/base.bundle"));
+    }
+}
@@ -0,0 +1,108 @@
+//! Cancellation and deadlines of long-running fetches and unpacking.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Token checked between packets and objects, to abort an operation from another thread
+///
+/// Clones share the same state, so cancelling any of them cancels all.
+///
+/// ```no_run
+/// use anni_fetch::cancel::CancellationToken;
+/// use anni_fetch::repo::{clone_bare, CloneOptions};
+/// use std::time::Duration;
+/// # fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let token = CancellationToken::new().with_timeout(Duration::from_secs(600));
+/// let abort = token.clone();
+/// std::thread::spawn(move || {
+///     // on user request
+///     abort.cancel();
+/// });
+/// let options = CloneOptions { cancel: Some(token), ..Default::default() };
+/// clone_bare("https://github.com/project-anni/repo.git", "repo.git", &options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+/// Reason an operation stopped early
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Interrupted {
+    Cancelled,
+    TimedOut,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up at `deadline`, as if cancelled but reported as timed out
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Give up after `timeout` from now
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Time left before the deadline, `None` without deadline
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Interrupted> {
+        if self.is_cancelled() {
+            Err(Interrupted::Cancelled)
+        } else if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            Err(Interrupted::TimedOut)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Check `token` if there is one
+pub(crate) fn check(token: Option<&CancellationToken>) -> Result<(), Interrupted> {
+    token.map_or(Ok(()), CancellationToken::check)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::cancel::{CancellationToken, Interrupted};
+
+    #[test]
+    fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        assert_eq!(token.check(), Ok(()));
+        assert_eq!(token.remaining(), None);
+        token.clone().cancel();
+        assert!(token.is_cancelled());
+        assert_eq!(token.check(), Err(Interrupted::Cancelled));
+
+        let token = CancellationToken::new().with_deadline(Instant::now());
+        assert_eq!(token.check(), Err(Interrupted::TimedOut));
+        assert_eq!(token.remaining(), Some(Duration::ZERO));
+        assert_eq!(CancellationToken::new().with_timeout(Duration::from_secs(60)).check(), Ok(()));
+    }
+}
@@ -0,0 +1,326 @@
+//! Write the tree of a commit into a working directory.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+use crate::object::{ObjectError, Tree};
+use crate::oid::ObjectId;
+use crate::pack::{ObjectStore, ObjectType};
+use crate::utils::hex;
+
+#[derive(Debug, Error)]
+pub enum CheckoutError {
+    #[error("object {0} not found")]
+    MissingObject(String),
+    #[error("object {0} is not a commit, tag or tree")]
+    NotTreeish(String),
+    #[error("refusing to check out path {0}")]
+    InvalidPath(String),
+
+    #[error(transparent)]
+    ObjectError(#[from] ObjectError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// Result of [checkout]
+#[derive(Debug, Default, PartialEq)]
+pub struct CheckoutReport {
+    /// Number of regular files and symlinks written
+    pub files: usize,
+    /// Submodules found in the tree, as `(path, commit id)`
+    ///
+    /// Submodules are not fetched, only an empty directory is created for each of them.
+    pub gitlinks: Vec<(String, ObjectId)>,
+}
+
+/// Find the tree of a commit, tag or tree `id`
+///
+/// Annotated tags are peeled until a commit or tree is found.
+pub fn resolve_tree<S: ObjectStore + ?Sized>(store: &S, id: &ObjectId) -> Result<ObjectId, CheckoutError> {
+    let mut id = *id;
+    loop {
+        let object = store.object(&id).ok_or_else(|| CheckoutError::MissingObject(hex(&id)))?;
+        match object.object_type {
+            ObjectType::Tree => return Ok(id),
+            ObjectType::Commit => return Ok(object.commit()?.tree),
+            ObjectType::Tag => id = object.tag()?.object,
+            _ => return Err(CheckoutError::NotTreeish(hex(&id))),
+        }
+    }
+}
+
+/// Write the tree of commit, tag or tree `id` into directory `dir`
+///
+/// `dir` is created if it does not exist, and existing files are overwritten.
+/// Executable bits and symlinks are restored on unix; on other platforms a symlink
+/// is written as a file containing its target, like git does with `core.symlinks=false`.
+pub fn checkout<S: ObjectStore + ?Sized, P: AsRef<Path>>(store: &S, id: &ObjectId, dir: P) -> Result<CheckoutReport, CheckoutError> {
+    let tree = resolve_tree(store, id)?;
+    let mut report = CheckoutReport::default();
+    fs::create_dir_all(dir.as_ref())?;
+    checkout_tree(store, &tree, dir.as_ref(), "", &mut report)?;
+    Ok(report)
+}
+
+fn checkout_tree<S: ObjectStore + ?Sized>(store: &S, id: &ObjectId, dir: &Path, prefix: &str, report: &mut CheckoutReport) -> Result<(), CheckoutError> {
+    let tree = read_tree(store, id)?;
+    let mut names = HashSet::new();
+    for entry in tree.entries.iter() {
+        let path = format!("{}{}", prefix, String::from_utf8_lossy(&entry.name));
+        // a duplicate name could turn a symlink written first into a directory to descend into
+        if !names.insert(entry.name.as_slice()) {
+            return Err(CheckoutError::InvalidPath(path));
+        }
+        let target = match os_name(&entry.name) {
+            Some(name) if is_safe_name(&entry.name) => dir.join(name),
+            _ => return Err(CheckoutError::InvalidPath(path)),
+        };
+
+        if entry.is_tree() {
+            create_dir(&target)?;
+            checkout_tree(store, &entry.id, &target, &format!("{}/", path), report)?;
+        } else if entry.is_gitlink() {
+            create_dir(&target)?;
+            report.gitlinks.push((path, entry.id));
+        } else if entry.is_blob() {
+            let blob = store.object(&entry.id).ok_or_else(|| CheckoutError::MissingObject(hex(&entry.id)))?;
+            remove_existing(&target)?;
+            if entry.is_symlink() {
+                write_symlink(&blob.data, &target)?;
+            } else {
+                fs::write(&target, &blob.data)?;
+                set_executable(&target, entry.is_executable())?;
+            }
+            report.files += 1;
+        } else {
+            return Err(CheckoutError::InvalidPath(path));
+        }
+    }
+    Ok(())
+}
+
+fn read_tree<S: ObjectStore + ?Sized>(store: &S, id: &ObjectId) -> Result<Tree, CheckoutError> {
+    let object = store.object(id).ok_or_else(|| CheckoutError::MissingObject(hex(id)))?;
+    Ok(object.tree()?)
+}
+
+/// Reject names which would escape the target directory or write into `.git`
+fn is_safe_name(name: &[u8]) -> bool {
+    !name.is_empty()
+        && name != b"."
+        && name != b".."
+        && !is_dotgit(name)
+        && !name.iter().any(|b| matches!(b, b'/' | b'\\' | 0))
+}
+
+/// Whether `name` refers to `.git` on some filesystem, see `is_ntfs_dotgit` and `is_hfs_dotgit` in git
+fn is_dotgit(name: &[u8]) -> bool {
+    // NTFS ignores alternate data streams and trailing dots and spaces, and has `GIT~1` as short name
+    let ntfs = name.split(|b| *b == b':').next().unwrap_or(name);
+    let end = ntfs.iter().rposition(|b| !matches!(b, b'.' | b' ')).map_or(0, |i| i + 1);
+    let ntfs = &ntfs[..end];
+    if ntfs.eq_ignore_ascii_case(b".git") || ntfs.eq_ignore_ascii_case(b"git~1") {
+        return true;
+    }
+
+    // HFS+ ignores some zero-width code points
+    match std::str::from_utf8(name) {
+        Ok(name) => {
+            let hfs: String = name.chars()
+                .filter(|c| !matches!(c, '\u{200c}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{206a}'..='\u{206f}' | '\u{feff}'))
+                .collect();
+            hfs.eq_ignore_ascii_case(".git")
+        }
+        Err(_) => false,
+    }
+}
+
+/// Create directory `path`, replacing a file or symlink at `path` so that it is never followed
+fn create_dir(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => Ok(()),
+        Ok(_) => {
+            fs::remove_file(path)?;
+            fs::create_dir(path)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => fs::create_dir(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// File name of a tree entry, which must be utf-8 on platforms other than unix
+#[cfg(unix)]
+fn os_name(name: &[u8]) -> Option<&std::ffi::OsStr> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(std::ffi::OsStr::from_bytes(name))
+}
+
+#[cfg(not(unix))]
+fn os_name(name: &[u8]) -> Option<&std::ffi::OsStr> {
+    std::str::from_utf8(name).ok().map(std::ffi::OsStr::new)
+}
+
+/// Remove a file or symlink at `path`, so that a symlink is never followed when writing
+fn remove_existing(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+fn write_symlink(target: &[u8], path: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), path)
+}
+
+#[cfg(not(unix))]
+fn write_symlink(target: &[u8], path: &Path) -> std::io::Result<()> {
+    fs::write(path, target)
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, executable: bool) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(if executable { mode | 0o111 } else { mode & !0o111 });
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _executable: bool) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use crate::checkout::{checkout, is_safe_name, resolve_tree, CheckoutError};
+    use crate::object::{Tree, TreeEntry};
+    use crate::oid::{HashAlgorithm, ObjectId};
+    use crate::pack::{Object, ObjectStore, ObjectType};
+    use crate::testing::{commit, git, pack_objects};
+    use crate::utils::{git_hash, unhex};
+    use crate::Pack;
+
+    struct Store(HashMap<ObjectId, Object>);
+
+    impl ObjectStore for Store {
+        fn object(&self, id: &ObjectId) -> Option<Cow<'_, Object>> {
+            self.0.get(id).map(Cow::Borrowed)
+        }
+    }
+
+    #[test]
+    fn test_checkout() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path().join("work");
+        std::fs::create_dir(&work).unwrap();
+        git(&work, &["init", "-q"]);
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("README.md", work.join("link")).unwrap();
+        commit(&work, &[("README.md", "# Test\n"), ("album/2021/a.toml", "title = \"a\"\n"), ("run.sh", "#!/bin/sh\n")], "Initial commit");
+        git(&work, &["update-index", "--chmod=+x", "run.sh"]);
+        let sub = "9192b5e5f2941fd76aa5a08043dc8aa6a31831a2";
+        git(&work, &["update-index", "--add", "--cacheinfo", &format!("160000,{},sub", sub)]);
+        git(&work, &["commit", "-q", "-m", "Add submodule"]);
+        git(&work, &["tag", "-a", "v1", "-m", "v1"]);
+
+        let pack = Pack::from_reader(&mut Cursor::new(pack_objects(&work, "v1"))).unwrap();
+        let tag = unhex(git(&work, &["rev-parse", "v1"]).trim()).unwrap();
+        let tree = unhex(git(&work, &["rev-parse", "HEAD^{tree}"]).trim()).unwrap();
+        assert_eq!(resolve_tree(&pack, &tag).unwrap(), tree);
+
+        let out = dir.path().join("out");
+        let report = checkout(&pack, &tag, &out).unwrap();
+        assert_eq!(report.gitlinks, vec![("sub".to_owned(), unhex(sub).unwrap())]);
+        assert_eq!(std::fs::read_to_string(out.join("album/2021/a.toml")).unwrap(), "title = \"a\"\n");
+        assert!(out.join("sub").is_dir());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(report.files, 4);
+            assert_eq!(std::fs::metadata(out.join("run.sh")).unwrap().permissions().mode() & 0o111, 0o111);
+            assert_eq!(std::fs::metadata(out.join("README.md")).unwrap().permissions().mode() & 0o111, 0);
+            assert_eq!(std::fs::read_link(out.join("link")).unwrap().to_str(), Some("README.md"));
+        }
+
+        // checkout again over existing files
+        checkout(&pack, &tree, &out).unwrap();
+    }
+
+    #[test]
+    fn test_unsafe_path() {
+        let tree = Tree {
+            entries: vec![TreeEntry { mode: TreeEntry::MODE_TREE, raw_mode: None, name: b"..".to_vec(), id: ObjectId::Sha1([0; 20]) }],
+        };
+        let data = tree.to_bytes();
+        let id = git_hash(HashAlgorithm::Sha1, "tree", &data);
+        let mut store = Store(HashMap::new());
+        store.0.insert(id, Object { object_type: ObjectType::Tree, data, compressed_length: 0, offset: 0, algorithm: HashAlgorithm::Sha1 });
+
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(checkout(&store, &id, dir.path()), Err(CheckoutError::InvalidPath(p)) if p == ".."));
+        assert!(matches!(checkout(&store, &ObjectId::Sha1([1; 20]), dir.path()), Err(CheckoutError::MissingObject(_))));
+
+        for name in [&b".GIT"[..], b".git.", b".git . ", b"GIT~1", b".git::$INDEX_ALLOCATION", ".g\u{200c}it".as_bytes(), "\u{feff}.GIT".as_bytes()] {
+            assert!(!is_safe_name(name), "{}", String::from_utf8_lossy(name));
+        }
+        assert!(is_safe_name(b".gitignore"));
+        assert!(is_safe_name(b"git~2"));
+    }
+
+    #[test]
+    fn test_symlink_escape() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        let mut store = Store(HashMap::new());
+        let mut add = |object_type: ObjectType, data: Vec<u8>| {
+            let id = git_hash(HashAlgorithm::Sha1, object_type.name(), &data);
+            store.0.insert(id, Object { object_type, data, compressed_length: 0, offset: 0, algorithm: HashAlgorithm::Sha1 });
+            id
+        };
+        let blob = add(ObjectType::Blob, b"pwned\n".to_vec());
+        let link = add(ObjectType::Blob, outside.to_str().unwrap().as_bytes().to_vec());
+        let sub = add(ObjectType::Tree, Tree {
+            entries: vec![TreeEntry { mode: TreeEntry::MODE_BLOB, raw_mode: None, name: b"x".to_vec(), id: blob }],
+        }.to_bytes());
+        // symlink `a` to a directory outside, then tree `a` writing through it
+        let tree = add(ObjectType::Tree, Tree {
+            entries: vec![
+                TreeEntry { mode: TreeEntry::MODE_SYMLINK, raw_mode: None, name: b"a".to_vec(), id: link },
+                TreeEntry { mode: TreeEntry::MODE_TREE, raw_mode: None, name: b"a".to_vec(), id: sub },
+            ],
+        }.to_bytes());
+        let only_sub = add(ObjectType::Tree, Tree {
+            entries: vec![TreeEntry { mode: TreeEntry::MODE_TREE, raw_mode: None, name: b"a".to_vec(), id: sub }],
+        }.to_bytes());
+
+        let out = dir.path().join("out");
+        assert!(matches!(checkout(&store, &tree, &out), Err(CheckoutError::InvalidPath(p)) if p == "a"));
+        assert!(!outside.join("x").exists());
+
+        // a symlink left in the directory is replaced, not followed
+        #[cfg(unix)]
+        {
+            std::fs::remove_dir_all(&out).unwrap();
+            std::fs::create_dir(&out).unwrap();
+            std::os::unix::fs::symlink(&outside, out.join("a")).unwrap();
+            checkout(&store, &only_sub, &out).unwrap();
+            assert!(!outside.join("x").exists());
+            assert!(std::fs::symlink_metadata(out.join("a")).unwrap().is_dir());
+            assert_eq!(std::fs::read(out.join("a/x")).unwrap(), b"pwned\n");
+        }
+        #[cfg(not(unix))]
+        let _ = only_sub;
+    }
+}
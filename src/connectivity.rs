@@ -0,0 +1,310 @@
+//! Check that all objects reachable from some tips are present, like `git rev-list --objects --missing=print`.
+//!
+//! Git runs such a check after fetching, before updating refs to the new tips.
+
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+use crate::object::{self, ObjectError, TreeEntry};
+use crate::oid::ObjectId;
+use crate::pack::{ObjectStore, ObjectType};
+use crate::utils::hex;
+
+#[derive(Debug, Error)]
+pub enum ConnectivityError {
+    #[error("invalid object {0}: {1}")]
+    InvalidObject(String, #[source] ObjectError),
+}
+
+/// Objects found missing by [Connectivity::check]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConnectivityReport {
+    /// Number of objects walked
+    pub objects: usize,
+    /// Objects reachable from tips which are not in store
+    pub missing: Vec<ObjectId>,
+    /// Missing objects allowed by [Connectivity::set_promisor]
+    pub promised: Vec<ObjectId>,
+}
+
+impl ConnectivityReport {
+    pub fn is_connected(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Walker of commits, trees and tags reachable from tips
+///
+/// Combine stores with a tuple to check a new pack with local objects:
+///
+/// ```no_run
+/// use anni_fetch::connectivity::Connectivity;
+/// # fn example(pack: &anni_fetch::Pack, local: &Vec<anni_fetch::Pack>, tip: anni_fetch::ObjectId) -> Result<(), Box<dyn std::error::Error>> {
+/// let store = (pack, local);
+/// let report = Connectivity::new(&store).check(&[tip])?;
+/// assert!(report.is_connected(), "missing {:?}", report.missing);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Connectivity<'a, S: ObjectStore + ?Sized> {
+    store: &'a S,
+    shallow: HashSet<ObjectId>,
+    complete: HashSet<ObjectId>,
+    promisor: bool,
+}
+
+impl<'a, S: ObjectStore + ?Sized> Connectivity<'a, S> {
+    pub fn new(store: &'a S) -> Self {
+        Self {
+            store,
+            shallow: HashSet::new(),
+            complete: HashSet::new(),
+            promisor: false,
+        }
+    }
+
+    /// Do not expect parents of `shallow` commits, as they were not fetched
+    pub fn set_shallow<I: IntoIterator<Item = ObjectId>>(&mut self, shallow: I) {
+        self.shallow = shallow.into_iter().collect();
+    }
+
+    /// Do not walk objects already known to be connected, like tips of local refs
+    pub fn set_complete<I: IntoIterator<Item = ObjectId>>(&mut self, complete: I) {
+        self.complete = complete.into_iter().collect();
+    }
+
+    /// Allow objects referred by other objects to be missing, as in a partial clone
+    /// from a promisor remote, which omits objects filtered by `filter`
+    ///
+    /// Tips are still required.
+    pub fn set_promisor(&mut self, promisor: bool) {
+        self.promisor = promisor;
+    }
+
+    /// Walk all objects reachable from `tips`, and list the missing ones
+    pub fn check(&self, tips: &[ObjectId]) -> Result<ConnectivityReport, ConnectivityError> {
+        let mut report = ConnectivityReport::default();
+        let mut seen: HashSet<ObjectId> = self.complete.clone();
+        // (id, expected type, is tip)
+        let mut pending: Vec<(ObjectId, Option<ObjectType>, bool)> = tips.iter().rev().map(|id| (*id, None, true)).collect();
+        while let Some((id, expected, tip)) = pending.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let object = match self.store.object(&id) {
+                Some(object) => object,
+                None if self.promisor && !tip => {
+                    report.promised.push(id);
+                    continue;
+                }
+                None => {
+                    report.missing.push(id);
+                    continue;
+                }
+            };
+            report.objects += 1;
+            let invalid = |e| ConnectivityError::InvalidObject(hex(&id), e);
+            if let Some(expected) = expected {
+                if object.object_type != expected {
+                    return Err(invalid(ObjectError::UnexpectedType(expected.name(), object.object_type.name())));
+                }
+            }
+
+            match object.object_type {
+                ObjectType::Commit => {
+                    let (tree, parents) = object::commit_links(&object.data).map_err(invalid)?;
+                    if !self.shallow.contains(&id) {
+                        pending.extend(parents.iter().rev().map(|p| (*p, Some(ObjectType::Commit), false)));
+                    }
+                    pending.push((tree, Some(ObjectType::Tree), false));
+                }
+                ObjectType::Tree => {
+                    let start = pending.len();
+                    for entry in object::tree_entries(&object.data, object.algorithm) {
+                        let (mode, _, id) = entry.map_err(invalid)?;
+                        if mode == TreeEntry::MODE_TREE {
+                            pending.push((id, Some(ObjectType::Tree), false));
+                        } else if object::is_blob_mode(mode) {
+                            pending.push((id, Some(ObjectType::Blob), false));
+                        }
+                        // gitlinks refer to commits of other repositories
+                    }
+                    pending[start..].reverse();
+                }
+                ObjectType::Tag => {
+                    let (target, target_type) = object::tag_target(&object.data).map_err(invalid)?;
+                    pending.push((target, Some(target_type), false));
+                }
+                _ => {}
+            }
+        }
+        Ok(report)
+    }
+
+    /// Commits among `commits` whose history, trees and blobs are all in store
+    ///
+    /// Unlike [Connectivity::check], each object is walked once for all `commits`,
+    /// so it suits picking the commits of a partial pack which can be sent as haves.
+    pub fn complete(&self, commits: &[ObjectId]) -> Result<HashSet<ObjectId>, ConnectivityError> {
+        let mut commit_state: HashMap<ObjectId, bool> = self.complete.iter().map(|id| (*id, true)).collect();
+        let mut tree_state: HashMap<ObjectId, bool> = HashMap::new();
+        let mut pending: Vec<ObjectId> = commits.to_vec();
+        while let Some(id) = pending.last().copied() {
+            if commit_state.contains_key(&id) {
+                pending.pop();
+                continue;
+            }
+            let object = match self.store.object(&id) {
+                Some(object) => object,
+                None => {
+                    commit_state.insert(id, false);
+                    pending.pop();
+                    continue;
+                }
+            };
+            let invalid = |e| ConnectivityError::InvalidObject(hex(&id), e);
+            let (tree, parents) = object::commit_links(&object.data).map_err(invalid)?;
+            let parents: &[ObjectId] = if self.shallow.contains(&id) { &[] } else { &parents };
+
+            // walk parents first, and come back to this commit later
+            let unknown: Vec<ObjectId> = parents.iter().filter(|p| !commit_state.contains_key(p)).copied().collect();
+            if !unknown.is_empty() {
+                pending.extend(unknown);
+                continue;
+            }
+            let complete = parents.iter().all(|p| commit_state[p]) && self.tree_complete(&tree, &mut tree_state)?;
+            commit_state.insert(id, complete);
+            pending.pop();
+        }
+        Ok(commits.iter().filter(|id| commit_state.get(id) == Some(&true)).copied().collect())
+    }
+
+    /// Whether tree `id` and all its subtrees and blobs are in store, remembering results in `state`
+    fn tree_complete(&self, id: &ObjectId, state: &mut HashMap<ObjectId, bool>) -> Result<bool, ConnectivityError> {
+        if let Some(complete) = state.get(id) {
+            return Ok(*complete);
+        }
+        let complete = match self.store.object(id) {
+            Some(object) => {
+                let mut complete = true;
+                for entry in object::tree_entries(&object.data, object.algorithm) {
+                    let (mode, _, entry) = entry.map_err(|e| ConnectivityError::InvalidObject(hex(id), e))?;
+                    complete = if mode == TreeEntry::MODE_TREE {
+                        self.tree_complete(&entry, state)?
+                    } else {
+                        !object::is_blob_mode(mode) || self.store.object(&entry).is_some()
+                    };
+                    if !complete {
+                        break;
+                    }
+                }
+                complete
+            }
+            None => false,
+        };
+        state.insert(*id, complete);
+        Ok(complete)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::connectivity::{Connectivity, ConnectivityError};
+    use crate::pack::{ObjectType, PackWriter};
+    use crate::testing::{commit, fixture, git, pack_objects};
+    use crate::utils::unhex;
+    use crate::Pack;
+
+    #[test]
+    fn test_connectivity() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let work = dir.path().join("work");
+        let id = |rev: &str| unhex(git(&work, &["rev-parse", rev]).trim()).unwrap();
+        let full = Pack::from_reader(&mut Cursor::new(pack_objects(&work, "v1"))).unwrap();
+        let report = Connectivity::new(&full).check(&[id("v1")]).unwrap();
+        assert!(report.is_connected());
+        assert_eq!(report.objects, full.objects.len());
+
+        // objects of the new commit only, completed by the full pack
+        commit(&work, &[("album/c.toml", "title = \"c\"\n")], "Add c");
+        let new = Pack::from_reader(&mut Cursor::new(pack_objects(&work, "HEAD\n^HEAD~1"))).unwrap();
+        let report = Connectivity::new(&new).check(&[id("HEAD")]).unwrap();
+        assert!(!report.is_connected());
+        assert!(report.missing.contains(&id("HEAD~1")));
+        assert!(report.missing.contains(&id("HEAD:README.md")));
+
+        let store = (&new, &full);
+        assert!(Connectivity::new(&store).check(&[id("HEAD")]).unwrap().is_connected());
+
+        let mut check = Connectivity::new(&new);
+        check.set_shallow(vec![id("HEAD")]);
+        check.set_complete(vec![id("HEAD:README.md"), id("HEAD:album/a.toml"), id("HEAD:album/b.toml")]);
+        let report = check.check(&[id("HEAD")]).unwrap();
+        assert!(report.is_connected());
+        assert_eq!(report.objects, new.objects.len());
+
+        // a partial clone may miss blobs, but not its tips
+        let mut check = Connectivity::new(&new);
+        check.set_shallow(vec![id("HEAD")]);
+        check.set_promisor(true);
+        let report = check.check(&[id("HEAD"), id("v1")]).unwrap();
+        assert_eq!(report.missing, vec![id("v1")]);
+        assert_eq!(report.promised.len(), 3);
+    }
+
+    #[test]
+    fn test_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let work = dir.path().join("work");
+        commit(&work, &[("album/c.toml", "title = \"c\"\n")], "Add c");
+        let id = |rev: &str| unhex(git(&work, &["rev-parse", rev]).trim()).unwrap();
+        let old = Pack::from_reader(&mut Cursor::new(pack_objects(&work, "HEAD~1"))).unwrap();
+        let new = Pack::from_reader(&mut Cursor::new(pack_objects(&work, "HEAD\n^HEAD~1"))).unwrap();
+
+        let store = (&new, &old);
+        let complete = Connectivity::new(&store).complete(&[id("HEAD"), id("HEAD~1")]).unwrap();
+        assert_eq!(complete.len(), 2);
+        assert!(Connectivity::new(&new).complete(&[id("HEAD")]).unwrap().is_empty());
+
+        // history is known, but unchanged blobs are not in the new pack
+        let mut check = Connectivity::new(&new);
+        check.set_complete(vec![id("HEAD~1")]);
+        assert!(check.complete(&[id("HEAD")]).unwrap().is_empty());
+        let mut check = Connectivity::new(&store);
+        check.set_shallow(vec![id("HEAD")]);
+        assert!(check.complete(&[id("HEAD")]).unwrap().contains(&id("HEAD")));
+    }
+
+    #[test]
+    fn test_unexpected_type() {
+        let mut writer = PackWriter::new();
+        let blob = writer.add(ObjectType::Blob, b"blob".to_vec());
+        let commit = writer.add(ObjectType::Commit, format!("tree {}\nauthor a <a> 0 +0000\ncommitter a <a> 0 +0000\n\nmessage\n", blob).into_bytes());
+        let mut raw = Vec::new();
+        writer.write(&mut raw).unwrap();
+        let pack = Pack::from_reader(&mut Cursor::new(raw)).unwrap();
+        assert!(matches!(Connectivity::new(&pack).check(&[commit]), Err(ConnectivityError::InvalidObject(id, _)) if id == blob.to_string()));
+    }
+
+    #[test]
+    fn test_raw_objects() {
+        // names, signatures and messages are not decoded
+        let mut writer = PackWriter::new();
+        let blob = writer.add(ObjectType::Blob, b"blob".to_vec());
+        let mut data = b"100644 caf\xe9\0".to_vec();
+        data.extend_from_slice(blob.as_bytes());
+        let tree = writer.add(ObjectType::Tree, data);
+        let mut data = format!("tree {}\n", tree).into_bytes();
+        data.extend_from_slice(b"author J\xf6rg <j> 0 +0000\ncommitter J\xf6rg <j> 0 +0000\nencoding ISO-8859-1\n\nGr\xfc\xdfe\n");
+        let commit = writer.add(ObjectType::Commit, data);
+        let mut raw = Vec::new();
+        writer.write(&mut raw).unwrap();
+        let pack = Pack::from_reader(&mut Cursor::new(raw)).unwrap();
+        let report = Connectivity::new(&pack).check(&[commit]).unwrap();
+        assert!(report.is_connected());
+        assert_eq!(report.objects, 3);
+        assert!(Connectivity::new(&pack).complete(&[commit]).unwrap().contains(&commit));
+    }
+}
@@ -0,0 +1,251 @@
+//! Compare the trees of two commits.
+
+use std::collections::{BTreeMap, HashMap};
+use thiserror::Error;
+use crate::checkout::{resolve_tree, CheckoutError};
+use crate::object::{ObjectError, Tree, TreeEntry};
+use crate::oid::{HashAlgorithm, ObjectId};
+use crate::pack::ObjectStore;
+use crate::utils::hex;
+
+#[derive(Debug, Error)]
+pub enum DiffError {
+    #[error("object {0} not found")]
+    MissingObject(String),
+
+    #[error(transparent)]
+    CheckoutError(#[from] CheckoutError),
+    #[error(transparent)]
+    ObjectError(#[from] ObjectError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Deleted,
+    /// Content or executable bit changed
+    Modified,
+    /// Changed between regular file, symlink and submodule
+    TypeChanged,
+    /// Moved from the contained path without changing content
+    Renamed(String),
+}
+
+/// A changed path
+///
+/// Like `git diff --raw`, mode and id of a missing side are zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub path: String,
+    pub old_mode: u32,
+    pub old_id: ObjectId,
+    pub new_mode: u32,
+    pub new_id: ObjectId,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    /// Report a deleted and an added file with the same blob id as [ChangeKind::Renamed]
+    pub renames: bool,
+}
+
+/// Find changed files between commits, tags or trees `old` and `new`
+///
+/// `None` stands for an empty tree, e.g. when there is no previous HEAD.
+/// Directories are compared recursively and only files and submodules are reported;
+/// a file replaced by a directory is reported as a deletion and additions.
+pub fn diff_trees<S: ObjectStore + ?Sized>(store: &S, old: Option<&ObjectId>, new: Option<&ObjectId>, options: &DiffOptions) -> Result<Vec<Change>, DiffError> {
+    let old = old.map(|id| resolve_tree(store, id)).transpose()?;
+    let new = new.map(|id| resolve_tree(store, id)).transpose()?;
+    let mut changes = Vec::new();
+    diff_tree(store, old.as_ref(), new.as_ref(), "", &mut changes)?;
+    if options.renames {
+        detect_renames(&mut changes);
+    }
+    Ok(changes)
+}
+
+fn diff_tree<S: ObjectStore + ?Sized>(store: &S, old: Option<&ObjectId>, new: Option<&ObjectId>, prefix: &str, changes: &mut Vec<Change>) -> Result<(), DiffError> {
+    if old == new {
+        return Ok(());
+    }
+    let old = old.map(|id| read_tree(store, id)).transpose()?.unwrap_or_default();
+    let new = new.map(|id| read_tree(store, id)).transpose()?.unwrap_or_default();
+
+    let mut entries: BTreeMap<&[u8], (Option<&TreeEntry>, Option<&TreeEntry>)> = BTreeMap::new();
+    for entry in old.entries.iter() {
+        entries.entry(&entry.name).or_default().0 = Some(entry);
+    }
+    for entry in new.entries.iter() {
+        entries.entry(&entry.name).or_default().1 = Some(entry);
+    }
+
+    for (name, (old, new)) in entries {
+        if let (Some(o), Some(n)) = (old, new) {
+            if o == n {
+                continue;
+            }
+        }
+        let path = format!("{}{}", prefix, String::from_utf8_lossy(name));
+        let old_file = old.filter(|e| !e.is_tree());
+        let new_file = new.filter(|e| !e.is_tree());
+        let kind = match (old_file, new_file) {
+            (None, None) => None,
+            (None, Some(_)) => Some(ChangeKind::Added),
+            (Some(_), None) => Some(ChangeKind::Deleted),
+            (Some(o), Some(n)) if file_type(o) != file_type(n) => Some(ChangeKind::TypeChanged),
+            (Some(_), Some(_)) => Some(ChangeKind::Modified),
+        };
+        if let Some(kind) = kind {
+            let null = old_file.or(new_file).map_or(HashAlgorithm::Sha1, |e| e.id.algorithm()).null();
+            changes.push(Change {
+                kind,
+                path: path.clone(),
+                old_mode: old_file.map_or(0, |e| e.mode),
+                old_id: old_file.map_or(null, |e| e.id),
+                new_mode: new_file.map_or(0, |e| e.mode),
+                new_id: new_file.map_or(null, |e| e.id),
+            });
+        }
+
+        let old_tree = old.filter(|e| e.is_tree());
+        let new_tree = new.filter(|e| e.is_tree());
+        if old_tree.is_some() || new_tree.is_some() {
+            diff_tree(store, old_tree.map(|e| &e.id), new_tree.map(|e| &e.id), &format!("{}/", path), changes)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_tree<S: ObjectStore + ?Sized>(store: &S, id: &ObjectId) -> Result<Tree, DiffError> {
+    let object = store.object(id).ok_or_else(|| DiffError::MissingObject(hex(id)))?;
+    Ok(object.tree()?)
+}
+
+fn file_type(entry: &TreeEntry) -> u32 {
+    entry.mode & 0o170000
+}
+
+/// Pair deleted and added files with identical content, in path order
+fn detect_renames(changes: &mut Vec<Change>) {
+    let mut deleted: HashMap<ObjectId, Vec<usize>> = HashMap::new();
+    for (i, change) in changes.iter().enumerate().rev() {
+        if change.kind == ChangeKind::Deleted && is_file(change.old_mode) {
+            deleted.entry(change.old_id).or_default().push(i);
+        }
+    }
+
+    let mut renamed = Vec::new();
+    for i in 0..changes.len() {
+        if changes[i].kind != ChangeKind::Added || !is_file(changes[i].new_mode) {
+            continue;
+        }
+        if let Some(from) = deleted.get_mut(&changes[i].new_id).and_then(|d| d.pop()) {
+            let old_path = changes[from].path.clone();
+            let old_mode = changes[from].old_mode;
+            let change = &mut changes[i];
+            change.kind = ChangeKind::Renamed(old_path);
+            change.old_mode = old_mode;
+            change.old_id = change.new_id;
+            renamed.push(from);
+        }
+    }
+
+    renamed.sort_unstable();
+    for i in renamed.into_iter().rev() {
+        changes.remove(i);
+    }
+}
+
+fn is_file(mode: u32) -> bool {
+    mode != TreeEntry::MODE_GITLINK
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::diff::{diff_trees, Change, ChangeKind, DiffOptions};
+    use crate::object::TreeEntry;
+    use crate::testing::{commit, git, pack_objects};
+    use crate::utils::unhex;
+    use crate::Pack;
+
+    #[test]
+    fn test_diff_trees() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path();
+        git(work, &["init", "-q"]);
+        let first = commit(work, &[
+            ("README.md", "# Test\n"),
+            ("album/a.toml", "title = \"a\"\n"),
+            ("album/b.toml", "title = \"b\"\n"),
+            ("run.sh", "#!/bin/sh\n"),
+            ("x", "file\n"),
+        ], "Initial commit");
+        git(work, &["mv", "album/b.toml", "album/c.toml"]);
+        git(work, &["rm", "-q", "x"]);
+        std::fs::create_dir(work.join("x")).unwrap();
+        std::fs::write(work.join("x/y"), "dir\n").unwrap();
+        std::fs::write(work.join("README.md"), "# Test\n\nMore\n").unwrap();
+        git(work, &["add", "-A"]);
+        git(work, &["update-index", "--chmod=+x", "run.sh"]);
+        git(work, &["commit", "-q", "-m", "Second commit"]);
+        let second = git(work, &["rev-parse", "HEAD"]).trim().to_owned();
+
+        let pack = Pack::from_reader(&mut Cursor::new(pack_objects(work, &second))).unwrap();
+        let first = unhex(&first).unwrap();
+        let second = unhex(&second).unwrap();
+        let id = |rev: &str| unhex(git(work, &["rev-parse", rev]).trim()).unwrap();
+
+        let changes = diff_trees(&pack, Some(&first), Some(&second), &DiffOptions::default()).unwrap();
+        let summary: Vec<_> = changes.iter().map(|c| (c.kind.clone(), c.path.as_str())).collect();
+        assert_eq!(summary, vec![
+            (ChangeKind::Modified, "README.md"),
+            (ChangeKind::Deleted, "album/b.toml"),
+            (ChangeKind::Added, "album/c.toml"),
+            (ChangeKind::Modified, "run.sh"),
+            (ChangeKind::Deleted, "x"),
+            (ChangeKind::Added, "x/y"),
+        ]);
+        assert_eq!(changes[0], Change {
+            kind: ChangeKind::Modified,
+            path: "README.md".to_owned(),
+            old_mode: TreeEntry::MODE_BLOB,
+            old_id: id("HEAD~:README.md"),
+            new_mode: TreeEntry::MODE_BLOB,
+            new_id: id("HEAD:README.md"),
+        });
+        assert_eq!((changes[3].old_mode, changes[3].new_mode), (TreeEntry::MODE_BLOB, TreeEntry::MODE_EXECUTABLE));
+        assert_eq!(changes[4].new_mode, 0);
+
+        let renames = diff_trees(&pack, Some(&first), Some(&second), &DiffOptions { renames: true }).unwrap();
+        assert_eq!(renames.len(), 5);
+        assert_eq!(renames[1].kind, ChangeKind::Renamed("album/b.toml".to_owned()));
+        assert_eq!(renames[1].path, "album/c.toml");
+        assert_eq!(renames[1].old_id, renames[1].new_id);
+
+        let all = diff_trees(&pack, None, Some(&second), &DiffOptions::default()).unwrap();
+        assert_eq!(all.len(), 5);
+        assert!(all.iter().all(|c| c.kind == ChangeKind::Added));
+        assert!(diff_trees(&pack, Some(&second), Some(&second), &DiffOptions::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_type_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path();
+        git(work, &["init", "-q"]);
+        let first = commit(work, &[("link", "README.md")], "Initial commit");
+        std::fs::remove_file(work.join("link")).unwrap();
+        git(work, &["update-index", "--add", "--cacheinfo", &format!("120000,{},link", git(work, &["rev-parse", "HEAD:link"]).trim())]);
+        git(work, &["commit", "-q", "-m", "Symlink"]);
+        let second = git(work, &["rev-parse", "HEAD"]);
+
+        let pack = Pack::from_reader(&mut Cursor::new(pack_objects(work, second.trim()))).unwrap();
+        let changes = diff_trees(&pack, Some(&unhex(&first).unwrap()), Some(&unhex(second.trim()).unwrap()), &DiffOptions { renames: true }).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].kind, ChangeKind::TypeChanged);
+        assert_eq!(changes[0].new_mode, TreeEntry::MODE_SYMLINK);
+    }
+}
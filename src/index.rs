@@ -0,0 +1,134 @@
+//! https://git-scm.com/docs/pack-format#_version_2_pack_idx_files_support_packs_larger_than_4_gib_and
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Write};
+use crate::oid::{HashAlgorithm, ObjectId};
+use crate::Pack;
+
+/// Write version 2 pack index of `pack` to `writer`, and return the checksum of the index.
+///
+/// ```text
+/// header = "\377tOc" version(4)
+/// fanout = 256 * count(4)
+/// ids = N * id(20 or 32)
+/// crc32 = N * crc(4)
+/// offsets = N * offset(4)
+/// large-offsets = M * offset(8)
+/// trailer = pack-checksum index-checksum
+/// ```
+///
+/// Ids and checksums are hashed with [Pack::algorithm].
+/// All deltas in `pack` must be resolved, as the id of every object is needed.
+pub fn write_index<W: Write>(writer: &mut W, pack: &Pack) -> std::io::Result<ObjectId> {
+    if pack.unresolved().next().is_some() {
+        return Err(Error::new(ErrorKind::InvalidInput, "pack has unresolved deltas"));
+    }
+
+    let entries = pack.objects.iter()
+        .map(|(id, object)| (*id, object.offset, pack.crc32(object.offset).unwrap_or(0)))
+        .collect();
+    write_entries(writer, entries, &pack.checksum, pack.algorithm)
+}
+
+/// Write version 2 pack index of `(id, offset, crc32)` entries of the pack with `checksum`
+pub(crate) fn write_entries<W: Write>(writer: &mut W, mut entries: Vec<(ObjectId, usize, u32)>, checksum: &ObjectId, algorithm: HashAlgorithm) -> std::io::Result<ObjectId> {
+    entries.sort_unstable_by_key(|(id, _, _)| *id);
+
+    let mut out = Vec::with_capacity(8 + 256 * 4 + entries.len() * 28 + 40);
+    out.extend_from_slice(b"\xfftOc");
+    out.extend_from_slice(&2u32.to_be_bytes());
+
+    let mut fanout = [0u32; 256];
+    for (id, _, _) in entries.iter() {
+        fanout[id.as_bytes()[0] as usize] += 1;
+    }
+    let mut count = 0;
+    for n in fanout.iter() {
+        count += n;
+        out.extend_from_slice(&count.to_be_bytes());
+    }
+
+    for (id, _, _) in entries.iter() {
+        out.extend_from_slice(id.as_bytes());
+    }
+    for (_, _, crc) in entries.iter() {
+        out.extend_from_slice(&crc.to_be_bytes());
+    }
+    let mut large_offsets = Vec::new();
+    for (_, offset, _) in entries.iter() {
+        let offset = *offset as u64;
+        if offset < 0x80000000 {
+            out.extend_from_slice(&(offset as u32).to_be_bytes());
+        } else {
+            out.extend_from_slice(&(0x80000000 | large_offsets.len() as u32).to_be_bytes());
+            large_offsets.push(offset);
+        }
+    }
+    for offset in large_offsets {
+        out.extend_from_slice(&offset.to_be_bytes());
+    }
+
+    out.extend_from_slice(checksum.as_bytes());
+    let checksum = algorithm.digest(&out);
+    out.extend_from_slice(checksum.as_bytes());
+    writer.write_all(&out)?;
+    Ok(checksum)
+}
+
+/// Entries of a version 2 pack index, as read by [read_index]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Index {
+    /// `(id, offset, crc32)` of each object, sorted by id
+    pub entries: Vec<(ObjectId, usize, u32)>,
+    /// Checksum of the pack the index belongs to
+    pub pack_checksum: ObjectId,
+}
+
+/// Read version 2 pack index `data` written with `algorithm`
+///
+/// The index checksum is verified, so that a truncated or corrupted index is not used.
+pub fn read_index(data: &[u8], algorithm: HashAlgorithm) -> std::io::Result<Index> {
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_owned());
+    let size = algorithm.size();
+    if data.len() < 8 + 256 * 4 + 2 * size {
+        return Err(invalid("index is truncated"));
+    }
+    if &data[..4] != b"\xfftOc" || data[4..8] != 2u32.to_be_bytes() {
+        return Err(invalid("unsupported index version"));
+    }
+    let (content, checksum) = data.split_at(data.len() - size);
+    if algorithm.digest(content).as_bytes() != checksum {
+        return Err(invalid("index checksum mismatch"));
+    }
+
+    let u32_at = |at: usize| u32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+    let count = u32_at(8 + 255 * 4) as usize;
+    let ids = 8 + 256 * 4;
+    let crcs = ids + count * size;
+    let offsets = crcs + count * 4;
+    let large_offsets = offsets + count * 4;
+    if content.len() < large_offsets + size {
+        return Err(invalid("index is truncated"));
+    }
+    let large_count = (content.len() - large_offsets - size) / 8;
+
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let id = ObjectId::from_bytes(&data[ids + i * size..ids + (i + 1) * size]).unwrap();
+        let crc32 = u32_at(crcs + i * 4);
+        let offset = u32_at(offsets + i * 4);
+        let offset = if offset & 0x80000000 == 0 {
+            offset as u64
+        } else {
+            let n = (offset & 0x7fffffff) as usize;
+            if n >= large_count {
+                return Err(invalid("invalid large offset"));
+            }
+            let at = large_offsets + n * 8;
+            u64::from_be_bytes(data[at..at + 8].try_into().unwrap())
+        };
+        entries.push((id, offset as usize, crc32));
+    }
+    let pack_checksum = ObjectId::from_bytes(&content[content.len() - size..]).unwrap();
+    Ok(Index { entries, pack_checksum })
+}
@@ -1,4 +1,4 @@
-/// https://git-scm.com/docs/protocol-common
+//! https://git-scm.com/docs/protocol-common
 
 use std::io::{Read, Write};
 use std::convert::TryInto;
@@ -65,16 +65,34 @@ pub fn read_pktline<R: Read>(reader: &mut R) -> std::io::Result<(Vec<u8>, usize)
 ///  When the grammar indicate PKT-LINE(...), unless otherwise noted the usual pkt-line LF rules apply:
 ///  the sender SHOULD include a LF, but the receiver MUST NOT complain if it is not present.
 pub fn write_pktline<W: Write>(writer: &mut W, data: &str) -> std::io::Result<()> {
-    writer.write(format!("{:04x}", data.len() + 1 + 4).as_bytes())?;
-    writer.write(data.as_bytes())?;
-    writer.write(b"\n")?;
+    writer.write_all(format!("{:04x}", data.len() + 1 + 4).as_bytes())?;
+    writer.write_all(data.as_bytes())?;
+    writer.write_all(b"\n")?;
     Ok(())
 }
 
 /// Write pkt line without the padding LF character
 pub fn write_pktline_nolf<W: Write>(writer: &mut W, data: &str) -> std::io::Result<()> {
-    writer.write(format!("{:04x}", data.len() + 4).as_bytes())?;
-    writer.write(data.as_bytes())?;
+    writer.write_all(format!("{:04x}", data.len() + 4).as_bytes())?;
+    writer.write_all(data.as_bytes())?;
+    Ok(())
+}
+
+/// Maximum length of data in a sideband packet, excluding length and band number
+const SIDEBAND_LEN: usize = 65515;
+
+/// Write `data` to sideband `band` in as many packets as needed
+///
+/// ```text
+/// PKT-LINE(band data)
+/// band = 1 (pack data) | 2 (progress) | 3 (fatal error)
+/// ```
+pub fn write_sideband<W: Write>(writer: &mut W, band: u8, data: &[u8]) -> std::io::Result<()> {
+    for chunk in data.chunks(SIDEBAND_LEN) {
+        writer.write_all(format!("{:04x}", chunk.len() + 1 + 4).as_bytes())?;
+        writer.write_all(&[band])?;
+        writer.write_all(chunk)?;
+    }
     Ok(())
 }
 
@@ -82,13 +100,13 @@ pub fn write_pktline_nolf<W: Write>(writer: &mut W, data: &str) -> std::io::Resu
 /// 0001 Delimiter Packet
 /// 0002 Response End Packet
 pub(crate) fn write_packet<W: Write>(writer: &mut W, data: u8) -> std::io::Result<()> {
-    writer.write(format!("{:04x}", data).as_bytes())?;
+    writer.write_all(format!("{:04x}", data).as_bytes())?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::io::{write_pktline, read_pktline, take_sized, token, u8, u32_be, read_len, write_pktline_nolf, write_packet};
+    use crate::io::{write_pktline, read_pktline, take_sized, token, u8, u32_be, read_len, write_pktline_nolf, write_packet, write_sideband};
     use std::io::{Read, Cursor};
 
     #[test]
@@ -204,4 +222,19 @@ mod tests {
         write_packet(&mut cursor, 0).expect("failed to write packet");
         assert_eq!(cursor.into_inner(), b"0009test\n0010another_test0000")
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_sideband_write() {
+        let mut out = Vec::new();
+        write_sideband(&mut out, 2, b"progress").unwrap();
+        assert_eq!(out, b"000d\x02progress");
+
+        let mut out = Vec::new();
+        write_sideband(&mut out, 1, &vec![0; 70000]).unwrap();
+        let mut cursor = std::io::Cursor::new(out);
+        let (first, len) = read_pktline(&mut cursor).unwrap();
+        assert_eq!((first.len(), len), (65516, 65520));
+        let (second, _) = read_pktline(&mut cursor).unwrap();
+        assert_eq!(second.len(), 70000 - 65515 + 1);
+    }
+}
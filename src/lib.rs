@@ -15,7 +15,7 @@
 //!
 //! fn main() {
 //!     let client = Client::new("https://github.com/project-anni/repo.git");
-//!     let iter = client.request(
+//!     let mut iter = client.request(
 //!         RequestBuilder::new(true)
 //!             .command("fetch")
 //!             .argument("thin-pack")
@@ -26,12 +26,14 @@
 //!             .build()
 //!     ).unwrap();
 //!     let mut pack = Vec::new();
-//!     for msg in iter {
+//!     for msg in &mut iter {
 //!         match msg {
 //!             PackData(mut d) => pack.append(&mut d),
 //!             _ => {}
 //!         }
 //!     }
+//!     // errors like a timeout end the iteration early
+//!     iter.finish().expect("response cut off");
 //!     let mut cursor = Cursor::new(pack);
 //!     Pack::from_reader(&mut cursor).expect("invalid pack file");
 //! }
@@ -41,13 +43,37 @@
 //! For example, you can just receive `Message::PackData` and
 //! write the content to a `pak` file.
 
+// `ClientError` wraps `ureq::Error` unboxed, it is part of the public API
+#![allow(clippy::result_large_err, clippy::large_enum_variant)]
+
 // TODO: no_std support
 // #![no_std]
 
 pub mod io;
+pub mod cancel;
+pub mod oid;
 pub mod pack;
+pub mod packfile;
+pub mod progress;
+pub mod object;
+pub mod checkout;
+pub mod view;
+pub mod diff;
+pub mod revwalk;
+pub mod connectivity;
+pub mod server;
+pub mod push;
+pub mod bundle;
 pub mod client;
+pub mod refspec;
+pub mod index;
+pub mod verify;
+pub mod repo;
 mod utils;
+#[cfg(test)]
+mod testing;
 
-pub use client::Client;
+pub use client::{Client, ClientBuilder};
+pub use oid::{HashAlgorithm, ObjectId};
 pub use pack::Pack;
+pub use repo::{clone_bare, CloneOptions, FetchOptions, Repository};
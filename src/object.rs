@@ -0,0 +1,643 @@
+//! Parsers and serializers of commit, tree and tag objects.
+//!
+//! https://git-scm.com/book/en/v2/Git-Internals-Git-Objects
+
+use thiserror::Error;
+use crate::oid::{HashAlgorithm, ObjectId};
+use crate::pack::{Object, ObjectType};
+use crate::utils::unhex;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum ObjectError {
+    #[error("expected {0} object, got {1}")]
+    UnexpectedType(&'static str, &'static str),
+    #[error("missing header {0}")]
+    MissingHeader(&'static str),
+    #[error("invalid header {0}")]
+    InvalidHeader(String),
+    #[error("invalid object id")]
+    InvalidId,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("invalid tree entry")]
+    InvalidTreeEntry,
+    #[error("invalid utf-8 data")]
+    InvalidUtf8,
+}
+
+/// Author, committer or tagger of an object
+///
+/// ```text
+/// name SP "<" email ">" SP timestamp SP timezone
+/// ```
+///
+/// Name and email are kept as raw bytes, their encoding is given by the `encoding` header of a commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub name: Vec<u8>,
+    pub email: Vec<u8>,
+    /// Seconds since unix epoch
+    pub time: i64,
+    /// Timezone as written in object, like `+0800`
+    pub timezone: String,
+}
+
+impl Signature {
+    /// Offset of [Signature::timezone] from UTC in seconds
+    pub fn utc_offset(&self) -> i32 {
+        let tz = self.timezone.as_bytes();
+        if tz.len() != 5 {
+            return 0;
+        }
+        let hours: i32 = self.timezone[1..3].parse().unwrap_or(0);
+        let minutes: i32 = self.timezone[3..5].parse().unwrap_or(0);
+        let offset = (hours * 60 + minutes) * 60;
+        if tz[0] == b'-' { -offset } else { offset }
+    }
+
+    pub fn parse(input: &[u8]) -> Result<Self, ObjectError> {
+        let start = input.iter().position(|b| *b == b'<').ok_or(ObjectError::InvalidSignature)?;
+        let end = input.iter().rposition(|b| *b == b'>').ok_or(ObjectError::InvalidSignature)?;
+        if end < start {
+            return Err(ObjectError::InvalidSignature);
+        }
+        let rest = std::str::from_utf8(&input[end + 1..]).map_err(|_| ObjectError::InvalidSignature)?;
+        let mut rest = rest.trim_start().splitn(2, ' ');
+        let time = rest.next().and_then(|t| t.parse().ok()).ok_or(ObjectError::InvalidSignature)?;
+        let timezone = rest.next().ok_or(ObjectError::InvalidSignature)?;
+        Ok(Self {
+            name: input[..start].trim_ascii_end().to_vec(),
+            email: input[start + 1..end].to_vec(),
+            time,
+            timezone: timezone.to_owned(),
+        })
+    }
+}
+
+/// A commit object
+///
+/// All headers are kept in [Commit::headers] in order, as raw bytes. `tree`, `parent`, `author` and
+/// `committer` are also parsed into their own fields for convenience.
+/// [Commit::to_bytes] writes back [Commit::headers] and [Commit::message] only, so that the result
+/// is byte-identical to the parsed data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    pub tree: ObjectId,
+    pub parents: Vec<ObjectId>,
+    pub author: Signature,
+    pub committer: Signature,
+    pub headers: Headers,
+    /// Whether an empty line follows the headers, which objects without message may lack
+    pub separator: bool,
+    /// Commit message, which may not be utf-8 if `encoding` header is set
+    pub message: Vec<u8>,
+}
+
+impl Commit {
+    pub fn parse(data: &[u8]) -> Result<Self, ObjectError> {
+        let (headers, separator, message) = split_headers(data)?;
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        for (key, value) in headers.iter() {
+            match key.as_slice() {
+                b"tree" if tree.is_none() => tree = Some(parse_id(value)?),
+                b"parent" => parents.push(parse_id(value)?),
+                b"author" if author.is_none() => author = Some(Signature::parse(value)?),
+                b"committer" if committer.is_none() => committer = Some(Signature::parse(value)?),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            tree: tree.ok_or(ObjectError::MissingHeader("tree"))?,
+            parents,
+            author: author.ok_or(ObjectError::MissingHeader("author"))?,
+            committer: committer.ok_or(ObjectError::MissingHeader("committer"))?,
+            headers,
+            separator,
+            message: message.to_vec(),
+        })
+    }
+
+    /// Value of the first header named `key`
+    ///
+    /// Values of multi-line headers (like `gpgsig` and `mergetag`) are joined with `\n`,
+    /// without the leading space of continuation lines.
+    pub fn header(&self, key: &str) -> Option<&[u8]> {
+        find_header(&self.headers, key)
+    }
+
+    /// Encoding of the message, names and emails, utf-8 if `None`
+    pub fn encoding(&self) -> Option<&[u8]> {
+        self.header("encoding")
+    }
+
+    pub fn gpgsig(&self) -> Option<&[u8]> {
+        self.header("gpgsig")
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        write_object(&self.headers, self.separator, &self.message)
+    }
+}
+
+/// An entry of tree object
+///
+/// ```text
+/// mode SP name NUL id(20 or 32)
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+    pub mode: u32,
+    /// Mode as written in the tree if it is not canonical, like zero-padded `040000` of old trees,
+    /// written back by [Tree::to_bytes] instead of [TreeEntry::mode]
+    pub raw_mode: Option<Vec<u8>>,
+    /// File name as raw bytes, which may not be utf-8
+    pub name: Vec<u8>,
+    pub id: ObjectId,
+}
+
+impl TreeEntry {
+    pub const MODE_TREE: u32 = 0o040000;
+    pub const MODE_BLOB: u32 = 0o100644;
+    pub const MODE_EXECUTABLE: u32 = 0o100755;
+    pub const MODE_SYMLINK: u32 = 0o120000;
+    pub const MODE_GITLINK: u32 = 0o160000;
+
+    pub fn is_tree(&self) -> bool {
+        self.mode == Self::MODE_TREE
+    }
+
+    /// Whether this entry is a regular file or a symlink
+    pub fn is_blob(&self) -> bool {
+        is_blob_mode(self.mode)
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.mode == Self::MODE_EXECUTABLE
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode == Self::MODE_SYMLINK
+    }
+
+    /// Whether this entry is a submodule commit
+    pub fn is_gitlink(&self) -> bool {
+        self.mode == Self::MODE_GITLINK
+    }
+}
+
+/// A tree object
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Tree {
+    pub entries: Vec<TreeEntry>,
+}
+
+impl Tree {
+    /// Parse a tree with SHA-1 entry ids
+    pub fn parse(data: &[u8]) -> Result<Self, ObjectError> {
+        Self::parse_with(data, HashAlgorithm::Sha1)
+    }
+
+    /// Parse a tree with entry ids of `algorithm`
+    pub fn parse_with(data: &[u8], algorithm: HashAlgorithm) -> Result<Self, ObjectError> {
+        let entries = raw_tree_entries(data, algorithm)
+            .map(|e| e.map(|(raw_mode, mode, name, id)| {
+                let raw_mode = Some(raw_mode).filter(|raw| *raw != format!("{:o}", mode).as_bytes()).map(|raw| raw.to_vec());
+                TreeEntry { mode, raw_mode, name: name.to_vec(), id }
+            }))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { entries })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.entries.len() * 48);
+        for entry in self.entries.iter() {
+            match &entry.raw_mode {
+                Some(mode) => out.extend_from_slice(mode),
+                None => out.extend_from_slice(format!("{:o}", entry.mode).as_bytes()),
+            }
+            out.push(b' ');
+            out.extend_from_slice(&entry.name);
+            out.push(0);
+            out.extend_from_slice(entry.id.as_bytes());
+        }
+        out
+    }
+
+    pub fn get<N: AsRef<[u8]>>(&self, name: N) -> Option<&TreeEntry> {
+        self.entries.iter().find(|e| e.name == name.as_ref())
+    }
+}
+
+/// An annotated tag object
+///
+/// Like [Commit], all headers are kept in [Tag::headers] and written back verbatim by [Tag::to_bytes].
+/// Signature of a signed tag is part of [Tag::message], as git appends it to the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub object: ObjectId,
+    pub object_type: ObjectType,
+    pub tag: Vec<u8>,
+    /// Missing in some old tags
+    pub tagger: Option<Signature>,
+    pub headers: Headers,
+    /// See [Commit::separator]
+    pub separator: bool,
+    pub message: Vec<u8>,
+}
+
+impl Tag {
+    pub fn parse(data: &[u8]) -> Result<Self, ObjectError> {
+        let (headers, separator, message) = split_headers(data)?;
+        let mut object = None;
+        let mut object_type = None;
+        let mut tag = None;
+        let mut tagger = None;
+        for (key, value) in headers.iter() {
+            match key.as_slice() {
+                b"object" if object.is_none() => object = Some(parse_id(value)?),
+                b"type" if object_type.is_none() => object_type = Some(match value.as_slice() {
+                    b"commit" => ObjectType::Commit,
+                    b"tree" => ObjectType::Tree,
+                    b"blob" => ObjectType::Blob,
+                    b"tag" => ObjectType::Tag,
+                    _ => return Err(ObjectError::InvalidHeader(String::from_utf8_lossy(value).into_owned())),
+                }),
+                b"tag" if tag.is_none() => tag = Some(value.clone()),
+                b"tagger" if tagger.is_none() => tagger = Some(Signature::parse(value)?),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            object: object.ok_or(ObjectError::MissingHeader("object"))?,
+            object_type: object_type.ok_or(ObjectError::MissingHeader("type"))?,
+            tag: tag.ok_or(ObjectError::MissingHeader("tag"))?,
+            tagger,
+            headers,
+            separator,
+            message: message.to_vec(),
+        })
+    }
+
+    /// Value of the first header named `key`, see [Commit::header]
+    pub fn header(&self, key: &str) -> Option<&[u8]> {
+        find_header(&self.headers, key)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        write_object(&self.headers, self.separator, &self.message)
+    }
+}
+
+impl Object {
+    /// Parse data of a commit object
+    pub fn commit(&self) -> Result<Commit, ObjectError> {
+        self.expect(ObjectType::Commit)?;
+        Commit::parse(&self.data)
+    }
+
+    /// Parse data of a tree object
+    pub fn tree(&self) -> Result<Tree, ObjectError> {
+        self.expect(ObjectType::Tree)?;
+        Tree::parse_with(&self.data, self.algorithm)
+    }
+
+    /// Parse data of a tag object
+    pub fn tag(&self) -> Result<Tag, ObjectError> {
+        self.expect(ObjectType::Tag)?;
+        Tag::parse(&self.data)
+    }
+
+    fn expect(&self, object_type: ObjectType) -> Result<(), ObjectError> {
+        if self.object_type == object_type {
+            Ok(())
+        } else {
+            Err(ObjectError::UnexpectedType(object_type.name(), self.object_type.name()))
+        }
+    }
+}
+
+/// Headers of a commit or tag as `(key, value)` in order
+pub type Headers = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn parse_id(input: &[u8]) -> Result<ObjectId, ObjectError> {
+    std::str::from_utf8(input).ok().and_then(unhex).ok_or(ObjectError::InvalidId)
+}
+
+fn find_header<'a>(headers: &'a Headers, key: &str) -> Option<&'a [u8]> {
+    headers.iter().find(|(k, _)| k == key.as_bytes()).map(|(_, v)| v.as_slice())
+}
+
+/// Split object data into headers and message
+///
+/// Continuation lines starting with a space are joined to the previous header with `\n`.
+fn split_headers(data: &[u8]) -> Result<(Headers, bool, &[u8]), ObjectError> {
+    let mut headers: Headers = Vec::new();
+    let mut rest = data;
+    let mut separator = false;
+    loop {
+        let (line, next) = match rest.iter().position(|b| *b == b'\n') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            // objects without message may not end with an empty line
+            None => (rest, &rest[rest.len()..]),
+        };
+        rest = next;
+        if line.is_empty() {
+            separator = true;
+            break;
+        }
+
+        let invalid = || ObjectError::InvalidHeader(String::from_utf8_lossy(line).into_owned());
+        if let Some(continuation) = line.strip_prefix(b" ") {
+            let (_, value) = headers.last_mut().ok_or_else(invalid)?;
+            value.push(b'\n');
+            value.extend_from_slice(continuation);
+        } else {
+            let space = line.iter().position(|b| *b == b' ').ok_or_else(invalid)?;
+            headers.push((line[..space].to_vec(), line[space + 1..].to_vec()));
+        }
+        if rest.is_empty() {
+            break;
+        }
+    }
+    Ok((headers, separator, rest))
+}
+
+/// Entries of tree `data` as `(mode, name, id)`, without copying names
+pub(crate) fn tree_entries(data: &[u8], algorithm: HashAlgorithm) -> impl Iterator<Item = Result<(u32, &[u8], ObjectId), ObjectError>> {
+    raw_tree_entries(data, algorithm).map(|e| e.map(|(_, mode, name, id)| (mode, name, id)))
+}
+
+/// Tree entry as `(raw mode, mode, name, id)`
+type RawTreeEntry<'a> = (&'a [u8], u32, &'a [u8], ObjectId);
+
+/// Entries of tree `data`, with modes as written
+fn raw_tree_entries(data: &[u8], algorithm: HashAlgorithm) -> impl Iterator<Item = Result<RawTreeEntry<'_>, ObjectError>> {
+    let len = algorithm.size();
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let entry = (|| {
+            let space = rest.iter().position(|b| *b == b' ').ok_or(ObjectError::InvalidTreeEntry)?;
+            let nul = rest.iter().position(|b| *b == 0).ok_or(ObjectError::InvalidTreeEntry)?;
+            if space == 0 || nul < space || rest.len() < nul + 1 + len {
+                return Err(ObjectError::InvalidTreeEntry);
+            }
+            let mode = rest[..space].iter().try_fold(0u32, |mode, b| match b {
+                b'0'..=b'7' => mode.checked_mul(8).map(|m| m + (b - b'0') as u32),
+                _ => None,
+            }).ok_or(ObjectError::InvalidTreeEntry)?;
+            let id = ObjectId::from_bytes(&rest[nul + 1..nul + 1 + len]).unwrap();
+            Ok((&rest[..space], mode, &rest[space + 1..nul], id, nul + 1 + len))
+        })();
+        Some(match entry {
+            Ok((raw_mode, mode, name, id, end)) => {
+                rest = &rest[end..];
+                Ok((raw_mode, mode, name, id))
+            }
+            Err(e) => {
+                rest = &[];
+                Err(e)
+            }
+        })
+    })
+}
+
+/// Whether a tree entry of `mode` is a regular file or a symlink
+pub(crate) fn is_blob_mode(mode: u32) -> bool {
+    mode & 0o170000 == 0o100000 || mode == TreeEntry::MODE_SYMLINK
+}
+
+/// `tree` and `parent` ids of commit `data`, read from the raw headers
+pub(crate) fn commit_links(data: &[u8]) -> Result<(ObjectId, Vec<ObjectId>), ObjectError> {
+    let mut tree = None;
+    let mut parents = Vec::new();
+    for line in header_lines(data) {
+        if let Some(id) = line.strip_prefix(b"tree ") {
+            if tree.is_none() {
+                tree = Some(parse_id(id)?);
+            }
+        } else if let Some(id) = line.strip_prefix(b"parent ") {
+            parents.push(parse_id(id)?);
+        }
+    }
+    Ok((tree.ok_or(ObjectError::MissingHeader("tree"))?, parents))
+}
+
+/// `object` id and `type` of tag `data`, read from the raw headers
+pub(crate) fn tag_target(data: &[u8]) -> Result<(ObjectId, ObjectType), ObjectError> {
+    let mut object = None;
+    let mut object_type = None;
+    for line in header_lines(data) {
+        if let Some(id) = line.strip_prefix(b"object ") {
+            if object.is_none() {
+                object = Some(parse_id(id)?);
+            }
+        } else if let Some(name) = line.strip_prefix(b"type ") {
+            if object_type.is_none() {
+                object_type = Some(match name {
+                    b"commit" => ObjectType::Commit,
+                    b"tree" => ObjectType::Tree,
+                    b"blob" => ObjectType::Blob,
+                    b"tag" => ObjectType::Tag,
+                    _ => return Err(ObjectError::InvalidHeader(String::from_utf8_lossy(name).into_owned())),
+                });
+            }
+        }
+    }
+    Ok((object.ok_or(ObjectError::MissingHeader("object"))?, object_type.ok_or(ObjectError::MissingHeader("type"))?))
+}
+
+/// Header lines of object `data` up to the empty line, continuation lines included
+fn header_lines(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split(|b| *b == b'\n').take_while(|line| !line.is_empty())
+}
+
+fn write_object(headers: &Headers, separator: bool, message: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(256 + message.len());
+    for (key, value) in headers.iter() {
+        out.extend_from_slice(key);
+        out.push(b' ');
+        for (i, line) in value.split(|b| *b == b'\n').enumerate() {
+            if i > 0 {
+                out.extend_from_slice(b"\n ");
+            }
+            out.extend_from_slice(line);
+        }
+        out.push(b'\n');
+    }
+    if separator || !message.is_empty() {
+        out.push(b'\n');
+    }
+    out.extend_from_slice(message);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::{Commit, Signature, Tag, Tree, TreeEntry, ObjectError};
+    use crate::pack::ObjectType;
+    use crate::testing::{git, fixture};
+    use crate::oid::{HashAlgorithm, ObjectId};
+    use crate::utils::{git_hash, unhex};
+
+    #[test]
+    fn test_signature() {
+        let sig = Signature::parse(b"yesterday17 <mmf@mmf.moe> 1615876429 -0130").unwrap();
+        assert_eq!(sig.name, b"yesterday17");
+        assert_eq!(sig.email, b"mmf@mmf.moe");
+        assert_eq!(sig.time, 1615876429);
+        assert_eq!(sig.utc_offset(), -5400);
+        assert_eq!(Signature::parse(b"yesterday17 mmf@mmf.moe 1615876429 +0800"), Err(ObjectError::InvalidSignature));
+    }
+
+    #[test]
+    fn test_commit() {
+        let data = b"tree 90d83dbf6a598d66405eb0b4baad14990d0f2755
+parent 9192b5e5f2941fd76aa5a08043dc8aa6a31831a2
+parent da32dc7b28d73b67dcbb894daf862538615d7765
+author yesterday17 <mmf@mmf.moe> 1615876429 +0800
+committer yesterday17 <mmf@mmf.moe> 1615876429 +0800
+encoding ISO-8859-1
+mergetag object da32dc7b28d73b67dcbb894daf862538615d7765
+ type commit
+ tag v1
+ tagger yesterday17 <mmf@mmf.moe> 1615876429 +0800
+\x20
+ v1
+gpgsig -----BEGIN PGP SIGNATURE-----
+\x20
+ iQEzBAABCAAdFiEE
+ -----END PGP SIGNATURE-----
+
+Merge tag 'v1'
+";
+        let commit = Commit::parse(data).unwrap();
+        assert_eq!(commit.tree, unhex("90d83dbf6a598d66405eb0b4baad14990d0f2755").unwrap());
+        assert_eq!(commit.parents.len(), 2);
+        assert_eq!(commit.committer.timezone, "+0800");
+        assert_eq!(commit.encoding(), Some(&b"ISO-8859-1"[..]));
+        assert!(commit.header("mergetag").unwrap().ends_with(b"+0800\n\nv1"));
+        assert_eq!(commit.gpgsig(), Some(&b"-----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n-----END PGP SIGNATURE-----"[..]));
+        assert_eq!(commit.message, b"Merge tag 'v1'\n");
+        assert_eq!(commit.to_bytes(), data.to_vec());
+
+        assert_eq!(Commit::parse(b"tree 90d83dbf6a598d66405eb0b4baad14990d0f2755\n\nmsg"), Err(ObjectError::MissingHeader("author")));
+        assert_eq!(Commit::parse(b"tree 90d83dbf\n"), Err(ObjectError::InvalidId));
+    }
+
+    #[test]
+    fn test_tree() {
+        let mut data = b"100644 README.md\0".to_vec();
+        data.extend_from_slice(&[0xaa; 20]);
+        data.extend_from_slice(b"40000 album\0");
+        data.extend_from_slice(&[0xbb; 20]);
+        let tree = Tree::parse(&data).unwrap();
+        assert_eq!(tree.entries, vec![
+            TreeEntry { mode: TreeEntry::MODE_BLOB, raw_mode: None, name: b"README.md".to_vec(), id: ObjectId::Sha1([0xaa; 20]) },
+            TreeEntry { mode: TreeEntry::MODE_TREE, raw_mode: None, name: b"album".to_vec(), id: ObjectId::Sha1([0xbb; 20]) },
+        ]);
+        assert!(tree.get("album").unwrap().is_tree());
+        assert_eq!(tree.to_bytes(), data);
+        assert_eq!(Tree::parse(&data[..data.len() - 1]), Err(ObjectError::InvalidTreeEntry));
+    }
+
+    #[test]
+    fn test_zero_padded_mode() {
+        // written by old versions of git, fsck only warns about it
+        let mut data = b"040000 album\0".to_vec();
+        data.extend_from_slice(&[0xbb; 20]);
+        let id = git_hash(HashAlgorithm::Sha1, "tree", &data);
+        let tree = Tree::parse(&data).unwrap();
+        assert!(tree.entries[0].is_tree());
+        assert_eq!(tree.entries[0].raw_mode.as_deref(), Some(&b"040000"[..]));
+        assert_eq!(git_hash(HashAlgorithm::Sha1, "tree", &tree.to_bytes()), id);
+    }
+
+    #[test]
+    fn test_without_separator() {
+        // no empty line after the headers, as the message is empty
+        let data = b"tree 90d83dbf6a598d66405eb0b4baad14990d0f2755
+author A <a@example.com> 1615876429 +0100
+committer A <a@example.com> 1615876429 +0100
+";
+        let id = git_hash(HashAlgorithm::Sha1, "commit", data);
+        let commit = Commit::parse(data).unwrap();
+        assert!(!commit.separator && commit.message.is_empty());
+        assert_eq!(git_hash(HashAlgorithm::Sha1, "commit", &commit.to_bytes()), id);
+
+        let data = b"object 90d83dbf6a598d66405eb0b4baad14990d0f2755
+type commit
+tag v1
+";
+        let id = git_hash(HashAlgorithm::Sha1, "tag", data);
+        let tag = Tag::parse(data).unwrap();
+        assert!(!tag.separator);
+        assert_eq!(git_hash(HashAlgorithm::Sha1, "tag", &tag.to_bytes()), id);
+
+        // an empty message after the separator is kept too
+        let data = b"object 90d83dbf6a598d66405eb0b4baad14990d0f2755\ntype commit\ntag v1\n\n";
+        let tag = Tag::parse(data).unwrap();
+        assert!(tag.separator);
+        assert_eq!(tag.to_bytes(), data.to_vec());
+    }
+
+    #[test]
+    fn test_non_canonical() {
+        // latin-1 names and message, headers out of git's order, and an unknown header
+        let data = b"tree 90d83dbf6a598d66405eb0b4baad14990d0f2755
+encoding ISO-8859-1
+committer J\xf6rg <j@example.com> 1615876429 +0100
+author J\xf6rg  <j@example.com>  1615876429 +0100
+x-custom  two  spaces
+ continued
+
+Gr\xfc\xdfe
+";
+        let commit = Commit::parse(data).unwrap();
+        assert_eq!(commit.author.name, b"J\xf6rg");
+        assert_eq!(commit.header("x-custom"), Some(&b" two  spaces\ncontinued"[..]));
+        assert_eq!(commit.to_bytes(), data.to_vec());
+        assert_eq!(git_hash(HashAlgorithm::Sha1, "commit", &commit.to_bytes()), git_hash(HashAlgorithm::Sha1, "commit", data));
+
+        let mut data = b"100644 caf\xe9.txt\0".to_vec();
+        data.extend_from_slice(&[0xaa; 20]);
+        let tree = Tree::parse(&data).unwrap();
+        assert_eq!(tree.get(b"caf\xe9.txt").unwrap().id, ObjectId::Sha1([0xaa; 20]));
+        assert_eq!(tree.to_bytes(), data);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let repo = dir.path().join("repo.git");
+        for line in git(&repo, &["cat-file", "--batch-all-objects", "--batch-check"]).lines() {
+            let mut parts = line.split(' ');
+            let (id, kind) = (parts.next().unwrap(), parts.next().unwrap());
+            let data = std::process::Command::new("git")
+                .current_dir(&repo)
+                .args(["cat-file", kind, id])
+                .output()
+                .unwrap()
+                .stdout;
+            let bytes = match kind {
+                "commit" => Commit::parse(&data).unwrap().to_bytes(),
+                "tree" => Tree::parse(&data).unwrap().to_bytes(),
+                "tag" => {
+                    let tag = Tag::parse(&data).unwrap();
+                    assert_eq!(tag.object_type, ObjectType::Commit);
+                    tag.to_bytes()
+                }
+                _ => continue,
+            };
+            assert_eq!(git_hash(HashAlgorithm::Sha1, kind, &bytes), unhex(id).unwrap());
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Object ids and the hash algorithms computing them.
+//!
+//! https://git-scm.com/docs/hash-function-transition
+
+use sha1::Digest;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use thiserror::Error;
+use crate::utils::{hex, unhex};
+
+/// Minimum length of abbreviated ids, like `core.abbrev` of git
+pub const MIN_ABBREV: usize = 4;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ObjectIdError {
+    #[error("invalid object id {0}")]
+    InvalidId(String),
+    #[error("abbreviated id {0} is too short")]
+    TooShort(String),
+    #[error("abbreviated id {0} is ambiguous")]
+    Ambiguous(String),
+    #[error("no object matches {0}")]
+    NotFound(String),
+}
+
+/// Hash algorithm of a repository, advertised as `object-format` capability
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Name used in `object-format` capability
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Length of ids in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// Id made of zeros, used for missing objects in ref updates
+    pub fn null(&self) -> ObjectId {
+        match self {
+            HashAlgorithm::Sha1 => ObjectId::Sha1([0; 20]),
+            HashAlgorithm::Sha256 => ObjectId::Sha256([0; 32]),
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    /// Hash of `data`, like the trailing checksum of pack and index files
+    pub fn digest(&self, data: &[u8]) -> ObjectId {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+/// Incremental hasher of a [HashAlgorithm]
+#[derive(Clone)]
+pub enum Hasher {
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> ObjectId {
+        match self {
+            Hasher::Sha1(h) => ObjectId::Sha1(h.finalize().into()),
+            Hasher::Sha256(h) => ObjectId::Sha256(h.finalize().into()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Id of an object, the hash of its type, length and data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ObjectId {
+    Sha1([u8; 20]),
+    Sha256([u8; 32]),
+}
+
+impl ObjectId {
+    /// Id from raw bytes, whose length decides the algorithm
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        use std::convert::TryInto;
+        match bytes.len() {
+            20 => Some(ObjectId::Sha1(bytes.try_into().unwrap())),
+            32 => Some(ObjectId::Sha256(bytes.try_into().unwrap())),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            ObjectId::Sha1(id) => id,
+            ObjectId::Sha256(id) => id,
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            ObjectId::Sha1(_) => HashAlgorithm::Sha1,
+            ObjectId::Sha256(_) => HashAlgorithm::Sha256,
+        }
+    }
+
+    /// Whether the hex form of this id starts with `prefix`, ignoring case
+    pub fn starts_with(&self, prefix: &str) -> bool {
+        let bytes = self.as_bytes();
+        prefix.len() <= bytes.len() * 2 && prefix.chars().enumerate().all(|(i, c)| {
+            let nibble = if i % 2 == 0 { bytes[i / 2] >> 4 } else { bytes[i / 2] & 0xf };
+            c.to_digit(16) == Some(nibble as u32)
+        })
+    }
+
+    /// Hex form truncated to `len` characters
+    pub fn abbreviate(&self, len: usize) -> String {
+        let mut result = hex(self);
+        result.truncate(len);
+        result
+    }
+}
+
+/// Check `prefix` is an abbreviated id which can be looked up, like `git rev-parse` does
+pub(crate) fn check_prefix(prefix: &str) -> Result<(), ObjectIdError> {
+    if prefix.len() > 64 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        Err(ObjectIdError::InvalidId(prefix.to_owned()))
+    } else if prefix.len() < MIN_ABBREV {
+        Err(ObjectIdError::TooShort(prefix.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = ObjectIdError;
+
+    /// Parse 40 hex digits of SHA-1, or 64 hex digits of SHA-256
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        unhex(s).ok_or_else(|| ObjectIdError::InvalidId(s.to_owned()))
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex(self))
+    }
+}
+
+impl AsRef<[u8]> for ObjectId {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl From<[u8; 20]> for ObjectId {
+    fn from(id: [u8; 20]) -> Self {
+        ObjectId::Sha1(id)
+    }
+}
+
+impl From<[u8; 32]> for ObjectId {
+    fn from(id: [u8; 32]) -> Self {
+        ObjectId::Sha256(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::oid::{HashAlgorithm, ObjectId, ObjectIdError};
+    use crate::utils::hex;
+
+    #[test]
+    fn test_digest() {
+        assert_eq!(hex(&HashAlgorithm::Sha1.digest(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&HashAlgorithm::Sha256.digest(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        assert_eq!(ObjectId::from_bytes(&[1; 20]), Some(ObjectId::Sha1([1; 20])));
+        assert_eq!(ObjectId::from_bytes(&[1; 32]).unwrap().algorithm(), HashAlgorithm::Sha256);
+        assert_eq!(ObjectId::from_bytes(&[1; 21]), None);
+        assert_eq!(HashAlgorithm::Sha256.null().as_bytes().len(), 32);
+    }
+
+    #[test]
+    fn test_parse() {
+        let id: ObjectId = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391".parse().unwrap();
+        assert_eq!(id.to_string(), "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391");
+        assert_eq!(format!("{}", "E69DE29BB2D1D6434B8B29AE775AD8C2E48C5391".parse::<ObjectId>().unwrap()), id.to_string());
+        assert_eq!("e69de29b".parse::<ObjectId>(), Err(ObjectIdError::InvalidId("e69de29b".to_owned())));
+        assert!("e69de29bb2d1d6434b8b29ae775ad8c2e48c539\n".parse::<ObjectId>().is_err());
+
+        assert!(id.starts_with("e69d"));
+        assert!(id.starts_with("E69DE2"));
+        assert!(!id.starts_with("e69e"));
+        assert!(!id.starts_with(&format!("{}0", id)));
+        assert_eq!(id.abbreviate(7), "e69de29");
+
+        assert!(ObjectId::Sha1([1; 20]) < ObjectId::Sha1([2; 20]));
+    }
+}
@@ -1,13 +1,17 @@
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
 use miniz_oxide::{DataFormat, MZFlush};
 use miniz_oxide::inflate::TINFLStatus;
 use miniz_oxide::inflate::stream::{InflateState, MinReset};
 use thiserror::Error;
-use sha1::Digest;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use crate::io::{token, u32_be, u8};
-use std::convert::TryInto;
-use crate::utils::git_sha1;
+use crate::cancel::{self, CancellationToken, Interrupted};
+use crate::progress::{should_report, Event, Progress};
+use crate::oid::{check_prefix, HashAlgorithm, Hasher, ObjectId, ObjectIdError, MIN_ABBREV};
+use crate::utils::git_hash;
 
 const INPUT_BUFFER_SIZE: usize = 8 * 1024;
 const OUTPUT_BUFFER_SIZE: usize = 16 * 1024;
@@ -20,12 +24,22 @@ pub enum UnpackError {
     InvalidTINFLStatus(TINFLStatus),
     #[error("invalid hash")]
     InvalidHash,
+    #[error("invalid delta data")]
+    InvalidDelta,
+    #[error("invalid compressed data at offset {0}")]
+    InvalidData(usize),
+    #[error("delta base of object at offset {0} not found")]
+    MissingBase(usize),
+    #[error("unpacking cancelled")]
+    Cancelled,
+    #[error("unpacking timed out")]
+    TimedOut,
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 }
 
 /// Read git variable integer and extract (object_type, length, bytes_used).
-fn vint_from_reader<R: Read>(reader: &mut R) -> std::io::Result<(u8, usize, usize)> {
+pub(crate) fn vint_from_reader<R: Read>(reader: &mut R) -> std::io::Result<(u8, usize, usize)> {
     let mut n = u8(reader)?;
     let object_type = (n >> 4) & 0b00000111;
     let mut len = (n as usize) & 0b00001111;
@@ -33,6 +47,9 @@ fn vint_from_reader<R: Read>(reader: &mut R) -> std::io::Result<(u8, usize, usiz
     let mut shift = 4;
     let mut used = 1;
     while n & 0b10000000 != 0 {
+        if shift + 7 > usize::BITS {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "entry size overflow"));
+        }
         n = u8(reader)?;
         len |= ((n as usize) & 0b01111111) << shift;
         shift += 7;
@@ -41,12 +58,29 @@ fn vint_from_reader<R: Read>(reader: &mut R) -> std::io::Result<(u8, usize, usiz
     Ok((object_type, len, used))
 }
 
+/// Write git variable integer of object entry header, the reverse of [vint_from_reader].
+pub(crate) fn vint_to_vec(object_type: u8, len: usize) -> Vec<u8> {
+    let mut result = Vec::with_capacity(4);
+    let mut n = (object_type << 4) | (len & 0b00001111) as u8;
+    let mut len = len >> 4;
+    while len != 0 {
+        result.push(n | 0b10000000);
+        n = (len & 0b01111111) as u8;
+        len >>= 7;
+    }
+    result.push(n);
+    result
+}
+
 /// Read OFS_DELTA offset and extract (distance, bytes_used).
-fn ofs_from_reader<R: Read>(reader: &mut R) -> std::io::Result<(usize, usize)> {
+pub(crate) fn ofs_from_reader<R: Read>(reader: &mut R) -> std::io::Result<(usize, usize)> {
     let mut n = u8(reader)?;
     let mut used = 1;
     let mut distance = n as usize & 0b01111111;
     while n & 0b10000000 != 0 {
+        if distance > usize::MAX >> 8 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "delta offset overflow"));
+        }
         n = u8(reader)?;
         distance += 1;
         distance = (distance << 7) + (n & 0b01111111) as usize;
@@ -55,42 +89,927 @@ fn ofs_from_reader<R: Read>(reader: &mut R) -> std::io::Result<(usize, usize)> {
     Ok((distance, used))
 }
 
+/// Write OFS_DELTA offset, the reverse of [ofs_from_reader].
+pub(crate) fn ofs_to_vec(distance: usize) -> Vec<u8> {
+    let mut result = vec![(distance & 0b01111111) as u8];
+    let mut distance = distance >> 7;
+    while distance != 0 {
+        distance -= 1;
+        result.push(0b10000000 | (distance & 0b01111111) as u8);
+        distance >>= 7;
+    }
+    result.reverse();
+    result
+}
+
+/// Apply git delta instructions in `delta` to `base`.
+///
+/// ```text
+/// delta = base-size result-size *instruction
+/// instruction = copy | insert
+/// copy = 1xxxxxxx [offset1] [offset2] [offset3] [offset4] [size1] [size2] [size3]
+/// insert = 0xxxxxxx data
+/// ```
+pub(crate) fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, UnpackError> {
+    fn size(delta: &[u8], pos: &mut usize) -> Result<usize, UnpackError> {
+        let mut result = 0;
+        let mut shift = 0;
+        loop {
+            let n = *delta.get(*pos).ok_or(UnpackError::InvalidDelta)?;
+            *pos += 1;
+            result |= ((n & 0b01111111) as usize) << shift;
+            shift += 7;
+            if n & 0b10000000 == 0 {
+                return Ok(result);
+            }
+        }
+    }
+
+    let mut pos = 0;
+    if size(delta, &mut pos)? != base.len() {
+        return Err(UnpackError::InvalidDelta);
+    }
+    let result_size = size(delta, &mut pos)?;
+    let mut result = Vec::with_capacity(result_size);
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        if op & 0b10000000 != 0 {
+            // copy from base
+            let mut args = [0usize; 7];
+            for (i, arg) in args.iter_mut().enumerate() {
+                if op & (1 << i) != 0 {
+                    *arg = *delta.get(pos).ok_or(UnpackError::InvalidDelta)? as usize;
+                    pos += 1;
+                }
+            }
+            let offset = args[0] | args[1] << 8 | args[2] << 16 | args[3] << 24;
+            let size = match args[4] | args[5] << 8 | args[6] << 16 {
+                0 => 0x10000,
+                size => size,
+            };
+            let data = base.get(offset..offset + size).ok_or(UnpackError::InvalidDelta)?;
+            result.extend_from_slice(data);
+        } else if op != 0 {
+            // insert new data
+            let size = op as usize;
+            let data = delta.get(pos..pos + size).ok_or(UnpackError::InvalidDelta)?;
+            result.extend_from_slice(data);
+            pos += size;
+        } else {
+            return Err(UnpackError::InvalidDelta);
+        }
+    }
+    if result.len() != result_size {
+        return Err(UnpackError::InvalidDelta);
+    }
+    Ok(result)
+}
+
 #[derive(Debug)]
 pub struct Pack {
     pub version: u32,
-    pub objects: HashMap<[u8; 20], Object>,
-    offsets: HashMap<usize, [u8; 20]>,
-    pub sha1: [u8; 20],
+    pub algorithm: HashAlgorithm,
+    pub objects: HashMap<ObjectId, Object>,
+    offsets: HashMap<usize, ObjectId>,
+    /// Delta objects whose base has not been found, keyed by offset
+    deltas: HashMap<usize, Object>,
+    /// CRC32 of raw entry data, keyed by offset
+    crc32: HashMap<usize, u32>,
+    pub checksum: ObjectId,
+    /// Threads resolving deltas, `0` for the number of available cores
+    threads: usize,
+    progress: Option<Arc<dyn Progress>>,
+    cancel: Option<CancellationToken>,
+}
+
+impl From<Interrupted> for UnpackError {
+    fn from(interrupted: Interrupted) -> Self {
+        match interrupted {
+            Interrupted::Cancelled => UnpackError::Cancelled,
+            Interrupted::TimedOut => UnpackError::TimedOut,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+/// Options of [Pack::from_reader_with_options]
+#[derive(Debug, Clone, Default)]
+pub struct UnpackOptions {
+    /// Hash algorithm of objects, which is not recorded in the pack itself
+    pub algorithm: HashAlgorithm,
+    /// Threads resolving deltas, `0` for the number of available cores
+    pub threads: usize,
+    /// Receiver of [Event::Inflated] and [Event::Resolved]
+    pub progress: Option<Arc<dyn Progress>>,
+    /// Token checked between objects, to stop with [UnpackError::Cancelled] or [UnpackError::TimedOut]
+    pub cancel: Option<CancellationToken>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Object {
     pub object_type: ObjectType,
     pub data: Vec<u8>,
     pub compressed_length: usize,
     pub offset: usize,
+    /// Hash algorithm of ids referred in data
+    pub algorithm: HashAlgorithm,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ObjectType {
     Commit,
     Tree,
     Blob,
     Tag,
     OfsDelta(usize),
-    RefDelta([u8; 20]),
+    RefDelta(ObjectId),
+}
+
+impl ObjectType {
+    /// Type name used in object header when hashing
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectType::Commit => "commit",
+            ObjectType::Tree => "tree",
+            ObjectType::Blob => "blob",
+            ObjectType::Tag => "tag",
+            ObjectType::OfsDelta(_) => "ofs-delta",
+            ObjectType::RefDelta(_) => "ref-delta",
+        }
+    }
+
+    pub fn is_delta(&self) -> bool {
+        matches!(self, ObjectType::OfsDelta(_) | ObjectType::RefDelta(_))
+    }
+
+    /// Type number used in pack entry header
+    pub(crate) fn code(&self) -> u8 {
+        match self {
+            ObjectType::Commit => 1,
+            ObjectType::Tree => 2,
+            ObjectType::Blob => 3,
+            ObjectType::Tag => 4,
+            ObjectType::OfsDelta(_) => 6,
+            ObjectType::RefDelta(_) => 7,
+        }
+    }
+}
+
+/// Type and data of a whole object, and offsets of deltas based on it
+type DeltaRoot<'a> = (&'a ObjectType, &'a Vec<u8>, Vec<usize>);
+
+/// Offset, id, type and data of a resolved delta
+type Resolved = (usize, ObjectId, ObjectType, Vec<u8>);
+
+/// Number of leading hex digits `a` and `b` have in common
+fn common_digits(a: &[u8], b: &[u8]) -> usize {
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(i) if a[i] >> 4 == b[i] >> 4 => i * 2 + 1,
+        Some(i) => i * 2,
+        None => a.len().min(b.len()) * 2,
+    }
+}
+
+/// Length of blocks of base indexed by [DeltaIndex]
+const DELTA_BLOCK: usize = 16;
+/// Maximum number of base offsets kept for the same block
+const DELTA_BUCKET: usize = 64;
+
+/// Index of blocks in a delta base, to compute deltas against it with [DeltaIndex::delta].
+pub(crate) struct DeltaIndex<'a> {
+    base: &'a [u8],
+    blocks: HashMap<&'a [u8], Vec<usize>>,
+}
+
+impl<'a> DeltaIndex<'a> {
+    pub fn new(base: &'a [u8]) -> Self {
+        let mut blocks: HashMap<&[u8], Vec<usize>> = HashMap::new();
+        for offset in (0..base.len().saturating_sub(DELTA_BLOCK - 1)).step_by(DELTA_BLOCK) {
+            let bucket = blocks.entry(&base[offset..offset + DELTA_BLOCK]).or_default();
+            if bucket.len() < DELTA_BUCKET {
+                bucket.push(offset);
+            }
+        }
+        Self { base, blocks }
+    }
+
+    /// Compute delta instructions turning base into `target`, the reverse of [apply_delta].
+    ///
+    /// Returns `None` if the delta would be larger than `max_size`.
+    pub fn delta(&self, target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        // copy offsets are limited to 4 bytes
+        if self.base.len() > u32::MAX as usize {
+            return None;
+        }
+        let base = self.base;
+        let mut out = Vec::with_capacity(max_size.min(target.len() / 2 + 16));
+        delta_size(&mut out, base.len());
+        delta_size(&mut out, target.len());
+
+        let mut pos = 0;
+        let mut insert_start = 0;
+        while pos + DELTA_BLOCK <= target.len() {
+            let mut best = (0, 0);
+            if let Some(offsets) = self.blocks.get(&target[pos..pos + DELTA_BLOCK]) {
+                for &offset in offsets {
+                    let len = base[offset..].iter().zip(target[pos..].iter()).take_while(|(a, b)| a == b).count();
+                    if len > best.1 {
+                        best = (offset, len);
+                        if pos + len == target.len() {
+                            break;
+                        }
+                    }
+                }
+            }
+            if best.1 < DELTA_BLOCK {
+                pos += 1;
+                if out.len() + (pos - insert_start) > max_size {
+                    return None;
+                }
+                continue;
+            }
+
+            // extend the match backwards into pending insert
+            let (mut offset, mut len) = best;
+            let mut start = pos;
+            while start > insert_start && offset > 0 && base[offset - 1] == target[start - 1] {
+                offset -= 1;
+                start -= 1;
+                len += 1;
+            }
+            delta_insert(&mut out, &target[insert_start..start]);
+            delta_copy(&mut out, offset, len);
+            pos = start + len;
+            insert_start = pos;
+            if out.len() > max_size {
+                return None;
+            }
+        }
+        delta_insert(&mut out, &target[insert_start..]);
+        if out.len() > max_size {
+            return None;
+        }
+        Some(out)
+    }
+}
+
+fn delta_size(out: &mut Vec<u8>, mut size: usize) {
+    loop {
+        let n = (size & 0b01111111) as u8;
+        size >>= 7;
+        if size == 0 {
+            out.push(n);
+            return;
+        }
+        out.push(n | 0b10000000);
+    }
+}
+
+fn delta_insert(out: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(0b01111111) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+fn delta_copy(out: &mut Vec<u8>, mut offset: usize, mut size: usize) {
+    while size > 0 {
+        let n = size.min(0xffffff);
+        let op = out.len();
+        out.push(0b10000000);
+        for i in 0..4 {
+            let byte = (offset >> (i * 8)) as u8;
+            if byte != 0 {
+                out[op] |= 1 << i;
+                out.push(byte);
+            }
+        }
+        for i in 0..3 {
+            let byte = (n >> (i * 8)) as u8;
+            if byte != 0 {
+                out[op] |= 1 << (4 + i);
+                out.push(byte);
+            }
+        }
+        offset += n;
+        size -= n;
+    }
+}
+
+/// Copy thin pack of `length` bytes from `reader` to `writer`, with `bases` appended as whole objects,
+/// so that every delta in it can be resolved. Return the checksum of the written pack.
+///
+/// This is what `git index-pack --fix-thin` does before storing a pack received with `thin-pack`.
+/// The object count in header and the trailing checksum are updated.
+pub fn complete_thin<R: Read, W: Write>(reader: &mut R, length: u64, bases: &[(ObjectType, Vec<u8>)], algorithm: HashAlgorithm, writer: &mut W) -> Result<ObjectId, UnpackError> {
+    if length < 12 + algorithm.size() as u64 {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    token(reader, b"PACK")?;
+    let version = u32_be(reader)?;
+    let count = u32_be(reader)? + bases.len() as u32;
+
+    let mut hasher = algorithm.hasher();
+    let mut write = |data: &[u8]| -> std::io::Result<()> {
+        hasher.update(data);
+        writer.write_all(data)
+    };
+    write(b"PACK")?;
+    write(&version.to_be_bytes())?;
+    write(&count.to_be_bytes())?;
+    let mut remaining = length - 12 - algorithm.size() as u64;
+    let mut buffer = vec![0u8; 64 * 1024];
+    while remaining > 0 {
+        let n = remaining.min(buffer.len() as u64) as usize;
+        reader.read_exact(&mut buffer[..n])?;
+        write(&buffer[..n])?;
+        remaining -= n as u64;
+    }
+    for (object_type, data) in bases {
+        write(&encode_entry(object_type.code(), data, DEFAULT_LEVEL))?;
+    }
+    let checksum = hasher.finalize();
+    writer.write_all(checksum.as_bytes())?;
+    Ok(checksum)
+}
+
+/// zlib compression level git uses by default
+pub(crate) const DEFAULT_LEVEL: u8 = 6;
+
+/// Encode pack entry with header and compressed `data`
+pub(crate) fn encode_entry(object_type: u8, data: &[u8], level: u8) -> Vec<u8> {
+    let mut entry = vint_to_vec(object_type, data.len());
+    entry.extend_from_slice(&miniz_oxide::deflate::compress_to_vec_zlib(data, level));
+    entry
+}
+
+/// Writer of version 2 pack files
+///
+/// ```text
+/// header = "PACK" version(4) count(4)
+/// entries = N * (vint-header [base-offset] zlib-data)
+/// trailer = checksum(20 or 32)
+/// ```
+///
+/// Objects are kept in memory until [PackWriter::write], as the header needs the object count.
+/// When writing, each object is compared with up to [PackWriter::window] objects of the same type,
+/// sorted by path hint and size like `git pack-objects` does, and stored as `OFS_DELTA` if the delta is small enough.
+///
+/// ```
+/// use anni_fetch::pack::{ObjectType, PackWriter};
+/// use anni_fetch::Pack;
+///
+/// let mut writer = PackWriter::new();
+/// let id = writer.add(ObjectType::Blob, b"# Test\n".to_vec());
+/// let mut raw = Vec::new();
+/// writer.write(&mut raw).unwrap();
+///
+/// let pack = Pack::from_reader(&mut std::io::Cursor::new(raw)).unwrap();
+/// assert_eq!(pack.objects[&id].data, b"# Test\n");
+/// ```
+#[derive(Debug)]
+pub struct PackWriter {
+    objects: Vec<PackEntry>,
+    ids: HashMap<ObjectId, usize>,
+    algorithm: HashAlgorithm,
+    level: u8,
+    window: usize,
+    depth: usize,
+}
+
+#[derive(Debug)]
+struct PackEntry {
+    object_type: ObjectType,
+    data: Vec<u8>,
+    name_hash: u32,
+}
+
+impl Default for PackWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PackWriter {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            ids: HashMap::new(),
+            algorithm: HashAlgorithm::Sha1,
+            level: DEFAULT_LEVEL,
+            window: 10,
+            depth: 50,
+        }
+    }
+
+    /// Set hash algorithm of object ids and pack checksum, SHA-1 by default
+    ///
+    /// It must be set before adding objects.
+    pub fn algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Set zlib compression level from 0 to 10, 6 by default
+    pub fn level(mut self, level: u8) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Set number of objects tried as delta base of each object, 10 by default
+    ///
+    /// Deltas are disabled with window 0.
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Set maximum length of delta chains, 50 by default
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Add an object and return its id
+    ///
+    /// Objects are written in the order they are added, except that delta bases are written first.
+    /// Adding an object twice has no effect.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `object_type` is a delta type.
+    pub fn add(&mut self, object_type: ObjectType, data: Vec<u8>) -> ObjectId {
+        self.add_with_path(object_type, data, "")
+    }
+
+    /// Add an object found at `path`, which helps finding similar objects as delta bases
+    pub fn add_with_path(&mut self, object_type: ObjectType, data: Vec<u8>, path: &str) -> ObjectId {
+        assert!(!object_type.is_delta(), "only whole objects can be added to a pack");
+        let id = git_hash(self.algorithm, object_type.name(), &data);
+        if !self.ids.contains_key(&id) {
+            self.ids.insert(id, self.objects.len());
+            self.objects.push(PackEntry { object_type, data, name_hash: name_hash(path) });
+        }
+        id
+    }
+
+    pub fn contains(&self, id: &ObjectId) -> bool {
+        self.ids.contains_key(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Write pack to `writer`, and return the checksum of the pack.
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<ObjectId> {
+        let mut reader = self.reader();
+        std::io::copy(&mut reader, writer)?;
+        Ok(reader.checksum.unwrap())
+    }
+
+    /// Read the pack data, compressing each object only when it is reached
+    ///
+    /// Deltas are computed up front, but the pack is never held in memory as a whole,
+    /// so it can be streamed as a request body.
+    pub fn reader(&self) -> PackReader<'_> {
+        let deltas = self.deltas();
+
+        // write bases before deltas, as OFS_DELTA can only refer to an earlier entry
+        let mut order = Vec::with_capacity(self.objects.len());
+        let mut ordered = vec![false; self.objects.len()];
+        for i in 0..self.objects.len() {
+            let mut chain = Vec::new();
+            let mut j = i;
+            while !ordered[j] {
+                ordered[j] = true;
+                chain.push(j);
+                match &deltas[j] {
+                    Some((base, _)) => j = *base,
+                    None => break,
+                }
+            }
+            order.extend(chain.into_iter().rev());
+        }
+
+        let mut header = Vec::with_capacity(12);
+        header.extend_from_slice(b"PACK");
+        header.extend_from_slice(&2u32.to_be_bytes());
+        header.extend_from_slice(&(self.objects.len() as u32).to_be_bytes());
+        let mut hasher = self.algorithm.hasher();
+        hasher.update(&header);
+
+        PackReader {
+            writer: self,
+            offsets: vec![0; self.objects.len()],
+            offset: header.len(),
+            deltas,
+            order: order.into_iter(),
+            hasher: Some(hasher),
+            buffer: header,
+            position: 0,
+            checksum: None,
+        }
+    }
+
+    /// Find delta base and delta for each object
+    fn deltas(&self) -> Vec<Option<(usize, Vec<u8>)>> {
+        let mut result = vec![None; self.objects.len()];
+        if self.window == 0 || self.depth == 0 {
+            return result;
+        }
+
+        let mut order: Vec<usize> = (0..self.objects.len()).collect();
+        order.sort_by(|&a, &b| {
+            let (a, b) = (&self.objects[a], &self.objects[b]);
+            a.object_type.code().cmp(&b.object_type.code())
+                .then(a.name_hash.cmp(&b.name_hash))
+                .then(b.data.len().cmp(&a.data.len()))
+        });
+
+        let mut depths = vec![0; self.objects.len()];
+        let mut window: VecDeque<(usize, DeltaIndex)> = VecDeque::with_capacity(self.window + 1);
+        for i in order {
+            let target = &self.objects[i];
+            let mut best: Option<(usize, Vec<u8>)> = None;
+            for (j, index) in window.iter().rev() {
+                let base = &self.objects[*j];
+                if base.object_type != target.object_type || depths[*j] >= self.depth {
+                    continue;
+                }
+                // like git, allow smaller deltas for bases deeper in a chain
+                let mut max_size = (target.data.len() / 2).saturating_sub(20) * (self.depth - depths[*j]) / self.depth;
+                if let Some((_, delta)) = &best {
+                    max_size = max_size.min(delta.len() - 1);
+                }
+                if target.data.len().saturating_sub(base.data.len()) >= max_size || target.data.len() < base.data.len() / 32 {
+                    continue;
+                }
+                if let Some(delta) = index.delta(&target.data, max_size) {
+                    best = Some((*j, delta));
+                }
+            }
+            if let Some((base, delta)) = best {
+                depths[i] = depths[base] + 1;
+                result[i] = Some((base, delta));
+            }
+
+            window.push_back((i, DeltaIndex::new(&target.data)));
+            if window.len() > self.window {
+                window.pop_front();
+            }
+        }
+        result
+    }
+}
+
+/// Reader returned by [PackWriter::reader]
+pub struct PackReader<'a> {
+    writer: &'a PackWriter,
+    deltas: Vec<Option<(usize, Vec<u8>)>>,
+    order: std::vec::IntoIter<usize>,
+    /// Offsets of written objects, for OFS_DELTA
+    offsets: Vec<usize>,
+    offset: usize,
+    hasher: Option<Hasher>,
+    /// Current entry, read from `position`
+    buffer: Vec<u8>,
+    position: usize,
+    checksum: Option<ObjectId>,
+}
+
+impl PackReader<'_> {
+    /// Checksum of the pack, once it is read to the end
+    pub fn checksum(&self) -> Option<&ObjectId> {
+        self.checksum.as_ref()
+    }
+
+    /// Encode the next entry into `buffer`, or the checksum after the last one
+    fn fill(&mut self) {
+        let writer = self.writer;
+        self.position = 0;
+        self.buffer = match (self.order.next(), self.hasher.take()) {
+            (Some(i), Some(mut hasher)) => {
+                let entry = match &self.deltas[i] {
+                    Some((base, delta)) => {
+                        let mut entry = vint_to_vec(ObjectType::OfsDelta(0).code(), delta.len());
+                        entry.extend_from_slice(&ofs_to_vec(self.offset - self.offsets[*base]));
+                        entry.extend_from_slice(&miniz_oxide::deflate::compress_to_vec_zlib(delta, writer.level));
+                        entry
+                    }
+                    None => {
+                        let object = &writer.objects[i];
+                        encode_entry(object.object_type.code(), &object.data, writer.level)
+                    }
+                };
+                hasher.update(&entry);
+                self.hasher = Some(hasher);
+                self.offsets[i] = self.offset;
+                self.offset += entry.len();
+                entry
+            }
+            (None, Some(hasher)) => {
+                let checksum = hasher.finalize();
+                self.checksum = Some(checksum);
+                checksum.as_bytes().to_vec()
+            }
+            _ => Vec::new(),
+        };
+    }
+}
+
+impl Read for PackReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.hasher.is_none() {
+                return Ok(0);
+            }
+            self.fill();
+        }
+        let len = buf.len().min(self.buffer.len() - self.position);
+        buf[..len].copy_from_slice(&self.buffer[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Hash of path which puts files with the same name together when sorted,
+/// as the last characters have the most weight, like `pack_name_hash` of git
+fn name_hash(path: &str) -> u32 {
+    path.bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .fold(0u32, |hash, c| (hash >> 2).wrapping_add((c as u32) << 24))
+}
+
+/// Lookup of objects by id
+///
+/// Stores holding objects in memory like [Pack] lend them, while stores reading them
+/// on demand like [crate::packfile::PackFile] return owned objects.
+pub trait ObjectStore {
+    fn object(&self, id: &ObjectId) -> Option<Cow<'_, Object>>;
+}
+
+impl ObjectStore for Pack {
+    fn object(&self, id: &ObjectId) -> Option<Cow<'_, Object>> {
+        self.objects.get(id).map(Cow::Borrowed)
+    }
+}
+
+impl<T: ObjectStore> ObjectStore for [T] {
+    fn object(&self, id: &ObjectId) -> Option<Cow<'_, Object>> {
+        self.iter().find_map(|p| p.object(id))
+    }
+}
+
+impl<T: ObjectStore> ObjectStore for Vec<T> {
+    fn object(&self, id: &ObjectId) -> Option<Cow<'_, Object>> {
+        self.as_slice().object(id)
+    }
+}
+
+impl<T: ObjectStore + ?Sized> ObjectStore for &T {
+    fn object(&self, id: &ObjectId) -> Option<Cow<'_, Object>> {
+        (**self).object(id)
+    }
+}
+
+/// Objects of the first store, then of the second one
+impl<A: ObjectStore, B: ObjectStore> ObjectStore for (A, B) {
+    fn object(&self, id: &ObjectId) -> Option<Cow<'_, Object>> {
+        self.0.object(id).or_else(|| self.1.object(id))
+    }
 }
 
 impl Pack {
+    /// Checksum of the pack, which was named `sha1` before SHA-256 support
+    #[deprecated(note = "use the `checksum` field")]
+    pub fn sha1(&self) -> &[u8] {
+        self.checksum.as_bytes()
+    }
+
     pub fn offset(&self, offset: usize) -> Option<&Object> {
         if let Some(hash) = self.offsets.get(&offset) {
             self.objects.get(hash)
         } else {
-            None
+            self.deltas.get(&offset)
+        }
+    }
+
+    /// Find the object whose id starts with hex `prefix` of at least [MIN_ABBREV] digits
+    pub fn resolve_prefix(&self, prefix: &str) -> Result<ObjectId, ObjectIdError> {
+        check_prefix(prefix)?;
+        let mut found = self.objects.keys().filter(|id| id.starts_with(prefix));
+        match (found.next(), found.next()) {
+            (Some(id), None) => Ok(*id),
+            (Some(_), Some(_)) => Err(ObjectIdError::Ambiguous(prefix.to_owned())),
+            (None, _) => Err(ObjectIdError::NotFound(prefix.to_owned())),
+        }
+    }
+
+    /// Shortest prefix of `id` with at least `min_len` digits which no other object in this pack starts with
+    pub fn abbreviate(&self, id: &ObjectId, min_len: usize) -> String {
+        let common = self.objects.keys()
+            .filter(|other| *other != id)
+            .map(|other| common_digits(id.as_bytes(), other.as_bytes()))
+            .max()
+            .unwrap_or(0);
+        id.abbreviate(min_len.max(MIN_ABBREV).max(common + 1))
+    }
+
+    /// Id of the object at `offset`, `None` for unresolved deltas
+    pub fn id_at(&self, offset: usize) -> Option<&ObjectId> {
+        self.offsets.get(&offset)
+    }
+
+    /// CRC32 of the raw entry at `offset`, as stored in pack index
+    pub fn crc32(&self, offset: usize) -> Option<u32> {
+        self.crc32.get(&offset).copied()
+    }
+
+    /// Delta objects whose base is not in this pack
+    ///
+    /// A thin pack may contain `RefDelta` objects based on objects the receiver already has.
+    pub fn unresolved(&self) -> impl Iterator<Item=&Object> {
+        self.deltas.values()
+    }
+
+    /// Number of threads used by [Pack::resolve_deltas], `0` for the number of available cores
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+
+    /// Report deltas resolved by [Pack::resolve_deltas] to `progress`
+    pub fn set_progress(&mut self, progress: Arc<dyn Progress>) {
+        self.progress = Some(progress);
+    }
+
+    /// Check `token` between deltas resolved by [Pack::resolve_deltas]
+    pub fn set_cancellation(&mut self, token: CancellationToken) {
+        self.cancel = Some(token);
+    }
+
+    /// Resolve delta objects in this pack.
+    ///
+    /// Bases not found in this pack are looked up with `external`, which returns
+    /// the type and the data of an object.
+    /// Deltas which still can not be resolved stay in [Pack::unresolved].
+    ///
+    /// Deltas form trees rooted at whole objects, which are resolved in parallel
+    /// like `git index-pack --threads` does, see [Pack::set_threads].
+    pub fn resolve_deltas<F>(&mut self, mut external: F) -> Result<(), UnpackError>
+        where F: FnMut(&ObjectId) -> Option<(ObjectType, Vec<u8>)> {
+        let mut external_bases: HashMap<ObjectId, (ObjectType, Vec<u8>)> = HashMap::new();
+        let total = self.deltas.len();
+        let done = AtomicUsize::new(0);
+        loop {
+            if self.deltas.is_empty() {
+                return Ok(());
+            }
+            let mut by_offset: HashMap<usize, Vec<usize>> = HashMap::new();
+            let mut by_id: HashMap<ObjectId, Vec<usize>> = HashMap::new();
+            for (offset, delta) in self.deltas.iter() {
+                match &delta.object_type {
+                    ObjectType::OfsDelta(distance) => {
+                        if let Some(base) = offset.checked_sub(*distance) {
+                            by_offset.entry(base).or_default().push(*offset);
+                        }
+                    }
+                    ObjectType::RefDelta(id) => by_id.entry(*id).or_default().push(*offset),
+                    _ => unreachable!(),
+                }
+            }
+
+            let mut roots: Vec<DeltaRoot> = Vec::new();
+            for (base, children) in by_offset.iter() {
+                if let Some(object) = self.offsets.get(base).and_then(|id| self.objects.get(id)) {
+                    roots.push((&object.object_type, &object.data, children.clone()));
+                }
+            }
+            for (id, children) in by_id.iter() {
+                if let Some((object_type, data)) = self.objects.get(id).map(|o| (&o.object_type, &o.data))
+                    .or_else(|| external_bases.get(id).map(|(t, d)| (t, d))) {
+                    roots.push((object_type, data, children.clone()));
+                }
+            }
+            let resolved = self.resolve_trees(roots, &by_offset, &by_id, (&done, total))?;
+
+            for (offset, id, object_type, data) in resolved {
+                let delta = match self.deltas.remove(&offset) {
+                    Some(delta) => delta,
+                    None => continue,
+                };
+                self.offsets.insert(offset, id);
+                self.objects.insert(id, Object {
+                    object_type,
+                    data,
+                    compressed_length: delta.compressed_length,
+                    offset,
+                    algorithm: self.algorithm,
+                });
+            }
+
+            // remaining deltas are based on objects outside this pack, or on other remaining deltas
+            let mut found = false;
+            for delta in self.deltas.values() {
+                if let ObjectType::RefDelta(id) = &delta.object_type {
+                    if !self.objects.contains_key(id) && !external_bases.contains_key(id) {
+                        if let Some(base) = external(id) {
+                            external_bases.insert(*id, base);
+                            found = true;
+                        }
+                    }
+                }
+            }
+            if !found {
+                return Ok(());
+            }
         }
     }
 
+    /// Resolve deltas in trees starting from `roots`, and return `(offset, id, type, data)` of them
+    ///
+    /// Each root is walked by a single thread, as a delta needs the data of its base.
+    fn resolve_trees(
+        &self,
+        roots: Vec<DeltaRoot>,
+        by_offset: &HashMap<usize, Vec<usize>>,
+        by_id: &HashMap<ObjectId, Vec<usize>>,
+        (done, total): (&AtomicUsize, usize),
+    ) -> Result<Vec<Resolved>, UnpackError> {
+        let next = AtomicUsize::new(0);
+        let worker = || -> Result<Vec<_>, UnpackError> {
+            let mut resolved: Vec<Resolved> = Vec::new();
+            loop {
+                let (object_type, data, children) = match roots.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    Some(root) => root,
+                    None => return Ok(resolved),
+                };
+                // (offset of delta, index of its base in `resolved`, or `None` for the root)
+                let mut stack: Vec<(usize, Option<usize>)> = children.iter().map(|c| (*c, None)).collect();
+                while let Some((offset, base)) = stack.pop() {
+                    cancel::check(self.cancel.as_ref())?;
+                    let (object_type, base): (&ObjectType, &[u8]) = match base {
+                        Some(i) => {
+                            let (_, _, object_type, data) = &resolved[i];
+                            (object_type, data)
+                        }
+                        None => (object_type, data),
+                    };
+                    let object_type = object_type.clone();
+                    let data = apply_delta(base, &self.deltas[&offset].data)?;
+                    let id = git_hash(self.algorithm, object_type.name(), &data);
+
+                    let index = resolved.len();
+                    let children = by_offset.get(&offset).into_iter().chain(by_id.get(&id)).flatten();
+                    stack.extend(children.map(|c| (*c, Some(index))));
+                    resolved.push((offset, id, object_type, data));
+                    if let Some(progress) = &self.progress {
+                        let done = (done.fetch_add(1, Ordering::Relaxed) + 1).min(total);
+                        if should_report(done, total) {
+                            progress.event(&Event::Resolved { done, total });
+                        }
+                    }
+                }
+            }
+        };
+
+        let threads = match self.threads {
+            0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            n => n,
+        }.min(roots.len());
+        if threads <= 1 {
+            return worker();
+        }
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads).map(|_| scope.spawn(worker)).collect();
+            let mut result = Vec::new();
+            for handle in handles {
+                result.append(&mut handle.join().unwrap()?);
+            }
+            Ok(result)
+        })
+    }
+
+    /// Read a pack of SHA-1 objects starting at the current position of `reader`
     pub fn from_reader<R: Read + Seek>(reader: &mut R) -> std::result::Result<Self, UnpackError> {
+        Self::from_reader_with(reader, HashAlgorithm::Sha1)
+    }
+
+    /// Read a pack of objects hashed with `algorithm`, which is not recorded in the pack itself
+    pub fn from_reader_with<R: Read + Seek>(reader: &mut R, algorithm: HashAlgorithm) -> std::result::Result<Self, UnpackError> {
+        Self::from_reader_with_options(reader, &UnpackOptions { algorithm, ..Default::default() })
+    }
+
+    /// Read a pack, inflating all objects first and then resolving deltas with [UnpackOptions::threads]
+    ///
+    /// Progress is reported to [UnpackOptions::progress] as objects are inflated and deltas resolved.
+    pub fn from_reader_with_options<R: Read + Seek>(reader: &mut R, options: &UnpackOptions) -> std::result::Result<Self, UnpackError> {
+        let algorithm = options.algorithm;
+        let start = reader.stream_position()?;
         token(reader, b"PACK")?;
         let version = u32_be(reader)?;
         let objects = u32_be(reader)?;
@@ -98,6 +1017,8 @@ impl Pack {
         let mut offset = 12;
         let mut result = HashMap::new();
         let mut offsets = HashMap::new();
+        let mut deltas = HashMap::new();
+        let mut entries = Vec::with_capacity(objects as usize);
 
         let mut state = InflateState::new_boxed(DataFormat::Zlib);
         let mut input_buf = vec![0u8; INPUT_BUFFER_SIZE];
@@ -105,6 +1026,7 @@ impl Pack {
 
         for _ in 0..objects {
             use crate::pack::ObjectType::*;
+            cancel::check(options.cancel.as_ref())?;
             let (object_type, decompressed_length, mut object_size) = vint_from_reader(reader)?;
             let object_type = match object_type {
                 1 => Commit,
@@ -117,10 +1039,10 @@ impl Pack {
                     OfsDelta(d)
                 }
                 7 => {
-                    let mut data = [0u8; 20];
+                    let mut data = vec![0u8; algorithm.size()];
                     reader.read_exact(&mut data)?;
-                    object_size += 20;
-                    RefDelta(data)
+                    object_size += data.len();
+                    RefDelta(ObjectId::from_bytes(&data).unwrap())
                 }
                 _ => return Err(UnpackError::InvalidObjectType),
             };
@@ -138,6 +1060,10 @@ impl Pack {
                 input_buf.resize(2048, 0);
                 output_buf.resize(4096, 0);
                 match state.last_status() {
+                    // Pack is truncated, as in an interrupted transfer
+                    TINFLStatus::NeedsMoreInput if bytes_available == 0 => {
+                        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                    }
                     // Need more input
                     // Next turn, provide more input
                     TINFLStatus::NeedsMoreInput => {
@@ -174,54 +1100,73 @@ impl Pack {
             }
             object_size += compressed_length;
 
-            let hash = git_sha1(match object_type {
-                Commit => "commit",
-                Tree => "tree",
-                Blob => "blob",
-                Tag => "tag",
-                OfsDelta(_) => "o", // TODO
-                RefDelta(_) => "r" // TODO
-            }, &data);
-
+            let is_delta = object_type.is_delta();
             let object = Object {
                 object_type,
                 data,
                 compressed_length,
                 offset,
+                algorithm,
             };
-            offsets.insert(offset, hash);
-            result.insert(hash, object);
+            if is_delta {
+                deltas.insert(offset, object);
+            } else {
+                let hash = git_hash(algorithm, object.object_type.name(), &object.data);
+                offsets.insert(offset, hash);
+                result.insert(hash, object);
+            }
+            entries.push(offset);
             offset += object_size;
+            if let Some(progress) = &options.progress {
+                if should_report(entries.len(), objects as usize) {
+                    progress.event(&Event::Inflated { done: entries.len(), total: objects as usize });
+                }
+            }
         }
 
-        // final sha1
-        let mut hasher = sha1::Sha1::new();
-        reader.seek(SeekFrom::Start(0))?;
-        std::io::copy(&mut reader.take(offset as u64), &mut hasher)?;
-        let hash_result = hasher.finalize();
-        let hash_result: [u8; 20] = hash_result.try_into().unwrap();
-        let mut checksum = [0u8; 20];
-        reader.read_exact(&mut checksum)?;
-        if hash_result != checksum {
+        // final checksum, and crc32 of each entry
+        let mut hasher = algorithm.hasher();
+        let mut crc32 = HashMap::with_capacity(entries.len());
+        reader.seek(SeekFrom::Start(start))?;
+        std::io::copy(&mut reader.take(12), &mut hasher)?;
+        let mut entry = Vec::new();
+        for (i, start) in entries.iter().enumerate() {
+            let end = entries.get(i + 1).copied().unwrap_or(offset);
+            entry.clear();
+            reader.take((end - start) as u64).read_to_end(&mut entry)?;
+            hasher.update(&entry);
+            crc32.insert(*start, crc32fast::hash(&entry));
+        }
+        let checksum = hasher.finalize();
+        let mut expected = vec![0u8; algorithm.size()];
+        reader.read_exact(&mut expected)?;
+        if checksum.as_bytes() != expected.as_slice() {
             return Err(UnpackError::InvalidHash);
         }
 
-        // bypass EOF check for now
-        // assert_eq!(std::io::copy(&mut reader.take(1), &mut input)?, 0);
+        // data after the checksum is left in reader, see [crate::verify::verify_pack] to detect it
 
-        Ok(Self {
+        let mut pack = Self {
             version,
+            algorithm,
             objects: result,
             offsets,
-            sha1: checksum,
-        })
+            deltas,
+            crc32,
+            checksum,
+            threads: options.threads,
+            progress: options.progress.clone(),
+            cancel: options.cancel.clone(),
+        };
+        pack.resolve_deltas(|_| None)?;
+        Ok(pack)
     }
 
-    fn extract_from(mut state: &mut Box<InflateState>, bytes_available: usize, input_buf: &[u8], mut output_buf: &mut Vec<u8>) -> (usize, i64, usize) {
+    fn extract_from(state: &mut InflateState, bytes_available: usize, input_buf: &[u8], output_buf: &mut Vec<u8>) -> (usize, i64, usize) {
         let r = miniz_oxide::inflate::stream::inflate(
-            &mut state,
+            state,
             &input_buf[..bytes_available],
-            &mut output_buf,
+            output_buf,
             MZFlush::Partial,
         );
         let consumed = r.bytes_consumed;
@@ -236,10 +1181,16 @@ impl Pack {
 
 #[cfg(test)]
 mod tests {
-    use crate::pack::{vint_from_reader, Object, ObjectType};
+    use crate::oid::{HashAlgorithm, ObjectId, ObjectIdError};
+    use crate::pack::{vint_from_reader, vint_to_vec, ofs_from_reader, ofs_to_vec, apply_delta, DeltaIndex, Object, ObjectType, PackWriter, UnpackError, UnpackOptions};
     use crate::{Pack, Client};
-    use std::io::Cursor;
+    use std::io::{Cursor, Write};
+    use std::process::{Command, Stdio};
+    use std::time::Instant;
+    use crate::cancel::CancellationToken;
     use crate::client::{RequestBuilder, Message};
+    use crate::testing::{git, commit, fixture, pack_objects};
+    use crate::utils::hex;
 
     #[test]
     fn test_vint() {
@@ -253,6 +1204,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_vint_to_vec() {
+        for &(t, len) in [(1u8, 0usize), (2, 15), (3, 16), (7, 0b1111 + (0b0101100 << 4) + (0b0010010 << 11))].iter() {
+            let v = vint_to_vec(t, len);
+            assert_eq!(vint_from_reader(&mut Cursor::new(&v)).unwrap(), (t, len, v.len()));
+        }
+    }
+
     #[test]
     fn test_unpack() {
         let data = [
@@ -287,6 +1246,7 @@ Initial commit
 ".to_vec(),
             compressed_length: 117,
             offset: 12,
+            algorithm: HashAlgorithm::Sha1,
         });
 
         assert_eq!(_pack.offset(131).unwrap().object_type, ObjectType::Tree);
@@ -301,31 +1261,254 @@ Initial commit
 ".to_vec(),
             compressed_length: 16,
             offset: 179,
+            algorithm: HashAlgorithm::Sha1,
         });
 
-        assert_eq!(_pack.sha1, [79, 16, 208, 2, 37, 46, 7, 195, 175, 219, 45, 204, 10, 184, 141, 54, 232, 171, 74, 38]);
+        assert_eq!(_pack.checksum.as_bytes(), [79, 16, 208, 2, 37, 46, 7, 195, 175, 219, 45, 204, 10, 184, 141, 54, 232, 171, 74, 38]);
+        #[allow(deprecated)]
+        let sha1 = _pack.sha1();
+        assert_eq!(sha1, _pack.checksum.as_bytes());
+
+        // truncated in the last object
+        let truncated = Pack::from_reader(&mut std::io::Cursor::new(&data[..190]));
+        assert!(matches!(truncated, Err(UnpackError::IOError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let base = b"hello world";
+        // base size 11, result size 13, copy 6 bytes at 0, insert "rust!!!"
+        let delta = [11, 13, 0b10010000, 6, 7, b'r', b'u', b's', b't', b'!', b'!', b'!'];
+        assert_eq!(apply_delta(base, &delta).unwrap(), b"hello rust!!!");
+        apply_delta(b"hello", &delta).expect_err("base size mismatch");
+        apply_delta(base, &delta[..8]).expect_err("truncated delta");
+    }
+
+    #[test]
+    fn test_ofs_to_vec() {
+        for &distance in [0usize, 1, 127, 128, 16511, 16512, 1 << 30].iter() {
+            let v = ofs_to_vec(distance);
+            assert_eq!(ofs_from_reader(&mut Cursor::new(&v)).unwrap(), (distance, v.len()));
+        }
+    }
+
+    #[test]
+    fn test_create_delta() {
+        let base: Vec<u8> = (0..2000).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+        let mut target = base.clone();
+        target.splice(100..110, b"inserted text".iter().cloned());
+        target.extend_from_slice(b"appended\n");
+        target.drain(5000..6000);
+
+        let index = DeltaIndex::new(&base);
+        let delta = index.delta(&target, target.len()).unwrap();
+        assert!(delta.len() < 100);
+        assert_eq!(apply_delta(&base, &delta).unwrap(), target);
+        assert!(index.delta(&target, 10).is_none());
+
+        // copies longer than the size field
+        let large = vec![7u8; 0x1000010];
+        let delta = DeltaIndex::new(&large).delta(&large, 100).unwrap();
+        assert_eq!(apply_delta(&large, &delta).unwrap(), large);
+
+        let unrelated = b"nothing in common with base, at all".repeat(10);
+        let delta = index.delta(&unrelated, usize::MAX).unwrap();
+        assert_eq!(apply_delta(&base, &delta).unwrap(), unrelated);
+    }
+
+    #[test]
+    fn test_pack_writer_delta() {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q"]);
+        let mut content: Vec<u8> = (0..500).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+        let mut versions = Vec::new();
+        for i in 0..8 {
+            content.extend_from_slice(format!("version {}\n", i).as_bytes());
+            versions.push(content.clone());
+        }
+
+        let write = |writer: &mut PackWriter| {
+            for (i, version) in versions.iter().enumerate() {
+                writer.add_with_path(ObjectType::Blob, version.clone(), "album/a.toml");
+                writer.add_with_path(ObjectType::Blob, format!("other {}", i).into_bytes(), "b.toml");
+            }
+            let mut raw = Vec::new();
+            writer.write(&mut raw).unwrap();
+            raw
+        };
+        let plain = write(&mut PackWriter::new().window(0));
+        let raw = write(&mut PackWriter::new().depth(3));
+        assert!(raw.len() * 3 < plain.len());
+
+        let pack = Pack::from_reader(&mut Cursor::new(&raw)).unwrap();
+        assert_eq!(pack.objects.len(), 16);
+        for version in versions.iter() {
+            assert_eq!(pack.objects[&crate::utils::git_hash(HashAlgorithm::Sha1, "blob", version)].data, *version);
+        }
+
+        let path = dir.path().join("test.pack");
+        std::fs::write(&path, &raw).unwrap();
+        git(dir.path(), &["index-pack", "test.pack"]);
+        let output = git(dir.path(), &["verify-pack", "-v", "test.pack"]);
+        assert!(output.contains("chain length = 3"));
+        assert!(!output.contains("chain length = 4"));
+    }
+
+    #[test]
+    fn test_resolve_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path();
+        git(work, &["init", "-q"]);
+        let content: String = (0..200).map(|i| format!("line {}\n", i)).collect();
+        commit(work, &[("a.txt", &content)], "a");
+        commit(work, &[("a.txt", &format!("{}line 200\n", content))], "b");
+
+        let pack = Pack::from_reader(&mut Cursor::new(pack_objects(work, "HEAD"))).unwrap();
+        assert_eq!(pack.unresolved().count(), 0);
+
+        let mut expected: Vec<String> = git(work, &["rev-list", "--objects", "HEAD"])
+            .lines()
+            .map(|l| l[..40].to_owned())
+            .collect();
+        expected.sort();
+        let mut got: Vec<String> = pack.objects.keys().map(hex).collect();
+        got.sort();
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn test_resolve_deltas_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path();
+        git(work, &["init", "-q"]);
+        let mut content: String = (0..300).map(|i| format!("line {}\n", i)).collect();
+        for i in 0..10 {
+            content.push_str(&format!("more {}\n", i));
+            let files: Vec<_> = (0..4).map(|f| (format!("{}.txt", f), content.replace("line", &f.to_string()))).collect();
+            let files: Vec<_> = files.iter().map(|(p, c)| (p.as_str(), c.as_str())).collect();
+            commit(work, &files, &format!("commit {}", i));
+        }
+
+        let ofs = pack_objects(work, "HEAD");
+        let output = Command::new("git")
+            .current_dir(work)
+            .args(["pack-objects", "--stdout", "--revs"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                child.stdin.take().unwrap().write_all(b"HEAD\n")?;
+                child.wait_with_output()
+            })
+            .unwrap();
+        for raw in [ofs, output.stdout] {
+            let sequential = Pack::from_reader_with_options(&mut Cursor::new(&raw), &UnpackOptions { threads: 1, ..Default::default() }).unwrap();
+            let parallel = Pack::from_reader_with_options(&mut Cursor::new(&raw), &UnpackOptions { threads: 4, ..Default::default() }).unwrap();
+            assert_eq!(sequential.unresolved().count(), 0);
+            assert_eq!(parallel.unresolved().count(), 0);
+            assert_eq!(parallel.objects, sequential.objects);
+            assert_eq!(parallel.objects.len(), git(work, &["rev-list", "--objects", "HEAD"]).lines().count());
+        }
+    }
+
+    #[test]
+    fn test_unpack_cancelled() {
+        let mut writer = PackWriter::new();
+        writer.add(ObjectType::Blob, b"blob".to_vec());
+        let mut raw = Vec::new();
+        writer.write(&mut raw).unwrap();
+
+        let token = CancellationToken::new();
+        let options = UnpackOptions { cancel: Some(token.clone()), ..Default::default() };
+        assert!(Pack::from_reader_with_options(&mut Cursor::new(&raw), &options).is_ok());
+        token.cancel();
+        assert!(matches!(Pack::from_reader_with_options(&mut Cursor::new(&raw), &options), Err(UnpackError::Cancelled)));
+
+        let options = UnpackOptions { cancel: Some(CancellationToken::new().with_deadline(Instant::now())), ..Default::default() };
+        assert!(matches!(Pack::from_reader_with_options(&mut Cursor::new(&raw), &options), Err(UnpackError::TimedOut)));
+    }
+
+    #[test]
+    fn test_pack_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let repo = dir.path().join("repo.git");
+        let source = Pack::from_reader(&mut Cursor::new(pack_objects(&repo, "master\nv1"))).unwrap();
+
+        let mut objects: Vec<_> = source.objects.values().collect();
+        objects.sort_by_key(|o| o.offset);
+        let mut writer = PackWriter::new();
+        for object in objects.iter() {
+            writer.add(object.object_type.clone(), object.data.clone());
+        }
+        writer.add(objects[0].object_type.clone(), objects[0].data.clone());
+        assert_eq!(writer.len(), objects.len());
+
+        let mut raw = Vec::new();
+        let checksum = writer.write(&mut raw).unwrap();
+        let pack = Pack::from_reader(&mut Cursor::new(&raw)).unwrap();
+        assert_eq!(pack.checksum, checksum);
+        assert_eq!(pack.objects.len(), source.objects.len());
+        for (id, object) in source.objects.iter() {
+            assert_eq!(pack.objects[id].object_type, object.object_type);
+            assert_eq!(pack.objects[id].data, object.data);
+        }
+
+        std::fs::write(dir.path().join("test.pack"), &raw).unwrap();
+        let output = git(&repo, &["index-pack", "../test.pack"]);
+        assert_eq!(output.trim(), hex(&checksum));
+    }
+
+    #[test]
+    fn test_resolve_prefix() {
+        // add blobs until two ids share the first 4 digits
+        let mut writer = PackWriter::new().window(0);
+        let mut ids: Vec<ObjectId> = Vec::new();
+        let (a, b) = loop {
+            let id = writer.add(ObjectType::Blob, format!("{}\n", ids.len()).into_bytes());
+            if let Some(other) = ids.iter().find(|other| other.abbreviate(4) == id.abbreviate(4)) {
+                break (*other, id);
+            }
+            ids.push(id);
+        };
+        let mut raw = Vec::new();
+        writer.write(&mut raw).unwrap();
+        let pack = Pack::from_reader(&mut Cursor::new(&raw)).unwrap();
+
+        let short = a.abbreviate(4);
+        assert_eq!(pack.resolve_prefix(&short), Err(ObjectIdError::Ambiguous(short.clone())));
+        let unique = pack.abbreviate(&a, 4);
+        assert!(unique.len() > 4 && b.starts_with(&unique[..unique.len() - 1]));
+        assert_eq!(pack.resolve_prefix(&unique), Ok(a));
+        assert_eq!(pack.resolve_prefix(&unique.to_uppercase()), Ok(a));
+        assert_eq!(pack.resolve_prefix(&a.to_string()), Ok(a));
+        assert_eq!(pack.abbreviate(&ids[0], 7).len(), 7);
+
+        assert!(matches!(pack.resolve_prefix("e69"), Err(ObjectIdError::TooShort(_))));
+        assert!(matches!(pack.resolve_prefix("e69g"), Err(ObjectIdError::InvalidId(_))));
+        assert!(matches!(pack.resolve_prefix(&format!("{}0", a)), Err(ObjectIdError::NotFound(_))));
     }
 
     #[test]
     fn test_ref_delta() {
         let cli = Client::new("https://github.com/project-anni/repo.git");
-        let iter = cli.request(
+        let mut iter = cli.request(
             RequestBuilder::new(true)
                 .command("fetch")
                 .argument("thin-pack")
                 .argument("ofs-delta")
                 .argument("deepen 1")
                 .want(&cli.ls_ref("HEAD").unwrap())
-                .have("da32dc7b28d73b67dcbb894daf862538615d7765")
+                .have(&"da32dc7b28d73b67dcbb894daf862538615d7765".parse().unwrap())
                 .build()
         ).unwrap();
         let mut p = Vec::new();
-        for msg in iter {
-            match msg {
-                Message::PackData(mut data) => p.append(&mut data),
-                _ => {}
+        for msg in &mut iter {
+            if let Message::PackData(mut data) = msg {
+                p.append(&mut data);
             }
         }
+        iter.finish().unwrap();
         Pack::from_reader(&mut Cursor::new(p)).unwrap();
     }
 }
\ No newline at end of file
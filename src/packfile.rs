@@ -0,0 +1,959 @@
+//! Read objects from a pack on demand, keeping only offsets and ids in memory.
+//!
+//! [Pack] inflates every object into memory, which needs gigabytes for big repositories.
+//! [PackFile] inflates each object once to compute its id, and inflates it again from
+//! the pack when it is requested. Bases of deltas are kept in a cache of limited size.
+//!
+//! Packs stored on disk can be memory-mapped with [PackFile::map], so compressed data is
+//! inflated directly from the mapped file. Mapping is unsafe, as the file must not change
+//! while it is mapped; [crate::repo::Repository::packs] maps only packs of the repository.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use memmap2::Mmap;
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+use miniz_oxide::inflate::stream::{inflate, InflateState, MinReset};
+use crate::index::Index;
+use crate::io::{token, u32_be};
+use crate::oid::{HashAlgorithm, Hasher, ObjectId};
+use crate::cancel::{self, CancellationToken};
+use crate::progress::{should_report, Event, Progress};
+use crate::pack::{apply_delta, encode_entry, ofs_from_reader, ofs_to_vec, vint_from_reader, vint_to_vec, Object, ObjectStore, ObjectType, UnpackError, DEFAULT_LEVEL};
+#[cfg(doc)]
+use crate::Pack;
+
+const INPUT_BUFFER_SIZE: usize = 8 * 1024;
+const OUTPUT_BUFFER_SIZE: usize = 16 * 1024;
+/// Limit of entries or bytes allocated ahead from counts and sizes declared in a pack
+const MAX_PREALLOCATED: u32 = 1 << 16;
+
+/// Options of [PackFile]
+#[derive(Debug, Clone)]
+pub struct PackFileOptions {
+    /// Hash algorithm of objects, which is not recorded in the pack itself
+    pub algorithm: HashAlgorithm,
+    /// Memory budget in bytes of inflated objects cached as delta bases, 64 MiB by default
+    ///
+    /// Objects larger than the budget are not cached, but the base of the last delta chain
+    /// is always kept, so that deltas sharing a base do not inflate it again.
+    pub cache_size: usize,
+    /// Receiver of [Event::Inflated] and [Event::Resolved]
+    pub progress: Option<Arc<dyn Progress>>,
+    /// Token checked between objects while reading the pack
+    pub cancel: Option<CancellationToken>,
+}
+
+impl Default for PackFileOptions {
+    fn default() -> Self {
+        Self {
+            algorithm: HashAlgorithm::Sha1,
+            cache_size: 64 * 1024 * 1024,
+            progress: None,
+            cancel: None,
+        }
+    }
+}
+
+/// Entry of the pack, without its data
+#[derive(Debug)]
+struct Entry {
+    /// Type as stored in pack, deltas keep their base
+    object_type: ObjectType,
+    /// Length of inflated data
+    size: usize,
+    /// Offset of compressed data
+    data: usize,
+    compressed_length: usize,
+    crc32: u32,
+    /// `None` for unresolved deltas
+    id: Option<ObjectId>,
+}
+
+/// Resolved type and data of an object
+type Cached = Arc<(ObjectType, Vec<u8>)>;
+
+/// Least recently used objects, keyed by offset, whose data fit in `limit` bytes
+struct Cache {
+    limit: usize,
+    used: usize,
+    tick: u64,
+    objects: HashMap<usize, (u64, Cached)>,
+    /// offset of objects by the tick they were last used
+    order: BTreeMap<u64, usize>,
+    /// Base of the last delta chain, kept outside of the budget
+    base: Option<(usize, Cached)>,
+}
+
+impl Cache {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: 0,
+            tick: 0,
+            objects: HashMap::new(),
+            order: BTreeMap::new(),
+            base: None,
+        }
+    }
+
+    fn get(&mut self, offset: usize) -> Option<Cached> {
+        if let Some((base, object)) = &self.base {
+            if *base == offset {
+                return Some(object.clone());
+            }
+        }
+        let (tick, object) = self.objects.get_mut(&offset)?;
+        self.order.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, offset);
+        Some(object.clone())
+    }
+
+    fn insert(&mut self, offset: usize, object: Cached) {
+        let size = object.1.len();
+        if size > self.limit || self.objects.contains_key(&offset) {
+            return;
+        }
+        while self.used + size > self.limit {
+            let (tick, evicted) = match self.order.iter().next() {
+                Some((tick, offset)) => (*tick, *offset),
+                None => break,
+            };
+            self.order.remove(&tick);
+            let (_, object) = self.objects.remove(&evicted).unwrap();
+            self.used -= object.1.len();
+        }
+        self.tick += 1;
+        self.used += size;
+        self.order.insert(self.tick, offset);
+        self.objects.insert(offset, (self.tick, object));
+    }
+
+    fn keep_base(&mut self, offset: usize, object: Cached) {
+        self.base = Some((offset, object));
+    }
+}
+
+/// Inflate zlib data of `size` bytes from `reader`, passing consumed input to `input` and inflated data to `output`
+fn inflate_from<R: BufRead>(
+    reader: &mut R,
+    state: &mut InflateState,
+    offset: usize,
+    size: usize,
+    mut input: impl FnMut(&[u8]),
+    mut output: impl FnMut(&[u8]),
+) -> Result<usize, UnpackError> {
+    let mut out = vec![0u8; OUTPUT_BUFFER_SIZE];
+    let mut consumed = 0;
+    let mut written = 0;
+    loop {
+        let buf = reader.fill_buf()?;
+        let eof = buf.is_empty();
+        let result = inflate(state, buf, &mut out, MZFlush::None);
+        input(&buf[..result.bytes_consumed]);
+        reader.consume(result.bytes_consumed);
+        consumed += result.bytes_consumed;
+        output(&out[..result.bytes_written]);
+        written += result.bytes_written;
+        match result.status {
+            Ok(MZStatus::StreamEnd) => break,
+            Ok(_) | Err(MZError::Buf) if !eof || result.bytes_written > 0 => {}
+            Ok(_) | Err(MZError::Buf) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            Err(_) => return Err(UnpackError::InvalidData(offset)),
+        }
+    }
+    state.reset_as(MinReset);
+    if written != size {
+        return Err(UnpackError::InvalidData(offset));
+    }
+    Ok(consumed)
+}
+
+/// Reader of the first pass, which hashes the whole pack and the crc32 of each entry
+struct Scanner<'a, R> {
+    reader: &'a mut R,
+    hasher: Hasher,
+    crc32: crc32fast::Hasher,
+    offset: usize,
+}
+
+impl<R: BufRead> Scanner<'_, R> {
+    fn inflate(&mut self, state: &mut InflateState, size: usize, output: impl FnMut(&[u8])) -> Result<usize, UnpackError> {
+        let Scanner { reader, hasher, crc32, offset } = self;
+        let consumed = inflate_from(*reader, state, *offset, size, |input| {
+            hasher.update(input);
+            crc32.update(input);
+        }, output)?;
+        *offset += consumed;
+        Ok(consumed)
+    }
+}
+
+impl<R: BufRead> Read for Scanner<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.crc32.update(&buf[..n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+/// Type and size of the entry at `offset`, whose header is read from `reader`
+///
+/// The base of an offset delta must be strictly before the entry, so that chains end.
+fn entry_header<R: Read>(reader: &mut R, offset: usize, algorithm: HashAlgorithm) -> Result<(ObjectType, usize), UnpackError> {
+    use crate::pack::ObjectType::*;
+    let (object_type, size, _) = vint_from_reader(reader)?;
+    let object_type = match object_type {
+        1 => Commit,
+        2 => Tree,
+        3 => Blob,
+        4 => Tag,
+        6 => match ofs_from_reader(reader)?.0 {
+            distance if distance == 0 || distance > offset => return Err(UnpackError::InvalidData(offset)),
+            distance => OfsDelta(distance),
+        },
+        7 => {
+            let mut id = vec![0u8; algorithm.size()];
+            reader.read_exact(&mut id)?;
+            RefDelta(ObjectId::from_bytes(&id).unwrap())
+        }
+        _ => return Err(UnpackError::InvalidObjectType),
+    };
+    Ok((object_type, size))
+}
+
+/// A pack read from `R`, whose objects are inflated when requested
+///
+/// Unlike [Pack], only the offset, id, type and length of each entry stay in memory.
+/// `R` is buffered to inflate from its buffer, like a [BufReader] of a file,
+/// or a [Cursor] of a slice, which does not copy compressed data.
+/// Reads are serialized by a lock, and [ObjectStore] returns owned objects.
+///
+/// ```no_run
+/// use anni_fetch::packfile::{PackFile, PackFileOptions};
+/// # fn example(id: &anni_fetch::ObjectId) -> Result<(), Box<dyn std::error::Error>> {
+/// let pack = PackFile::open("flutter.pack", PackFileOptions { cache_size: 16 << 20, ..Default::default() })?;
+/// if let Some(object) = pack.object(id)? {
+///     println!("{} {}", object.object_type.name(), object.data.len());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct PackFile<R> {
+    pub version: u32,
+    pub algorithm: HashAlgorithm,
+    pub checksum: ObjectId,
+    /// Position of pack header in reader
+    start: u64,
+    entries: HashMap<usize, Entry>,
+    ids: HashMap<ObjectId, usize>,
+    inner: Mutex<Inner<R>>,
+}
+
+/// State of a [PackFile] used to inflate objects
+struct Inner<R> {
+    reader: R,
+    cache: Cache,
+    state: Box<InflateState>,
+}
+
+/// A [PackFile] mapped into memory by [PackFile::map]
+pub type MappedPackFile = PackFile<Cursor<Mmap>>;
+
+impl PackFile<Cursor<Mmap>> {
+    /// Memory-map pack at `path`
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated or modified, by this or another process, while the
+    /// returned pack is alive, as reading a changed mapping is undefined behavior.
+    /// Packs git has written hold this, as they are never changed in place.
+    pub unsafe fn map<P: AsRef<Path>>(path: P, options: PackFileOptions) -> Result<Self, UnpackError> {
+        Self::new(Cursor::new(map(path)?), options)
+    }
+}
+
+/// Memory-map the file at `path` for reading
+///
+/// # Safety
+///
+/// Same as [PackFile::map], for as long as the mapping is alive.
+pub(crate) unsafe fn map<P: AsRef<Path>>(path: P) -> std::io::Result<Mmap> {
+    let file = File::open(path)?;
+    Mmap::map(&file)
+}
+
+/// Cut pack `file`, which was partially received, after its last complete entry,
+/// and return the number of entries kept
+///
+/// Like [crate::verify::salvage_pack], but on disk: each entry must have a valid header
+/// and a complete zlib stream with a matching Adler-32 and length. The header gets the count
+/// of kept entries and the pack gets a new checksum. Nothing is changed if no entry is complete.
+pub fn salvage(file: &mut File, algorithm: HashAlgorithm) -> std::io::Result<u32> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::with_capacity(INPUT_BUFFER_SIZE, &mut *file);
+    let mut scanner = Scanner {
+        reader: &mut reader,
+        hasher: algorithm.hasher(),
+        crc32: crc32fast::Hasher::new(),
+        offset: 0,
+    };
+    let header = token(&mut scanner, b"PACK").and_then(|_| u32_be(&mut scanner)).and_then(|_| u32_be(&mut scanner));
+    let count = match header {
+        Ok(count) => count,
+        Err(_) => return Ok(0),
+    };
+    let mut state = InflateState::new_boxed(DataFormat::Zlib);
+    let mut complete = 0;
+    let mut end = scanner.offset;
+    while complete < count {
+        let offset = scanner.offset;
+        let entry = entry_header(&mut scanner, offset, algorithm)
+            .and_then(|(_, size)| scanner.inflate(&mut state, size, |_| {}));
+        if entry.is_err() {
+            break;
+        }
+        complete += 1;
+        end = scanner.offset;
+    }
+    drop(reader);
+    if complete == 0 {
+        return Ok(0);
+    }
+
+    file.set_len(end as u64)?;
+    file.seek(SeekFrom::Start(8))?;
+    file.write_all(&complete.to_be_bytes())?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = algorithm.hasher();
+    std::io::copy(file, &mut hasher)?;
+    file.write_all(hasher.finalize().as_bytes())?;
+    Ok(complete)
+}
+
+impl PackFile<BufReader<File>> {
+    /// Open pack at `path`, reading it with buffered reads
+    pub fn open<P: AsRef<Path>>(path: P, options: PackFileOptions) -> Result<Self, UnpackError> {
+        Self::new(BufReader::with_capacity(INPUT_BUFFER_SIZE, File::open(path)?), options)
+    }
+
+    /// Copy pack from `stream`, which can not seek, like a response of [crate::Client], into a temporary file
+    ///
+    /// The file is removed when the returned pack is dropped.
+    pub fn from_stream<S: Read>(stream: &mut S, options: PackFileOptions) -> Result<Self, UnpackError> {
+        let mut file = tempfile::tempfile()?;
+        std::io::copy(stream, &mut file)?;
+        file.seek(SeekFrom::Start(0))?;
+        Self::new(BufReader::with_capacity(INPUT_BUFFER_SIZE, file), options)
+    }
+}
+
+impl<R: BufRead + Seek> PackFile<R> {
+    /// Read a pack starting at the current position of `reader`
+    ///
+    /// Every object is inflated to compute its id, and deltas in this pack are resolved.
+    /// Deltas whose base is not in this pack are listed in [PackFile::unresolved].
+    pub fn new(mut reader: R, options: PackFileOptions) -> Result<Self, UnpackError> {
+        let algorithm = options.algorithm;
+        let start = reader.stream_position()?;
+        let mut state = InflateState::new_boxed(DataFormat::Zlib);
+
+        let mut scanner = Scanner {
+            reader: &mut reader,
+            hasher: algorithm.hasher(),
+            crc32: crc32fast::Hasher::new(),
+            offset: 0,
+        };
+        token(&mut scanner, b"PACK")?;
+        let version = u32_be(&mut scanner)?;
+        let count = u32_be(&mut scanner)?;
+
+        // the count comes from the sender, so maps grow as entries are actually read
+        let capacity = count.min(MAX_PREALLOCATED) as usize;
+        let mut entries = HashMap::with_capacity(capacity);
+        let mut ids = HashMap::with_capacity(capacity);
+        for _ in 0..count {
+            cancel::check(options.cancel.as_ref())?;
+            let offset = scanner.offset;
+            scanner.crc32 = crc32fast::Hasher::new();
+            let (object_type, size) = entry_header(&mut scanner, offset, algorithm)?;
+
+            let data = scanner.offset;
+            let mut hasher = None;
+            if !object_type.is_delta() {
+                let mut h = algorithm.hasher();
+                h.update(format!("{} {}\0", object_type.name(), size).as_bytes());
+                hasher = Some(h);
+            }
+            let compressed_length = scanner.inflate(&mut state, size, |output| {
+                if let Some(hasher) = hasher.as_mut() {
+                    hasher.update(output);
+                }
+            })?;
+            let id = hasher.map(Hasher::finalize);
+            if let Some(id) = id {
+                ids.insert(id, offset);
+            }
+            let crc32 = std::mem::replace(&mut scanner.crc32, crc32fast::Hasher::new()).finalize();
+            entries.insert(offset, Entry { object_type, size, data, compressed_length, crc32, id });
+            if let Some(progress) = &options.progress {
+                if should_report(entries.len(), count as usize) {
+                    progress.event(&Event::Inflated { done: entries.len(), total: count as usize });
+                }
+            }
+        }
+
+        let checksum = scanner.hasher.finalize();
+        let mut expected = vec![0u8; algorithm.size()];
+        reader.read_exact(&mut expected)?;
+        if checksum.as_bytes() != expected.as_slice() {
+            return Err(UnpackError::InvalidHash);
+        }
+
+        let mut pack = Self {
+            version,
+            algorithm,
+            checksum,
+            start,
+            entries,
+            ids,
+            inner: Mutex::new(Inner { reader, cache: Cache::new(options.cache_size), state }),
+        };
+        pack.resolve_deltas(options.progress.as_deref(), options.cancel.as_ref())?;
+        Ok(pack)
+    }
+
+    /// Read a pack starting at the current position of `reader`, with entries of its `index`
+    ///
+    /// Only entry headers are read, as ids and offsets are known from the index,
+    /// so opening a pack does not inflate any object. The index must belong to the pack,
+    /// which is checked against the pack checksum.
+    pub fn from_index(mut reader: R, index: &Index, options: PackFileOptions) -> Result<Self, UnpackError> {
+        let algorithm = options.algorithm;
+        let start = reader.stream_position()?;
+        token(&mut reader, b"PACK")?;
+        let version = u32_be(&mut reader)?;
+        let count = u32_be(&mut reader)?;
+        let end = reader.seek(SeekFrom::End(0))? - start;
+        if count as usize != index.entries.len() || end < 12 + algorithm.size() as u64 {
+            return Err(UnpackError::InvalidHash);
+        }
+        let end = end as usize - algorithm.size();
+        reader.seek(SeekFrom::Start(start + end as u64))?;
+        let mut checksum = vec![0u8; algorithm.size()];
+        reader.read_exact(&mut checksum)?;
+        if checksum != index.pack_checksum.as_bytes() {
+            return Err(UnpackError::InvalidHash);
+        }
+
+        let mut offsets: Vec<usize> = index.entries.iter().map(|(_, offset, _)| *offset).collect();
+        offsets.sort_unstable();
+        let mut entries = HashMap::with_capacity(index.entries.len());
+        let mut ids = HashMap::with_capacity(index.entries.len());
+        for (id, offset, crc32) in index.entries.iter() {
+            cancel::check(options.cancel.as_ref())?;
+            if *offset < 12 || *offset >= end {
+                return Err(UnpackError::InvalidData(*offset));
+            }
+            reader.seek(SeekFrom::Start(start + *offset as u64))?;
+            let (object_type, size) = entry_header(&mut reader, *offset, algorithm)?;
+            let data = (reader.stream_position()? - start) as usize;
+            // entries are contiguous, so each one ends where the next one starts
+            let next = offsets.partition_point(|o| o <= offset);
+            let next = offsets.get(next).copied().unwrap_or(end);
+            let compressed_length = next.checked_sub(data).ok_or(UnpackError::InvalidData(*offset))?;
+            entries.insert(*offset, Entry { object_type, size, data, compressed_length, crc32: *crc32, id: Some(*id) });
+            ids.insert(*id, *offset);
+        }
+
+        Ok(Self {
+            version,
+            algorithm,
+            checksum: index.pack_checksum,
+            start,
+            entries,
+            ids,
+            inner: Mutex::new(Inner {
+                reader,
+                cache: Cache::new(options.cache_size),
+                state: InflateState::new_boxed(DataFormat::Zlib),
+            }),
+        })
+    }
+
+    /// Compute ids of deltas, until no more base can be found
+    fn resolve_deltas(&mut self, progress: Option<&dyn Progress>, token: Option<&CancellationToken>) -> Result<(), UnpackError> {
+        let mut pending: Vec<usize> = self.entries.iter()
+            .filter(|(_, entry)| entry.id.is_none())
+            .map(|(offset, _)| *offset)
+            .collect();
+        pending.sort_unstable();
+        let total = pending.len();
+        let mut done = 0;
+        loop {
+            let before = pending.len();
+            let mut remaining = Vec::new();
+            for offset in pending {
+                cancel::check(token)?;
+                match self.load(offset) {
+                    Ok(object) => {
+                        let mut hasher = self.algorithm.hasher();
+                        hasher.update(format!("{} {}\0", object.0.name(), object.1.len()).as_bytes());
+                        hasher.update(&object.1);
+                        let id = hasher.finalize();
+                        self.entries.get_mut(&offset).unwrap().id = Some(id);
+                        self.ids.insert(id, offset);
+                        done += 1;
+                        if let Some(progress) = progress {
+                            if should_report(done, total) {
+                                progress.event(&Event::Resolved { done, total });
+                            }
+                        }
+                    }
+                    Err(UnpackError::MissingBase(_)) => remaining.push(offset),
+                    Err(e) => return Err(e),
+                }
+            }
+            if remaining.is_empty() || remaining.len() == before {
+                return Ok(());
+            }
+            pending = remaining;
+        }
+    }
+
+    /// Resolved type and data of the object at `offset`
+    fn load(&self, offset: usize) -> Result<Cached, UnpackError> {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { reader, cache, state } = &mut *inner;
+        let mut inflate_at = |offset: usize| -> Result<Vec<u8>, UnpackError> {
+            // data of a delta entry is the delta itself
+            let entry = &self.entries[&offset];
+            reader.seek(SeekFrom::Start(self.start + entry.data as u64))?;
+            let mut data = Vec::with_capacity(entry.size.min(MAX_PREALLOCATED as usize));
+            inflate_from(reader, state, entry.data, entry.size, |_| {}, |output| data.extend_from_slice(output))?;
+            Ok(data)
+        };
+
+        // walk down the delta chain until a cached object or a whole object
+        let mut chain = Vec::new();
+        let mut current = offset;
+        let mut object = loop {
+            if let Some(object) = cache.get(current) {
+                break object;
+            }
+            let entry = self.entries.get(&current).ok_or(UnpackError::MissingBase(offset))?;
+            match &entry.object_type {
+                ObjectType::OfsDelta(distance) => {
+                    chain.push(current);
+                    current = match current.checked_sub(*distance) {
+                        Some(base) if base < current => base,
+                        _ => return Err(UnpackError::InvalidData(current)),
+                    };
+                }
+                ObjectType::RefDelta(id) => {
+                    chain.push(current);
+                    current = *self.ids.get(id).ok_or(UnpackError::MissingBase(offset))?;
+                }
+                object_type => {
+                    let object_type = object_type.clone();
+                    let object = Arc::new((object_type, inflate_at(current)?));
+                    cache.insert(current, object.clone());
+                    break object;
+                }
+            }
+        };
+        if !chain.is_empty() {
+            cache.keep_base(current, object.clone());
+        }
+
+        for current in chain.into_iter().rev() {
+            let delta = inflate_at(current)?;
+            object = Arc::new((object.0.clone(), apply_delta(&object.1, &delta)?));
+            cache.insert(current, object.clone());
+        }
+        Ok(object)
+    }
+
+    /// Object with `id`, inflated from the pack unless cached
+    pub fn object(&self, id: &ObjectId) -> Result<Option<Object>, UnpackError> {
+        match self.ids.get(id) {
+            Some(offset) => self.offset(*offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Resolved object at `offset`
+    pub fn offset(&self, offset: usize) -> Result<Object, UnpackError> {
+        let object = self.load(offset)?;
+        let entry = &self.entries[&offset];
+        Ok(Object {
+            object_type: object.0.clone(),
+            data: object.1.clone(),
+            compressed_length: entry.compressed_length,
+            offset,
+            algorithm: self.algorithm,
+        })
+    }
+}
+
+impl<R: BufRead + Seek> PackFile<R> {
+    /// Write a pack of the entries at offsets `keep` accepts to `writer`, and return its checksum
+    ///
+    /// Entries are copied without being inflated, and offset deltas get the distance to their
+    /// base in the written pack. Resolved deltas whose base is left out are written as whole
+    /// objects, and unresolved ones are left out too, except deltas against objects by id which
+    /// are not in this pack, as in a thin pack.
+    pub fn copy_entries<W: Write, F: Fn(usize) -> bool>(&self, keep: F, writer: &mut W) -> Result<ObjectId, UnpackError> {
+        enum Copy {
+            Raw,
+            Rebase(usize),
+            Whole,
+        }
+        let mut offsets: Vec<usize> = self.entries.keys().copied().filter(|offset| keep(*offset)).collect();
+        offsets.sort_unstable();
+        let mut copies = Vec::with_capacity(offsets.len());
+        let mut written = HashSet::new();
+        for offset in offsets {
+            let entry = &self.entries[&offset];
+            let base = match &entry.object_type {
+                ObjectType::OfsDelta(distance) => Some(offset - distance),
+                ObjectType::RefDelta(id) => self.ids.get(id).copied(),
+                _ => None,
+            };
+            let copy = match (&entry.object_type, base) {
+                (ObjectType::OfsDelta(_), Some(base)) if written.contains(&base) => Copy::Rebase(base),
+                (ObjectType::RefDelta(_), Some(base)) if written.contains(&base) => Copy::Raw,
+                (ObjectType::RefDelta(_), None) => Copy::Raw,
+                (_, Some(_)) if entry.id.is_some() => Copy::Whole,
+                (_, Some(_)) => continue,
+                (_, None) => Copy::Raw,
+            };
+            written.insert(offset);
+            copies.push((offset, copy));
+        }
+
+        let mut hasher = self.algorithm.hasher();
+        let mut write = |data: &[u8]| -> std::io::Result<()> {
+            hasher.update(data);
+            writer.write_all(data)
+        };
+        write(b"PACK")?;
+        write(&2u32.to_be_bytes())?;
+        write(&(copies.len() as u32).to_be_bytes())?;
+        let mut position = 12;
+        let mut moved = HashMap::with_capacity(copies.len());
+        let mut buffer = vec![0u8; OUTPUT_BUFFER_SIZE];
+        for (offset, copy) in copies {
+            moved.insert(offset, position);
+            let entry = &self.entries[&offset];
+            let (header, from) = match copy {
+                Copy::Raw => (Vec::new(), offset),
+                Copy::Rebase(base) => {
+                    let mut header = vint_to_vec(6, entry.size);
+                    header.extend_from_slice(&ofs_to_vec(position - moved[&base]));
+                    (header, entry.data)
+                }
+                Copy::Whole => {
+                    let object = self.load(offset)?;
+                    let encoded = encode_entry(object.0.code(), &object.1, DEFAULT_LEVEL);
+                    write(&encoded)?;
+                    position += encoded.len();
+                    continue;
+                }
+            };
+            write(&header)?;
+            position += header.len();
+
+            let mut inner = self.inner.lock().unwrap();
+            inner.reader.seek(SeekFrom::Start(self.start + from as u64))?;
+            let mut remaining = entry.data + entry.compressed_length - from;
+            position += remaining;
+            while remaining > 0 {
+                let n = remaining.min(buffer.len());
+                inner.reader.read_exact(&mut buffer[..n])?;
+                write(&buffer[..n])?;
+                remaining -= n;
+            }
+        }
+        let checksum = hasher.finalize();
+        writer.write_all(checksum.as_bytes())?;
+        Ok(checksum)
+    }
+}
+
+/// Objects which can not be read are treated as missing
+impl<R: BufRead + Seek> ObjectStore for PackFile<R> {
+    fn object(&self, id: &ObjectId) -> Option<Cow<'_, Object>> {
+        PackFile::object(self, id).ok().flatten().map(Cow::Owned)
+    }
+}
+
+impl<R> PackFile<R> {
+    /// Reader the pack is read from, at an unspecified position
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner().unwrap().reader
+    }
+
+    pub fn contains(&self, id: &ObjectId) -> bool {
+        self.ids.contains_key(id)
+    }
+
+    /// Number of entries, including unresolved deltas
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Ids of resolved objects, in no particular order
+    pub fn ids(&self) -> impl Iterator<Item=&ObjectId> {
+        self.ids.keys()
+    }
+
+    /// Offsets of all entries, in no particular order
+    pub fn offsets(&self) -> impl Iterator<Item=usize> + '_ {
+        self.entries.keys().copied()
+    }
+
+    /// Type of resolved object `id`, found from the base of its delta chain without inflating it
+    pub fn object_type(&self, id: &ObjectId) -> Option<ObjectType> {
+        let mut offset = *self.ids.get(id)?;
+        // resolved chains end, this only bounds the walk
+        for _ in 0..=self.entries.len() {
+            offset = match &self.entries.get(&offset)?.object_type {
+                ObjectType::OfsDelta(distance) => offset - distance,
+                ObjectType::RefDelta(base) => *self.ids.get(base)?,
+                object_type => return Some(object_type.clone()),
+            };
+        }
+        None
+    }
+
+    /// Id of the object at `offset`, `None` for unresolved deltas
+    pub fn id_at(&self, offset: usize) -> Option<&ObjectId> {
+        self.entries.get(&offset).and_then(|entry| entry.id.as_ref())
+    }
+
+    /// CRC32 of the raw entry at `offset`, as stored in pack index
+    pub fn crc32(&self, offset: usize) -> Option<u32> {
+        self.entries.get(&offset).map(|entry| entry.crc32)
+    }
+
+    /// Offsets and bases of delta objects whose base is not in this pack, as in a thin pack
+    pub fn unresolved(&self) -> impl Iterator<Item=(usize, &ObjectType)> {
+        self.entries.iter()
+            .filter(|(_, entry)| entry.id.is_none())
+            .map(|(offset, entry)| (*offset, &entry.object_type))
+    }
+
+    /// Write version 2 pack index, like [crate::index::write_index]
+    pub fn write_index<W: Write>(&self, writer: &mut W) -> std::io::Result<ObjectId> {
+        use std::io::{Error, ErrorKind};
+        if self.unresolved().next().is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput, "pack has unresolved deltas"));
+        }
+        let entries = self.ids.iter()
+            .map(|(id, offset)| (*id, *offset, self.entries[offset].crc32))
+            .collect();
+        crate::index::write_entries(writer, entries, &self.checksum, self.algorithm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Seek, Write};
+    use std::process::{Command, Stdio};
+    use crate::index::{read_index, write_index};
+    use crate::oid::HashAlgorithm;
+    use crate::packfile::{PackFile, PackFileOptions};
+    use crate::pack::{ObjectType, UnpackError};
+    use crate::testing::{commit, git, pack_objects};
+    use crate::Pack;
+
+    #[test]
+    fn test_pack_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path();
+        git(work, &["init", "-q"]);
+        let mut content: String = (0..500).map(|i| format!("line {}\n", i)).collect();
+        for i in 0..8 {
+            content.push_str(&format!("more {}\n", i));
+            commit(work, &[("a.txt", &content), ("b.txt", &content.replace("line", "row"))], &format!("commit {}", i));
+        }
+        let raw = pack_objects(work, "HEAD");
+        let pack = Pack::from_reader(&mut Cursor::new(&raw)).unwrap();
+
+        // a budget smaller than any blob, or fitting a few, must not change results
+        for cache_size in [0, 8 * 1024, 1 << 20] {
+            let options = PackFileOptions { cache_size, ..Default::default() };
+            let file = PackFile::new(Cursor::new(&raw), options).unwrap();
+            assert_eq!(file.len(), pack.objects.len());
+            assert_eq!(file.checksum, pack.checksum);
+            assert_eq!(file.unresolved().count(), 0);
+            for (id, expected) in pack.objects.iter() {
+                assert!(file.contains(id));
+                assert_eq!(&file.object(id).unwrap().unwrap(), expected);
+                assert_eq!(file.crc32(expected.offset), pack.crc32(expected.offset));
+            }
+            assert!(file.object(&HashAlgorithm::Sha1.null()).unwrap().is_none());
+
+            let mut expected = Vec::new();
+            write_index(&mut expected, &pack).unwrap();
+            let mut index = Vec::new();
+            file.write_index(&mut index).unwrap();
+            assert_eq!(index, expected);
+        }
+
+        let file = PackFile::from_stream(&mut raw.as_slice(), PackFileOptions::default()).unwrap();
+        assert_eq!(file.ids().count(), pack.objects.len());
+
+        let path = dir.path().join("test.pack");
+        std::fs::write(&path, &raw).unwrap();
+        // SAFETY: the file is not changed by the test while it is mapped
+        let mapped = unsafe { PackFile::map(&path, PackFileOptions { cache_size: 0, ..Default::default() }) }.unwrap();
+        for (id, expected) in pack.objects.iter() {
+            assert_eq!(&mapped.object(id).unwrap().unwrap(), expected);
+        }
+        // without a budget, the base of the last chain is still kept
+        let offset = mapped.entries.iter()
+            .find(|(_, entry)| matches!(entry.object_type, ObjectType::OfsDelta(_)))
+            .map(|(offset, _)| *offset)
+            .unwrap();
+        mapped.offset(offset).unwrap();
+        let inner = mapped.inner.lock().unwrap();
+        assert!(inner.cache.objects.is_empty());
+        assert!(inner.cache.base.is_some());
+        drop(inner);
+        assert_eq!(PackFile::open(&path, PackFileOptions::default()).unwrap().len(), mapped.len());
+
+        // entries read from the index match entries found by inflating the pack
+        let mut idx = Vec::new();
+        write_index(&mut idx, &pack).unwrap();
+        let index = read_index(&idx, HashAlgorithm::Sha1).unwrap();
+        assert_eq!(index.pack_checksum, pack.checksum);
+        let indexed = PackFile::from_index(Cursor::new(&raw), &index, PackFileOptions::default()).unwrap();
+        assert_eq!(indexed.len(), mapped.len());
+        for (id, expected) in pack.objects.iter() {
+            assert_eq!(indexed.crc32(expected.offset), mapped.crc32(expected.offset));
+            assert_eq!(&indexed.object(id).unwrap().unwrap(), expected);
+        }
+        let mut other = raw.clone();
+        let len = other.len();
+        other[len - 1] ^= 1;
+        assert!(matches!(PackFile::from_index(Cursor::new(&other), &index, PackFileOptions::default()), Err(UnpackError::InvalidHash)));
+        idx[10] ^= 1;
+        assert!(read_index(&idx, HashAlgorithm::Sha1).is_err());
+
+        let mut corrupted = raw.clone();
+        let len = corrupted.len();
+        corrupted[len - 1] ^= 1;
+        assert!(matches!(PackFile::new(Cursor::new(corrupted), PackFileOptions::default()), Err(UnpackError::InvalidHash)));
+    }
+
+    #[test]
+    fn test_thin_pack_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path();
+        git(work, &["init", "-q"]);
+        let content: String = (0..500).map(|i| format!("line {}\n", i)).collect();
+        commit(work, &[("a.txt", &content)], "a");
+        commit(work, &[("a.txt", &format!("{}line 500\n", content))], "b");
+
+        let mut child = Command::new("git")
+            .current_dir(work)
+            .args(["pack-objects", "--stdout", "--revs", "--thin"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"HEAD\n^HEAD~1\n").unwrap();
+        let raw = child.wait_with_output().unwrap().stdout;
+
+        let file = PackFile::new(Cursor::new(raw), PackFileOptions::default()).unwrap();
+        let unresolved: Vec<_> = file.unresolved().map(|(offset, base)| (offset, base.clone())).collect();
+        assert_eq!(unresolved.len(), 1);
+        let base = git(work, &["rev-parse", "HEAD~1:a.txt"]).trim().parse().unwrap();
+        assert_eq!(unresolved[0].1, ObjectType::RefDelta(base));
+        assert!(matches!(file.offset(unresolved[0].0), Err(UnpackError::MissingBase(_))));
+        assert!(file.write_index(&mut Vec::new()).is_err());
+        assert_eq!(file.ids().count(), file.len() - 1);
+    }
+
+    #[test]
+    fn test_invalid_pack_file() {
+        let pack = |count: u32, entries: &[&[u8]]| -> Vec<u8> {
+            let mut raw = b"PACK\0\0\0\x02".to_vec();
+            raw.extend_from_slice(&count.to_be_bytes());
+            for entry in entries {
+                raw.extend_from_slice(entry);
+            }
+            let checksum = HashAlgorithm::Sha1.digest(&raw);
+            raw.extend_from_slice(checksum.as_bytes());
+            raw
+        };
+        let zlib = |data: &[u8]| miniz_oxide::deflate::compress_to_vec_zlib(data, 6);
+        let mut blob = vec![0x31];
+        blob.extend_from_slice(&zlib(b"a"));
+        // an offset delta based on itself, with distance 0
+        let mut delta = vec![0x63, 0x00];
+        delta.extend_from_slice(&zlib(b"\x01\x01\x81"));
+
+        let raw = pack(2, &[&blob, &delta]);
+        let result = PackFile::new(Cursor::new(raw), PackFileOptions::default());
+        assert!(matches!(result, Err(UnpackError::InvalidData(offset)) if offset == 12 + blob.len()));
+
+        // a count far larger than the entries is not allocated ahead
+        let raw = pack(u32::MAX, &[&blob]);
+        assert!(PackFile::new(Cursor::new(raw), PackFileOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_salvage_and_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path();
+        git(work, &["init", "-q"]);
+        let mut content: String = (0..500).map(|i| format!("line {}\n", i)).collect();
+        for i in 0..8 {
+            content.push_str(&format!("more {}\n", i));
+            commit(work, &[("a.txt", &content)], &format!("commit {}", i));
+        }
+        let raw = pack_objects(work, "HEAD");
+        let pack = Pack::from_reader(&mut Cursor::new(&raw)).unwrap();
+
+        // cut inside the last entries, which are lost
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&raw[..raw.len() - 60]).unwrap();
+        let count = super::salvage(&mut file, HashAlgorithm::Sha1).unwrap();
+        assert!(count > 0 && (count as usize) < pack.objects.len());
+        file.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let salvaged = PackFile::new(std::io::BufReader::new(file), PackFileOptions::default()).unwrap();
+        assert_eq!(salvaged.len(), count as usize);
+        for id in salvaged.ids() {
+            assert_eq!(salvaged.object(id).unwrap().unwrap().data, pack.objects[id].data);
+        }
+
+        // blobs are deltas of each other, kept whole or rebased when their base is left out
+        let full = PackFile::new(Cursor::new(&raw), PackFileOptions::default()).unwrap();
+        let left_out = pack.objects.iter().find(|(_, o)| o.object_type == ObjectType::Blob).map(|(id, _)| id);
+        let keep = |offset| full.id_at(offset).is_some_and(|id| full.object_type(id) == Some(ObjectType::Blob) && Some(id) != left_out);
+        let mut copied = Vec::new();
+        let checksum = full.copy_entries(keep, &mut copied).unwrap();
+        let copied = PackFile::new(Cursor::new(copied), PackFileOptions::default()).unwrap();
+        assert_eq!(copied.checksum, checksum);
+        assert_eq!(copied.unresolved().count(), 0);
+        assert_eq!(copied.len(), pack.objects.values().filter(|o| o.object_type == ObjectType::Blob).count() - 1);
+        for id in copied.ids() {
+            assert_eq!(copied.object(id).unwrap().unwrap().data, pack.objects[id].data);
+        }
+    }
+}
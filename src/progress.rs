@@ -0,0 +1,157 @@
+//! Structured progress of fetching and unpacking, to render progress bars.
+//!
+//! Progress text sent by the server in sideband 2 looks like:
+//!
+//! ```text
+//! Enumerating objects: 20, done.
+//! Counting objects:  45% (9/20)\r
+//! Counting objects: 100% (20/20), done.
+//! Total 20 (delta 3), reused 0 (delta 0), pack-reused 0
+//! ```
+
+use std::fmt;
+use std::time::Instant;
+
+/// Receiver of progress [Event]s
+///
+/// Events may be sent from threads resolving deltas, so implementations use interior mutability.
+/// Closures taking `&Event` implement this trait.
+pub trait Progress: Send + Sync {
+    fn event(&self, event: &Event);
+}
+
+impl<F: Fn(&Event) + Send + Sync> Progress for F {
+    fn event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+impl fmt::Debug for dyn Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Stage reported by the server
+    Remote(Stage),
+    /// Other text from the server, like `Total 20 (delta 3), reused 0 (delta 0)`
+    RemoteText(String),
+    /// Bytes of pack data received, and the average rate in bytes per second since the request
+    Received { bytes: u64, rate: f64 },
+    /// Objects inflated, out of the count in pack header
+    Inflated { done: usize, total: usize },
+    /// Deltas resolved, out of all deltas in pack
+    Resolved { done: usize, total: usize },
+}
+
+/// A stage of the server like `Counting objects: 45% (9/20)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stage {
+    pub name: String,
+    pub done: u64,
+    /// `None` for stages without a known total, like `Enumerating objects: 20`
+    pub total: Option<u64>,
+    /// Whether the stage ends with `, done.`
+    pub finished: bool,
+}
+
+impl Stage {
+    pub fn percent(&self) -> Option<u32> {
+        match self.total {
+            Some(0) => Some(100),
+            Some(total) => Some((self.done * 100 / total) as u32),
+            None => None,
+        }
+    }
+
+    /// Parse a single line of progress, `None` for other text
+    ///
+    /// ```text
+    /// stage = name ": " (percent "% (" done "/" total ")" / done) [", " throughput] [", done."]
+    /// ```
+    pub fn parse(line: &str) -> Option<Self> {
+        let (name, rest) = line.trim().rsplit_once(": ")?;
+        let rest = rest.trim_start();
+        let finished = rest.ends_with(", done.");
+        let (done, total) = match rest.split_once("% (") {
+            Some((_, counts)) => {
+                let (done, total) = counts.split_once(')')?.0.split_once('/')?;
+                (done.parse().ok()?, Some(total.parse().ok()?))
+            }
+            None => {
+                let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                (rest[..digits].parse().ok()?, None)
+            }
+        };
+        Some(Stage { name: name.to_owned(), done, total, finished })
+    }
+}
+
+/// Parse progress text received in a `Message::PackProgress`, which may have several updates
+pub fn parse_remote(text: &str) -> Vec<Event> {
+    text.split(['\r', '\n'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| match Stage::parse(line) {
+            Some(stage) => Event::Remote(stage),
+            None => Event::RemoteText(line.to_owned()),
+        })
+        .collect()
+}
+
+/// Whether `done` out of `total` is worth reporting, as the percentage changed or all is done
+pub(crate) fn should_report(done: usize, total: usize) -> bool {
+    done == total || done * 100 / total != (done - 1) * 100 / total
+}
+
+/// Counter of received pack data, reporting [Event::Received]
+pub(crate) struct Transfer {
+    start: Instant,
+    bytes: u64,
+}
+
+impl Transfer {
+    pub fn new() -> Self {
+        Self { start: Instant::now(), bytes: 0 }
+    }
+
+    pub fn received(&mut self, progress: &dyn Progress, bytes: usize) {
+        self.bytes += bytes as u64;
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { self.bytes as f64 / elapsed } else { 0.0 };
+        progress.event(&Event::Received { bytes: self.bytes, rate });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::progress::{parse_remote, Event, Stage};
+
+    #[test]
+    fn test_parse_stage() {
+        let stage = Stage::parse("Counting objects:  45% (9/20)").unwrap();
+        assert_eq!(stage, Stage { name: "Counting objects".to_owned(), done: 9, total: Some(20), finished: false });
+        assert_eq!(stage.percent(), Some(45));
+
+        let stage = Stage::parse("Compressing objects: 100% (12/12), done.").unwrap();
+        assert_eq!((stage.done, stage.total, stage.finished), (12, Some(12), true));
+        let stage = Stage::parse("Enumerating objects: 20, done.").unwrap();
+        assert_eq!((stage.done, stage.total, stage.finished), (20, None, true));
+        assert_eq!(stage.percent(), None);
+        let stage = Stage::parse("remote: Receiving objects:  50% (5/10), 1.00 MiB | 2.00 MiB/s").unwrap();
+        assert_eq!((stage.name.as_str(), stage.done, stage.total), ("remote: Receiving objects", 5, Some(10)));
+
+        assert_eq!(Stage::parse("Total 20 (delta 3), reused 0 (delta 0), pack-reused 0"), None);
+    }
+
+    #[test]
+    fn test_parse_remote() {
+        let events = parse_remote("Counting objects:  50% (1/2)\rCounting objects: 100% (2/2), done.\nTotal 2 (delta 0)");
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], Event::Remote(s) if s.done == 1));
+        assert!(matches!(&events[1], Event::Remote(s) if s.finished));
+        assert_eq!(events[2], Event::RemoteText("Total 2 (delta 0)".to_owned()));
+    }
+}
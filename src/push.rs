@@ -0,0 +1,708 @@
+//! Update refs of a remote repository with `git-receive-pack`.
+//!
+//! https://git-scm.com/docs/pack-protocol#_pushing_data_to_a_server
+
+use std::collections::HashSet;
+use std::io::{Cursor, Read, Write};
+use thiserror::Error;
+use crate::client::{self, ClientError};
+use crate::io;
+use crate::object::ObjectError;
+use crate::oid::{HashAlgorithm, ObjectId};
+use crate::pack::{ObjectStore, ObjectType, PackWriter};
+use crate::refspec::UpdateKind;
+use crate::revwalk::{RevWalk, RevWalkError};
+use crate::utils::{hex, unhex_with};
+
+#[derive(Debug, Error)]
+pub enum PushError {
+    #[error("invalid ref advertisement: {0}")]
+    InvalidAdvertisement(String),
+    #[error("invalid status report: {0}")]
+    InvalidReport(String),
+    #[error("remote does not support {0}")]
+    Unsupported(&'static str),
+    #[error("remote failed to unpack objects: {0}")]
+    UnpackFailed(String),
+    #[error("remote error: {0}")]
+    RemoteError(String),
+    #[error("invalid object id {0}")]
+    InvalidId(String),
+    #[error("object {0} not found")]
+    MissingObject(String),
+
+    #[error(transparent)]
+    ObjectError(#[from] ObjectError),
+    #[error(transparent)]
+    RevWalkError(#[from] RevWalkError),
+    #[error(transparent)]
+    ClientError(#[from] ClientError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// Why a ref was not updated, either checked locally or reported by `ng` from the remote
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PushRejection {
+    /// Remote ref is not an ancestor of the new id
+    #[error("non-fast-forward")]
+    NonFastForward,
+    /// Remote ref points to an object which is not available locally
+    #[error("fetch first")]
+    FetchFirst,
+    /// Tags are only replaced with force
+    #[error("already exists")]
+    AlreadyExists,
+    /// Another ref of an atomic push was rejected
+    #[error("atomic push failed")]
+    AtomicFailed,
+    /// Declined by the pre-receive or update hook
+    #[error("hook declined")]
+    HookDeclined,
+    #[error("deletion prohibited")]
+    DeletionProhibited,
+    /// Branch checked out in a non-bare remote repository
+    #[error("branch is currently checked out")]
+    CurrentBranch,
+    #[error("{0}")]
+    Other(String),
+}
+
+impl PushRejection {
+    /// Parse the reason of an `ng` line
+    fn parse(reason: &str) -> Self {
+        match reason {
+            "non-fast-forward" => PushRejection::NonFastForward,
+            "fetch first" => PushRejection::FetchFirst,
+            "already exists" => PushRejection::AlreadyExists,
+            "branch is currently checked out" => PushRejection::CurrentBranch,
+            r if r.contains("atomic") => PushRejection::AtomicFailed,
+            r if r.ends_with("hook declined") => PushRejection::HookDeclined,
+            r if r.starts_with("deletion") && r.ends_with("prohibited") => PushRejection::DeletionProhibited,
+            r => PushRejection::Other(r.to_owned()),
+        }
+    }
+}
+
+/// A ref to update on the remote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushUpdate {
+    /// Full name of the remote ref
+    pub name: String,
+    /// New object id, or `None` to delete the ref
+    pub new: Option<ObjectId>,
+    /// Update even if it is not a fast-forward
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PushOptions {
+    /// Update all refs or none of them
+    pub atomic: bool,
+    /// Strings passed to hooks of the remote as `GIT_PUSH_OPTION_<n>`
+    pub push_options: Vec<String>,
+    /// Shallow commits of the local repository, as listed in `shallow`, whose parents are missing
+    pub shallow: Vec<ObjectId>,
+}
+
+/// Result of a [PushUpdate]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushedRef {
+    pub name: String,
+    /// Id of the remote ref before the push
+    pub old: Option<ObjectId>,
+    pub new: Option<ObjectId>,
+    pub result: Result<UpdateKind, PushRejection>,
+}
+
+/// Refs and capabilities advertised by `git-receive-pack`
+///
+/// ```text
+/// advertised-refs = *1("version 1")
+///                   (no-refs / list-of-refs)
+///                   *shallow
+///                   flush-pkt
+/// no-refs = PKT-LINE(zero-id SP "capabilities^{}" NUL capability-list)
+/// list-of-refs = first-ref *other-ref
+/// first-ref = PKT-LINE(obj-id SP refname NUL capability-list)
+/// other-ref = PKT-LINE(other-tip / other-peeled)
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Advertisement {
+    /// `(name, id)` of remote refs
+    pub refs: Vec<(String, ObjectId)>,
+    /// Ids from `.have` lines, which the remote has in alternate repositories
+    pub haves: Vec<ObjectId>,
+    pub capabilities: Vec<String>,
+}
+
+impl Advertisement {
+    /// Read an advertisement, skipping the `# service=` preamble of smart HTTP
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, PushError> {
+        let mut result = Advertisement::default();
+        let mut first = true;
+        let mut preamble = false;
+        let mut algorithm = HashAlgorithm::Sha1;
+        loop {
+            let (data, len) = io::read_pktline(reader)?;
+            if len == 0 && data.is_empty() {
+                return Err(PushError::InvalidAdvertisement("unexpected end of advertisement".to_owned()));
+            } else if len < 4 {
+                if preamble {
+                    preamble = false;
+                    continue;
+                }
+                break;
+            }
+            let line = String::from_utf8(data).map_err(|_| PushError::InvalidAdvertisement("invalid utf-8".to_owned()))?;
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            if let Some(message) = line.strip_prefix("ERR ") {
+                return Err(PushError::RemoteError(message.to_owned()));
+            } else if line.starts_with("# service=") {
+                // followed by a flush-pkt before the refs
+                preamble = true;
+                continue;
+            } else if line == "version 1" || line.starts_with("shallow ") {
+                continue;
+            }
+
+            let (line, capabilities) = match line.split_once('\0') {
+                Some((line, capabilities)) => (line, Some(capabilities)),
+                None => (line, None),
+            };
+            if first {
+                result.capabilities = capabilities.unwrap_or_default().split(' ').filter(|c| !c.is_empty()).map(|c| c.to_owned()).collect();
+                algorithm = client::object_format(&result.capabilities)?;
+                first = false;
+            }
+            let (id, name) = line.split_once(' ').and_then(|(id, name)| Some((unhex_with(id, algorithm)?, name)))
+                .ok_or_else(|| PushError::InvalidAdvertisement(line.to_owned()))?;
+            match name {
+                "capabilities^{}" => {}
+                ".have" => result.haves.push(id),
+                _ => result.refs.push((name.to_owned(), id)),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Whether capability `name` is advertised, with or without a value
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.iter().any(|c| c == name || c.strip_prefix(name).is_some_and(|v| v.starts_with('=')))
+    }
+
+    /// Id of remote ref `name`
+    pub fn id(&self, name: &str) -> Option<&ObjectId> {
+        self.refs.iter().find(|(n, _)| n == name).map(|(_, id)| id)
+    }
+}
+
+/// Push `updates` over a connection to `git-receive-pack`, e.g. pipes of a subprocess
+///
+/// Objects are taken from `store`, and the pack is streamed to `writer`.
+/// Rejected refs are reported in [PushedRef::result]; an `Err` means the push failed as a whole.
+pub fn push<R, W, S>(reader: &mut R, writer: &mut W, store: &S, updates: &[PushUpdate], options: &PushOptions) -> Result<Vec<PushedRef>, PushError>
+    where R: Read, W: Write, S: ObjectStore + ?Sized {
+    let advertisement = Advertisement::read(reader)?;
+    let mut results = plan(store, &advertisement, updates, options)?;
+    if !has_commands(&results) {
+        // a flush-pkt alone ends the session
+        io::write_packet(writer, 0)?;
+        writer.flush()?;
+        return Ok(results);
+    }
+    write_request(writer, store, &advertisement, &results, options, client::AGENT)?;
+    writer.flush()?;
+    read_report(reader, advertisement.has_capability("side-band-64k"), &mut results)?;
+    Ok(results)
+}
+
+/// Check `updates` against the advertised refs
+///
+/// Locally rejected and up-to-date refs are not sent; with [PushOptions::atomic],
+/// nothing is sent once any ref is rejected.
+pub(crate) fn plan<S: ObjectStore + ?Sized>(store: &S, advertisement: &Advertisement, updates: &[PushUpdate], options: &PushOptions) -> Result<Vec<PushedRef>, PushError> {
+    if !advertisement.has_capability("report-status") {
+        return Err(PushError::Unsupported("report-status"));
+    } else if options.atomic && !advertisement.has_capability("atomic") {
+        return Err(PushError::Unsupported("atomic"));
+    } else if !options.push_options.is_empty() && !advertisement.has_capability("push-options") {
+        return Err(PushError::Unsupported("push-options"));
+    }
+
+    let algorithm = client::object_format(&advertisement.capabilities)?;
+    let mut results = Vec::with_capacity(updates.len());
+    for update in updates {
+        let old = advertisement.id(&update.name).copied();
+        if let Some(new) = &update.new {
+            if new.algorithm() != algorithm {
+                return Err(PushError::InvalidId(hex(new)));
+            } else if store.object(new).is_none() {
+                return Err(PushError::MissingObject(hex(new)));
+            }
+        }
+        let result = match (old.as_ref(), update.new.as_ref()) {
+            (None, None) => Ok(UpdateKind::UpToDate),
+            (Some(_), None) if !advertisement.has_capability("delete-refs") => return Err(PushError::Unsupported("delete-refs")),
+            (Some(_), None) => Ok(UpdateKind::Deleted),
+            (None, Some(_)) => Ok(UpdateKind::New),
+            (Some(old), Some(new)) if old == new => Ok(UpdateKind::UpToDate),
+            (Some(old), Some(new)) => classify(store, &update.name, old, new, update.force),
+        };
+        results.push(PushedRef { name: update.name.clone(), old, new: update.new, result });
+    }
+
+    if options.atomic && results.iter().any(|r| r.result.is_err()) {
+        for r in results.iter_mut() {
+            if matches!(r.result, Ok(kind) if kind != UpdateKind::UpToDate) {
+                r.result = Err(PushRejection::AtomicFailed);
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Check a non-deleting update of an existing ref, in the order of `git push`
+fn classify<S: ObjectStore + ?Sized>(store: &S, name: &str, old: &ObjectId, new: &ObjectId, force: bool) -> Result<UpdateKind, PushRejection> {
+    let rejection = if name.starts_with("refs/tags/") {
+        PushRejection::AlreadyExists
+    } else if store.object(old).is_some() {
+        if is_ancestor(store, old, new) {
+            return Ok(UpdateKind::FastForward);
+        }
+        PushRejection::NonFastForward
+    } else {
+        PushRejection::FetchFirst
+    };
+    if force {
+        Ok(UpdateKind::Forced)
+    } else {
+        Err(rejection)
+    }
+}
+
+fn is_ancestor<S: ObjectStore + ?Sized>(store: &S, ancestor: &ObjectId, descendant: &ObjectId) -> bool {
+    let mut walk = RevWalk::new(store);
+    if walk.push(descendant).is_err() {
+        return false;
+    }
+    walk.map_while(Result::ok).any(|(id, _)| id == *ancestor)
+}
+
+fn is_command(r: &PushedRef) -> bool {
+    matches!(r.result, Ok(kind) if kind != UpdateKind::UpToDate)
+}
+
+pub(crate) fn has_commands(results: &[PushedRef]) -> bool {
+    results.iter().any(is_command)
+}
+
+/// Write update commands, push options and the pack
+///
+/// ```text
+/// update-requests = *shallow ( command-list | push-cert )
+/// command-list = PKT-LINE(command NUL capability-list)
+///                *PKT-LINE(command)
+///                flush-pkt
+/// command = create / delete / update
+/// push-options = *PKT-LINE(push-option) flush-pkt
+/// ```
+pub(crate) fn write_request<W, S>(writer: &mut W, store: &S, advertisement: &Advertisement, results: &[PushedRef], options: &PushOptions, agent: &str) -> Result<(), PushError>
+    where W: Write, S: ObjectStore + ?Sized {
+    if let Some(pack) = write_commands(writer, store, advertisement, results, options, agent)? {
+        pack.write(writer)?;
+    }
+    Ok(())
+}
+
+/// Write update commands and push options, and collect objects of the pack to send after them
+///
+/// A pack is only sent when some ref is not deleted.
+pub(crate) fn write_commands<W, S>(writer: &mut W, store: &S, advertisement: &Advertisement, results: &[PushedRef], options: &PushOptions, agent: &str) -> Result<Option<PackWriter>, PushError>
+    where W: Write, S: ObjectStore + ?Sized {
+    let algorithm = client::object_format(&advertisement.capabilities)?;
+    let zero_id = algorithm.null();
+    let object_format = format!("object-format={}", algorithm.name());
+    let mut capabilities = vec!["report-status"];
+    if advertisement.has_capability("side-band-64k") {
+        capabilities.push("side-band-64k");
+    }
+    if options.atomic {
+        capabilities.push("atomic");
+    }
+    if !options.push_options.is_empty() {
+        capabilities.push("push-options");
+    }
+    if advertisement.has_capability("object-format") {
+        capabilities.push(&object_format);
+    }
+    let agent = format!("agent={}", agent);
+    capabilities.push(&agent);
+
+    let mut first = true;
+    for r in results.iter().filter(|r| is_command(r)) {
+        let command = format!("{} {} {}", hex(r.old.as_ref().unwrap_or(&zero_id)), hex(r.new.as_ref().unwrap_or(&zero_id)), r.name);
+        if first {
+            io::write_pktline(writer, &format!("{}\0{}", command, capabilities.join(" ")))?;
+            first = false;
+        } else {
+            io::write_pktline(writer, &command)?;
+        }
+    }
+    io::write_packet(writer, 0)?;
+
+    if !options.push_options.is_empty() {
+        for option in options.push_options.iter() {
+            io::write_pktline(writer, option)?;
+        }
+        io::write_packet(writer, 0)?;
+    }
+
+    let wants: Vec<ObjectId> = results.iter()
+        .filter(|r| is_command(r))
+        .filter_map(|r| r.new)
+        .collect();
+    if wants.is_empty() {
+        return Ok(None);
+    }
+    let haves: Vec<ObjectId> = advertisement.refs.iter().map(|(_, id)| *id)
+        .chain(advertisement.haves.iter().copied())
+        .collect();
+    let pack = pack_objects(store, &wants, &haves, &options.shallow, algorithm, advertisement.has_capability("ofs-delta"))?;
+    Ok(Some(pack))
+}
+
+/// Collect objects reachable from `wants` but not from `haves`
+///
+/// History of `haves` is only walked as far as commits of `wants` reach, and not
+/// beyond `shallow` commits, so haves may be missing locally or have missing ancestors.
+fn pack_objects<S: ObjectStore + ?Sized>(store: &S, wants: &[ObjectId], haves: &[ObjectId], shallow: &[ObjectId], algorithm: HashAlgorithm, ofs_delta: bool) -> Result<PackWriter, PushError> {
+    let mut writer = PackWriter::new().algorithm(algorithm).window(if ofs_delta { 10 } else { 0 });
+    let known: HashSet<ObjectId> = haves.iter().copied().collect();
+
+    let mut walk = RevWalk::new(store);
+    walk.set_shallow(shallow.iter().copied());
+    let mut edges = HashSet::new();
+    for have in haves {
+        if let Some(id) = peel(store, have)? {
+            if let Some(object) = store.object(&id).filter(|o| o.object_type == ObjectType::Commit) {
+                walk.hide(&id)?;
+                edges.insert(object.commit()?.tree);
+            }
+        }
+    }
+
+    let mut trees = Vec::new();
+    let mut blobs = Vec::new();
+    for want in wants {
+        let mut id = *want;
+        loop {
+            if known.contains(&id) {
+                break;
+            }
+            let object = store.object(&id).ok_or_else(|| PushError::MissingObject(hex(&id)))?;
+            match object.object_type {
+                ObjectType::Tag => {
+                    writer.add(ObjectType::Tag, object.data.clone());
+                    id = object.tag()?.object;
+                }
+                ObjectType::Commit => break walk.push(&id)?,
+                ObjectType::Tree => break trees.push((id, String::new())),
+                _ => break blobs.push(id),
+            }
+        }
+    }
+
+    let mut sent = HashSet::new();
+    let mut parents = Vec::new();
+    for commit in walk {
+        let (id, commit) = commit?;
+        writer.add(ObjectType::Commit, store.object(&id).unwrap().data.clone());
+        sent.insert(id);
+        trees.push((commit.tree, String::new()));
+        // parents of shallow commits are missing, the remote may not have them either
+        if !shallow.contains(&id) {
+            parents.extend(commit.parents);
+        }
+    }
+    for parent in parents.into_iter().filter(|p| !sent.contains(p)) {
+        if let Some(object) = store.object(&parent) {
+            edges.insert(object.commit()?.tree);
+        }
+    }
+
+    // objects reachable from commits the remote has are not sent
+    let mut seen = HashSet::new();
+    let mut pending: Vec<ObjectId> = edges.into_iter().collect();
+    while let Some(id) = pending.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        if let Some(object) = store.object(&id).filter(|o| o.object_type == ObjectType::Tree) {
+            pending.extend(object.tree()?.entries.into_iter().filter(|e| !e.is_gitlink()).map(|e| e.id));
+        }
+    }
+
+    // trees are reversed to be walked in order
+    trees.reverse();
+    while let Some((id, path)) = trees.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let object = store.object(&id).ok_or_else(|| PushError::MissingObject(hex(&id)))?;
+        writer.add_with_path(ObjectType::Tree, object.data.clone(), &path);
+        for entry in object.tree()?.entries.into_iter().rev() {
+            let name = String::from_utf8_lossy(&entry.name);
+            let path = if path.is_empty() { name.into_owned() } else { format!("{}/{}", path, name) };
+            if entry.is_tree() {
+                trees.push((entry.id, path));
+            } else if entry.is_blob() && seen.insert(entry.id) {
+                let blob = store.object(&entry.id).ok_or_else(|| PushError::MissingObject(hex(&entry.id)))?;
+                writer.add_with_path(ObjectType::Blob, blob.data.clone(), &path);
+            }
+        }
+    }
+    for id in blobs {
+        let object = store.object(&id).ok_or_else(|| PushError::MissingObject(hex(&id)))?;
+        writer.add(ObjectType::Blob, object.data.clone());
+    }
+    Ok(writer)
+}
+
+/// Peel tags of `id`, or `None` if some object is missing
+fn peel<S: ObjectStore + ?Sized>(store: &S, id: &ObjectId) -> Result<Option<ObjectId>, PushError> {
+    let mut id = *id;
+    loop {
+        match store.object(&id) {
+            Some(object) if object.object_type == ObjectType::Tag => id = object.tag()?.object,
+            Some(_) => return Ok(Some(id)),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Read the status report into `results`
+///
+/// ```text
+/// report-status = unpack-status
+///                 1*(command-status)
+///                 flush-pkt
+/// unpack-status = PKT-LINE("unpack" SP unpack-result)
+/// command-status = PKT-LINE("ok" SP refname) / PKT-LINE("ng" SP refname SP error-msg)
+/// ```
+///
+/// With `side-band-64k`, the report is sent in band 1 as pkt-lines itself.
+pub(crate) fn read_report<R: Read>(reader: &mut R, sideband: bool, results: &mut [PushedRef]) -> Result<(), PushError> {
+    if sideband {
+        let mut report = Vec::new();
+        loop {
+            let (data, len) = io::read_pktline(reader)?;
+            if len < 5 {
+                break;
+            }
+            match data[0] {
+                1 => report.extend_from_slice(&data[1..]),
+                // progress
+                2 => {}
+                _ => return Err(PushError::RemoteError(String::from_utf8_lossy(&data[1..]).trim_end().to_owned())),
+            }
+        }
+        return read_report(&mut Cursor::new(report), false, results);
+    }
+
+    let mut reported = HashSet::new();
+    let mut unpacked = false;
+    loop {
+        let (data, len) = io::read_pktline(reader)?;
+        if len < 4 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&data);
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        if let Some(status) = line.strip_prefix("unpack ") {
+            if status != "ok" {
+                return Err(PushError::UnpackFailed(status.to_owned()));
+            }
+            unpacked = true;
+        } else if let Some(name) = line.strip_prefix("ok ") {
+            reported.insert(name.to_owned());
+        } else if let Some(rest) = line.strip_prefix("ng ") {
+            let (name, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+            if let Some(r) = results.iter_mut().find(|r| r.name == name) {
+                r.result = Err(PushRejection::parse(reason));
+            }
+            reported.insert(name.to_owned());
+        } else if let Some(message) = line.strip_prefix("ERR ") {
+            return Err(PushError::RemoteError(message.to_owned()));
+        } else {
+            return Err(PushError::InvalidReport(line.to_owned()));
+        }
+    }
+    if !unpacked {
+        return Err(PushError::InvalidReport("missing unpack status".to_owned()));
+    }
+    for r in results.iter_mut().filter(|r| is_command(r)) {
+        if !reported.contains(&r.name) {
+            r.result = Err(PushRejection::Other("not reported".to_owned()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+    use crate::push::{push, PushError, PushOptions, PushRejection, PushUpdate, PushedRef};
+    use crate::refspec::UpdateKind;
+    use crate::testing::{commit, fixture, git, pack_objects, serve};
+    use crate::{Client, Pack};
+
+    /// Push to bare repository `repo` through a `git receive-pack` subprocess
+    fn receive_pack(repo: &Path, store: &Pack, updates: &[PushUpdate], options: &PushOptions) -> Result<Vec<PushedRef>, PushError> {
+        let mut child = Command::new("git")
+            .arg("receive-pack")
+            .arg(repo)
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to run git receive-pack");
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let result = push(&mut stdout, &mut stdin, store, updates, options);
+        drop(stdin);
+        child.wait().unwrap();
+        result
+    }
+
+    fn update(name: &str, new: Option<&str>, force: bool) -> PushUpdate {
+        PushUpdate { name: name.to_owned(), new: new.map(|n| n.parse().unwrap()), force }
+    }
+
+    fn store(work: &Path, revs: &str) -> Pack {
+        Pack::from_reader(&mut Cursor::new(pack_objects(work, revs))).unwrap()
+    }
+
+    #[test]
+    fn test_push() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let work = dir.path().join("work");
+        let repo = dir.path().join("repo.git");
+        let rev = |name: &str| git(&repo, &["for-each-ref", "--format=%(objectname)", name]).trim().to_owned();
+
+        let third = commit(&work, &[("album/c.toml", "title = \"c\"\n")], "Add c");
+        git(&work, &["tag", "-a", "v2", "-m", "v2"]);
+        let v2 = git(&work, &["rev-parse", "v2"]).trim().to_owned();
+        let pack = store(&work, "master\nv2");
+        let results = receive_pack(&repo, &pack, &[
+            update("refs/heads/master", Some(&third), false),
+            update("refs/heads/dev", Some(&third), false),
+            update("refs/tags/v2", Some(&v2), false),
+            update("refs/heads/none", None, false),
+        ], &PushOptions::default()).unwrap();
+        let kinds: Vec<_> = results.iter().map(|r| r.result.clone()).collect();
+        assert_eq!(kinds, vec![Ok(UpdateKind::FastForward), Ok(UpdateKind::New), Ok(UpdateKind::New), Ok(UpdateKind::UpToDate)]);
+        assert_eq!(results[0].old, git(&work, &["rev-parse", "HEAD~"]).trim().parse().ok());
+        assert_eq!(rev("refs/heads/master"), third);
+        assert_eq!(rev("refs/tags/v2"), v2);
+        git(&repo, &["fsck", "--no-dangling"]);
+
+        let results = receive_pack(&repo, &pack, &[update("refs/heads/dev", None, false)], &PushOptions::default()).unwrap();
+        assert_eq!(results[0].result, Ok(UpdateKind::Deleted));
+        assert_eq!(rev("refs/heads/dev"), "");
+
+        // diverged history
+        git(&work, &["reset", "-q", "--hard", "HEAD~"]);
+        let fourth = commit(&work, &[("album/d.toml", "title = \"d\"\n")], "Add d");
+        let pack = store(&work, &format!("{}\n{}", fourth, third));
+        let results = receive_pack(&repo, &pack, &[
+            update("refs/heads/master", Some(&fourth), false),
+            update("refs/heads/other", Some(&fourth), false),
+        ], &PushOptions { atomic: true, ..Default::default() }).unwrap();
+        assert_eq!(results[0].result, Err(PushRejection::NonFastForward));
+        assert_eq!(results[1].result, Err(PushRejection::AtomicFailed));
+        assert_eq!(rev("refs/heads/other"), "");
+        let results = receive_pack(&repo, &pack, &[
+            update("refs/heads/master", Some(&fourth), true),
+            update("refs/tags/v1", Some(&fourth), false),
+        ], &PushOptions::default()).unwrap();
+        assert_eq!(results[0].result, Ok(UpdateKind::Forced));
+        assert_eq!(results[1].result, Err(PushRejection::AlreadyExists));
+        assert_eq!(rev("refs/heads/master"), fourth);
+
+        // remote has a commit unknown locally
+        let results = receive_pack(&repo, &store(&work, &third), &[update("refs/heads/master", Some(&third), false)], &PushOptions::default()).unwrap();
+        assert_eq!(results[0].result, Err(PushRejection::FetchFirst));
+        git(&repo, &["fsck", "--no-dangling"]);
+    }
+
+    #[test]
+    fn test_push_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let work = dir.path().join("work");
+        let repo = dir.path().join("repo.git");
+        git(&repo, &["config", "receive.advertisePushOptions", "true"]);
+        let hook = repo.join("hooks/pre-receive");
+        std::fs::create_dir_all(hook.parent().unwrap()).unwrap();
+        std::fs::write(&hook, "#!/bin/sh\necho \"$GIT_PUSH_OPTION_COUNT $GIT_PUSH_OPTION_0\" > options.txt\nexit 1\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let third = commit(&work, &[("album/c.toml", "title = \"c\"\n")], "Add c");
+        let pack = store(&work, "master");
+        let options = PushOptions { push_options: vec!["ci.skip".to_owned()], ..Default::default() };
+        let results = receive_pack(&repo, &pack, &[update("refs/heads/master", Some(&third), false)], &options).unwrap();
+        assert_eq!(results[0].result, Err(PushRejection::HookDeclined));
+        assert_eq!(std::fs::read_to_string(repo.join("options.txt")).unwrap(), "1 ci.skip\n");
+        assert_ne!(git(&repo, &["rev-parse", "master"]).trim(), third);
+
+        git(&repo, &["config", "receive.advertisePushOptions", "false"]);
+        let err = receive_pack(&repo, &pack, &[update("refs/heads/master", Some(&third), false)], &options).unwrap_err();
+        assert!(matches!(err, PushError::Unsupported("push-options")));
+    }
+
+    #[test]
+    fn test_push_shallow() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let work = dir.path().join("work");
+        let repo = dir.path().join("repo.git");
+        let second = git(&work, &["rev-parse", "master"]).trim().to_owned();
+
+        // local history stops at `second`, and the remote tag v1 is not available locally
+        let third = commit(&work, &[("album/c.toml", "title = \"c\"\n")], "Add c");
+        let pack = store(&work, "master\n^master~2");
+        let options = PushOptions { shallow: vec![second.parse().unwrap()], ..Default::default() };
+        let results = receive_pack(&repo, &pack, &[update("refs/heads/master", Some(&third), false)], &options).unwrap();
+        assert_eq!(results[0].result, Ok(UpdateKind::FastForward));
+        assert_eq!(git(&repo, &["rev-parse", "master"]).trim(), third);
+        git(&repo, &["fsck", "--no-dangling"]);
+    }
+
+    #[test]
+    fn test_push_http() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let work = dir.path().join("work");
+        let repo = dir.path().join("repo.git");
+        git(&repo, &["config", "http.receivepack", "true"]);
+        let client = Client::new(&format!("{}/repo.git", serve(dir.path())));
+
+        let third = commit(&work, &[("album/c.toml", "title = \"c\"\n")], "Add c");
+        let pack = store(&work, "master");
+        let results = client.push(&pack, &[update("refs/heads/master", Some(&third), false)], &PushOptions::default()).unwrap();
+        assert_eq!(results[0].result, Ok(UpdateKind::FastForward));
+        assert_eq!(git(&repo, &["rev-parse", "master"]).trim(), third);
+        let results = client.push(&pack, &[update("refs/heads/master", Some(&third), false)], &PushOptions::default()).unwrap();
+        assert_eq!(results[0].result, Ok(UpdateKind::UpToDate));
+        git(&repo, &["fsck", "--no-dangling"]);
+    }
+}
@@ -0,0 +1,495 @@
+//! https://git-scm.com/docs/git-fetch#_configured_remote_tracking_branches
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+use crate::client::Ref;
+use crate::oid::ObjectId;
+use crate::utils::unhex;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RefspecError {
+    #[error("empty refspec")]
+    Empty,
+    #[error("invalid refspec {0}")]
+    InvalidRefspec(String),
+    #[error("pattern mismatch in refspec {0}")]
+    PatternMismatch(String),
+    #[error("negative refspec {0} must not have a destination")]
+    NegativeWithDestination(String),
+    #[error("multiple updates for ref {0}")]
+    Conflict(String),
+}
+
+/// Rules used by git to expand a short ref name, see `ref_rev_parse_rules` in git source.
+const REV_PARSE_RULES: [(&str, &str); 6] = [
+    ("", ""),
+    ("refs/", ""),
+    ("refs/tags/", ""),
+    ("refs/heads/", ""),
+    ("refs/remotes/", ""),
+    ("refs/remotes/", "/HEAD"),
+];
+
+/// A refspec maps remote refs to local refs.
+///
+/// ```text
+/// [+]<src>[:<dst>]
+/// ^<src>
+/// ```
+///
+/// `src` and `dst` may contain a single `*` each, in which case the refspec is a pattern.
+/// A leading `+` allows non fast-forward updates, and a leading `^` excludes refs
+/// matched by `src` from other refspecs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refspec {
+    pub force: bool,
+    pub negative: bool,
+    pub src: String,
+    pub dst: Option<String>,
+}
+
+impl Refspec {
+    /// Whether this refspec contains `*`
+    pub fn is_pattern(&self) -> bool {
+        self.src.contains('*')
+    }
+
+    /// Whether `src` is a full object id instead of a ref name
+    pub fn is_exact_id(&self) -> bool {
+        is_hex_id(&self.src)
+    }
+
+    /// Match a remote ref name against `src`.
+    ///
+    /// Returns the part matched by `*` for pattern refspecs, or an empty string for exact matches.
+    pub fn matches<'a>(&self, name: &'a str) -> Option<&'a str> {
+        if let Some(star) = self.src.find('*') {
+            let (prefix, suffix) = (&self.src[..star], &self.src[star + 1..]);
+            if name.len() >= prefix.len() + suffix.len() && name.starts_with(prefix) && name.ends_with(suffix) {
+                Some(&name[prefix.len()..name.len() - suffix.len()])
+            } else {
+                None
+            }
+        } else if self.rule(name).is_some() {
+            Some("")
+        } else {
+            None
+        }
+    }
+
+    /// Index of the first rev-parse rule expanding a short `src` to `name`
+    fn rule(&self, name: &str) -> Option<usize> {
+        REV_PARSE_RULES.iter().position(|(p, s)| name.len() == p.len() + self.src.len() + s.len()
+            && name.starts_with(p) && name.ends_with(s) && name[p.len()..name.len() - s.len()] == self.src)
+    }
+
+    /// Map a remote ref name to its local destination.
+    ///
+    /// Returns `None` if the name is not matched, `Some(None)` if it is matched
+    /// but the refspec has no destination.
+    pub fn map(&self, name: &str) -> Option<Option<String>> {
+        let matched = self.matches(name)?;
+        Some(self.dst.as_ref().map(|dst| dst.replacen('*', matched, 1)))
+    }
+
+    /// Whether local ref `name` is a possible destination of this refspec
+    pub fn matches_local(&self, name: &str) -> bool {
+        match &self.dst {
+            Some(dst) => match dst.find('*') {
+                Some(star) => {
+                    let (prefix, suffix) = (&dst[..star], &dst[star + 1..]);
+                    name.len() >= prefix.len() + suffix.len() && name.starts_with(prefix) && name.ends_with(suffix)
+                }
+                None => dst == name,
+            },
+            None => false,
+        }
+    }
+
+    /// `ref-prefix` arguments to send with `ls-refs` so that the server
+    /// only advertises refs this refspec might match.
+    pub fn ref_prefixes(&self) -> Vec<String> {
+        if self.negative || self.is_exact_id() {
+            Vec::new()
+        } else if let Some(star) = self.src.find('*') {
+            vec![self.src[..star].to_owned()]
+        } else if self.src == "HEAD" || self.src.starts_with("refs/") {
+            vec![self.src.clone()]
+        } else {
+            REV_PARSE_RULES.iter().map(|(p, s)| format!("{}{}{}", p, self.src, s)).collect()
+        }
+    }
+}
+
+impl FromStr for Refspec {
+    type Err = RefspecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(RefspecError::Empty);
+        }
+
+        let (force, negative, rest) = if let Some(rest) = s.strip_prefix('+') {
+            (true, false, rest)
+        } else if let Some(rest) = s.strip_prefix('^') {
+            (false, true, rest)
+        } else {
+            (false, false, s)
+        };
+        let (src, dst) = match rest.rfind(':') {
+            Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+            None => (rest, None),
+        };
+        // `:dst` fetches HEAD into dst, and an empty dst is the same as no dst
+        let src = if src.is_empty() { "HEAD" } else { src };
+        let dst = dst.filter(|d| !d.is_empty());
+
+        if negative && dst.is_some() {
+            return Err(RefspecError::NegativeWithDestination(s.to_owned()));
+        }
+        if !is_valid_ref_part(src) || !dst.is_none_or(is_valid_ref_part) {
+            return Err(RefspecError::InvalidRefspec(s.to_owned()));
+        }
+        let src_stars = src.matches('*').count();
+        if let Some(dst) = dst {
+            if src_stars != dst.matches('*').count() {
+                return Err(RefspecError::PatternMismatch(s.to_owned()));
+            }
+        }
+        if negative && is_hex_id(src) {
+            return Err(RefspecError::InvalidRefspec(s.to_owned()));
+        }
+
+        Ok(Self {
+            force,
+            negative,
+            src: src.to_owned(),
+            dst: dst.map(|d| d.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for Refspec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.force {
+            f.write_str("+")?;
+        } else if self.negative {
+            f.write_str("^")?;
+        }
+        f.write_str(&self.src)?;
+        if let Some(dst) = &self.dst {
+            write!(f, ":{}", dst)?;
+        }
+        Ok(())
+    }
+}
+
+/// A remote ref selected by a list of refspecs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefMapping {
+    /// Remote ref name, or the object id for exact id refspecs
+    pub remote: String,
+    /// Object id the remote ref points to
+    pub id: ObjectId,
+    /// Local ref to update, `None` if the ref is only fetched
+    pub local: Option<String>,
+    pub force: bool,
+}
+
+/// Kind of a local ref update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateKind {
+    /// Local ref already points to the new id
+    UpToDate,
+    /// Local ref does not exist yet
+    New,
+    /// New id is a descendant of the old id
+    FastForward,
+    /// Non fast-forward update allowed by `+`
+    Forced,
+    /// Non fast-forward update without `+`
+    Rejected,
+    /// Local ref was removed on the remote side
+    Deleted,
+}
+
+impl RefMapping {
+    /// Decide how the local ref should be updated.
+    ///
+    /// `fast_forward` tells whether `old` is an ancestor of [RefMapping::id].
+    /// Existing tags are never fast-forwarded, as in git they can only be updated with force.
+    pub fn classify(&self, old: Option<&ObjectId>, fast_forward: bool) -> UpdateKind {
+        match old {
+            None => UpdateKind::New,
+            Some(old) if *old == self.id => UpdateKind::UpToDate,
+            Some(_) => {
+                let is_tag = self.local.as_deref().is_some_and(|l| l.starts_with("refs/tags/"));
+                if fast_forward && !is_tag {
+                    UpdateKind::FastForward
+                } else if self.force {
+                    UpdateKind::Forced
+                } else {
+                    UpdateKind::Rejected
+                }
+            }
+        }
+    }
+}
+
+/// `ref-prefix` arguments for a list of refspecs, deduplicated and in order.
+pub fn ref_prefixes(specs: &[Refspec]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    specs.iter()
+        .flat_map(|s| s.ref_prefixes())
+        .filter(|p| seen.insert(p.clone()))
+        .collect()
+}
+
+/// Map refs advertised by `ls-refs` with a list of refspecs.
+///
+/// Refs matched by any negative refspec are skipped.
+/// Refspecs with a full object id as `src` are mapped even if no ref advertises that id.
+pub fn map_refs(specs: &[Refspec], refs: &[Ref]) -> Result<Vec<RefMapping>, RefspecError> {
+    let (negative, positive): (Vec<_>, Vec<_>) = specs.iter().partition(|s| s.negative);
+    let mut result: Vec<RefMapping> = Vec::new();
+
+    for spec in positive {
+        if spec.is_exact_id() {
+            result.push(RefMapping {
+                remote: spec.src.clone(),
+                id: spec.src.parse().map_err(|_| RefspecError::InvalidRefspec(spec.src.clone()))?,
+                local: spec.dst.clone(),
+                force: spec.force,
+            });
+            continue;
+        }
+
+        let refs = refs.iter().filter(|r| !negative.iter().any(|n| n.matches(&r.name).is_some()));
+        if spec.is_pattern() {
+            for r in refs {
+                if let Some(local) = spec.map(&r.name) {
+                    result.push(RefMapping {
+                        remote: r.name.clone(),
+                        id: r.id,
+                        local,
+                        force: spec.force,
+                    });
+                }
+            }
+        } else if let Some(r) = refs
+            .filter_map(|r| spec.rule(&r.name).map(|i| (i, r)))
+            .min_by_key(|(i, _)| *i)
+            .map(|(_, r)| r) {
+            // an exact refspec selects only one ref, by rev-parse rule priority
+            result.push(RefMapping {
+                remote: r.name.clone(),
+                id: r.id,
+                local: spec.map(&r.name).unwrap(),
+                force: spec.force,
+            });
+        }
+    }
+
+    // the same local ref must not be updated from different remote objects
+    let mut seen: Vec<&RefMapping> = Vec::new();
+    for m in result.iter() {
+        if let Some(local) = &m.local {
+            if seen.iter().any(|s| s.local.as_ref() == Some(local) && s.id != m.id) {
+                return Err(RefspecError::Conflict(local.clone()));
+            }
+            seen.push(m);
+        }
+    }
+    let mut seen = HashSet::new();
+    result.retain(|m| seen.insert((m.remote.clone(), m.local.clone())));
+    Ok(result)
+}
+
+/// Object ids to send as `want`, deduplicated and in order.
+pub fn wants(mappings: &[RefMapping]) -> Vec<ObjectId> {
+    let mut seen = HashSet::new();
+    mappings.iter()
+        .map(|m| m.id)
+        .filter(|id| seen.insert(*id))
+        .collect()
+}
+
+/// Whether `s` is a full SHA-1 or SHA-256 object id
+fn is_hex_id(s: &str) -> bool {
+    unhex(s).is_some()
+}
+
+/// Loose check of ref name rules, see `git check-ref-format`
+pub(crate) fn is_valid_ref_part(s: &str) -> bool {
+    !s.is_empty()
+        && !s.contains("..")
+        && !s.contains("@{")
+        && !s.contains("//")
+        && !s.starts_with('/')
+        && !s.ends_with('/')
+        && !s.ends_with('.')
+        && !s.ends_with(".lock")
+        && s.matches('*').count() <= 1
+        && !s.bytes().any(|b| b < 0x20 || b == 0x7f || b" ~^:?[\\".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::refspec::{Refspec, RefspecError, RefMapping, UpdateKind, map_refs, ref_prefixes, wants};
+    use crate::client::Ref;
+
+    fn r(name: &str, id: &str) -> Ref {
+        Ref {
+            name: name.to_owned(),
+            id: id.repeat(40).parse().unwrap(),
+            symref_target: None,
+            peeled: None,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let spec: Refspec = "+refs/heads/*:refs/remotes/origin/*".parse().unwrap();
+        assert_eq!(spec, Refspec {
+            force: true,
+            negative: false,
+            src: "refs/heads/*".to_owned(),
+            dst: Some("refs/remotes/origin/*".to_owned()),
+        });
+        assert!(spec.is_pattern());
+        assert_eq!(spec.to_string(), "+refs/heads/*:refs/remotes/origin/*");
+
+        let spec: Refspec = "^refs/heads/wip/*".parse().unwrap();
+        assert!(spec.negative);
+        assert_eq!(spec.dst, None);
+
+        let spec: Refspec = ":refs/heads/x".parse().unwrap();
+        assert_eq!(spec.src, "HEAD");
+
+        assert_eq!("".parse::<Refspec>(), Err(RefspecError::Empty));
+        assert!(matches!("refs/heads/*:refs/x".parse::<Refspec>(), Err(RefspecError::PatternMismatch(_))));
+        assert!(matches!("^refs/heads/a:refs/heads/b".parse::<Refspec>(), Err(RefspecError::NegativeWithDestination(_))));
+        assert!(matches!("refs/heads/a..b".parse::<Refspec>(), Err(RefspecError::InvalidRefspec(_))));
+        assert!(matches!("refs/*/*".parse::<Refspec>(), Err(RefspecError::InvalidRefspec(_))));
+    }
+
+    #[test]
+    fn test_match() {
+        let spec: Refspec = "refs/heads/*:refs/remotes/origin/*".parse().unwrap();
+        assert_eq!(spec.matches("refs/heads/feature/a"), Some("feature/a"));
+        assert_eq!(spec.matches("refs/tags/v1"), None);
+        assert_eq!(spec.map("refs/heads/main"), Some(Some("refs/remotes/origin/main".to_owned())));
+        assert!(spec.matches_local("refs/remotes/origin/main"));
+        assert!(!spec.matches_local("refs/heads/main"));
+
+        let spec: Refspec = "main".parse().unwrap();
+        assert_eq!(spec.matches("refs/heads/main"), Some(""));
+        assert_eq!(spec.matches("refs/heads/xmain"), None);
+        assert_eq!(spec.map("refs/heads/main"), Some(None));
+    }
+
+    #[test]
+    fn test_ref_prefixes() {
+        let specs: Vec<Refspec> = vec![
+            "+refs/heads/*:refs/remotes/origin/*".parse().unwrap(),
+            "^refs/heads/wip/*".parse().unwrap(),
+            "refs/heads/*:refs/x/*".parse().unwrap(),
+            "HEAD".parse().unwrap(),
+            "v1".parse().unwrap(),
+        ];
+        assert_eq!(ref_prefixes(&specs), vec![
+            "refs/heads/",
+            "HEAD",
+            "v1",
+            "refs/v1",
+            "refs/tags/v1",
+            "refs/heads/v1",
+            "refs/remotes/v1",
+            "refs/remotes/v1/HEAD",
+        ]);
+    }
+
+    #[test]
+    fn test_map_refs() {
+        let refs = vec![
+            r("HEAD", "a"),
+            r("refs/heads/main", "a"),
+            r("refs/heads/wip/x", "b"),
+            r("refs/tags/v1", "c"),
+        ];
+        let specs: Vec<Refspec> = vec![
+            "+refs/heads/*:refs/remotes/origin/*".parse().unwrap(),
+            "^refs/heads/wip/*".parse().unwrap(),
+            "v1:refs/tags/v1".parse().unwrap(),
+        ];
+        let mappings = map_refs(&specs, &refs).unwrap();
+        assert_eq!(mappings, vec![
+            RefMapping {
+                remote: "refs/heads/main".to_owned(),
+                id: "a".repeat(40).parse().unwrap(),
+                local: Some("refs/remotes/origin/main".to_owned()),
+                force: true,
+            },
+            RefMapping {
+                remote: "refs/tags/v1".to_owned(),
+                id: "c".repeat(40).parse().unwrap(),
+                local: Some("refs/tags/v1".to_owned()),
+                force: false,
+            },
+        ]);
+        assert_eq!(wants(&mappings), vec![mappings[0].id, mappings[1].id]);
+
+        // short names resolve by rev-parse rule priority, not advertisement order
+        let ambiguous = vec![
+            r("refs/heads/v1", "a"),
+            r("refs/remotes/v1", "b"),
+            r("refs/tags/v1", "c"),
+        ];
+        let specs: Vec<Refspec> = vec!["v1".parse().unwrap()];
+        let mappings = map_refs(&specs, &ambiguous).unwrap();
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].remote, "refs/tags/v1");
+
+        let specs: Vec<Refspec> = vec![
+            "refs/heads/main:refs/x".parse().unwrap(),
+            "refs/tags/v1:refs/x".parse().unwrap(),
+        ];
+        assert_eq!(map_refs(&specs, &refs), Err(RefspecError::Conflict("refs/x".to_owned())));
+    }
+
+    #[test]
+    fn test_sha256_id() {
+        let id = "d".repeat(64);
+        let spec: Refspec = format!("{}:refs/heads/x", id).parse().unwrap();
+        assert!(spec.is_exact_id());
+        let mappings = map_refs(&[spec], &[]).unwrap();
+        assert_eq!(mappings[0].id, id.parse().unwrap());
+        assert_eq!(mappings[0].local.as_deref(), Some("refs/heads/x"));
+
+        assert!(matches!(format!("^{}", id).parse::<Refspec>(), Err(RefspecError::InvalidRefspec(_))));
+        assert!(matches!(format!("^{}", "d".repeat(40)).parse::<Refspec>(), Err(RefspecError::InvalidRefspec(_))));
+        // hex digits of another length are a ref name
+        assert!(!"d".repeat(50).parse::<Refspec>().unwrap().is_exact_id());
+    }
+
+    #[test]
+    fn test_classify() {
+        let mut m = RefMapping {
+            remote: "refs/heads/main".to_owned(),
+            id: "a".repeat(40).parse().unwrap(),
+            local: Some("refs/remotes/origin/main".to_owned()),
+            force: false,
+        };
+        let old = "b".repeat(40).parse().unwrap();
+        assert_eq!(m.classify(None, false), UpdateKind::New);
+        assert_eq!(m.classify(Some(&m.id), false), UpdateKind::UpToDate);
+        assert_eq!(m.classify(Some(&old), true), UpdateKind::FastForward);
+        assert_eq!(m.classify(Some(&old), false), UpdateKind::Rejected);
+        m.force = true;
+        assert_eq!(m.classify(Some(&old), false), UpdateKind::Forced);
+        m.local = Some("refs/tags/v1".to_owned());
+        assert_eq!(m.classify(Some(&old), true), UpdateKind::Forced);
+    }
+}
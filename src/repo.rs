@@ -0,0 +1,1427 @@
+//! Bare repository on disk, populated by fetching from a git server.
+//!
+//! https://git-scm.com/docs/gitrepository-layout
+
+use std::fs;
+use std::io::{BufReader, BufWriter, Cursor, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use std::collections::{HashMap, HashSet};
+use crate::{Client, Pack};
+use crate::bundle::{BundleError, BundleHeader, BundleList, BundleMode};
+use crate::cancel::CancellationToken;
+use crate::client::{self, ClientBuilder, ClientError, Message, Ref};
+use crate::connectivity::{Connectivity, ConnectivityError};
+use crate::oid::{HashAlgorithm, ObjectId, ObjectIdError};
+use crate::pack::{self, ObjectStore, ObjectType, UnpackError};
+use crate::index;
+use crate::packfile::{self, MappedPackFile, PackFile, PackFileOptions};
+use crate::progress::{self, Progress, Transfer};
+use crate::refspec::{self, Refspec, RefspecError, RefMapping, UpdateKind};
+use crate::revwalk::RevWalk;
+use crate::utils::{hex, unhex_with};
+
+/// Number of local commits sent as haves in addition to ref tips
+const MAX_HAVES: usize = 256;
+/// Pack data received by a fetch, kept until the pack is stored
+///
+/// It has no `.pack` extension, so that it is not read as a pack of the repository.
+const PARTIAL_PACK: &str = "objects/pack/tmp_fetch_pack";
+/// Shallow commits received with [PARTIAL_PACK]
+const PARTIAL_SHALLOW: &str = "objects/pack/tmp_fetch_shallow";
+/// Lock held while a fetch reads or writes [PARTIAL_PACK], so that concurrent fetches fail
+/// instead of overwriting each other
+const FETCH_LOCK: &str = "objects/pack/tmp_fetch.lock";
+
+#[derive(Debug, Error)]
+pub enum RepoError {
+    #[error("{0} is not a git repository")]
+    NotRepository(PathBuf),
+    #[error("destination path {0} already exists and is not an empty directory")]
+    AlreadyExists(PathBuf),
+    #[error("invalid ref name {0}")]
+    InvalidRefName(String),
+    #[error("remote error: {0}")]
+    RemoteError(String),
+    #[error("remote {0} not found")]
+    RemoteNotFound(String),
+    #[error("object {0} not found")]
+    MissingObject(String),
+    #[error("unsupported object format {0}")]
+    UnsupportedObjectFormat(String),
+    #[error("pack downloaded from {0} does not match hash {1}")]
+    PackHashMismatch(String, String),
+    #[error("{0} exists, another fetch is running or was killed")]
+    Locked(PathBuf),
+
+    #[error(transparent)]
+    ObjectIdError(#[from] ObjectIdError),
+    #[error(transparent)]
+    ConnectivityError(#[from] ConnectivityError),
+    #[error(transparent)]
+    ClientError(#[from] ClientError),
+    #[error(transparent)]
+    BundleError(#[from] BundleError),
+    #[error(transparent)]
+    UnpackError(#[from] UnpackError),
+    #[error(transparent)]
+    RefspecError(#[from] RefspecError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// Options of [clone_bare]
+#[derive(Debug, Clone)]
+pub struct CloneOptions {
+    /// Name of the remote written to `config`
+    pub remote: String,
+    /// Refspecs used to select remote refs and map them to local refs
+    ///
+    /// Default to `+refs/heads/*:refs/heads/*` and `+refs/tags/*:refs/tags/*`
+    pub refspecs: Vec<Refspec>,
+    /// Create a shallow clone with history truncated to the specified number of commits
+    pub depth: Option<u32>,
+    /// Protocols like `https` of `packfile-uris` the server may offload packs to, none by default
+    pub uri_protocols: Vec<String>,
+    /// Download bundles advertised by `bundle-uri` before fetching the remaining objects
+    pub bundle_uri: bool,
+    /// Receiver of server progress, received bytes and unpacking progress
+    pub progress: Option<Arc<dyn Progress>>,
+    /// Token to abort cloning, also checked while unpacking
+    pub cancel: Option<CancellationToken>,
+    /// HTTP settings like proxy and extra headers
+    pub client: ClientBuilder,
+}
+
+impl Default for CloneOptions {
+    fn default() -> Self {
+        Self {
+            remote: "origin".to_owned(),
+            refspecs: vec![
+                "+refs/heads/*:refs/heads/*".parse().unwrap(),
+                "+refs/tags/*:refs/tags/*".parse().unwrap(),
+            ],
+            depth: None,
+            uri_protocols: Vec::new(),
+            bundle_uri: false,
+            progress: None,
+            cancel: None,
+            client: ClientBuilder::new(),
+        }
+    }
+}
+
+/// Options of [Repository::fetch]
+#[derive(Debug, Clone, Default)]
+pub struct FetchOptions {
+    /// Refspecs to use instead of `remote.<name>.fetch` in `config`
+    pub refspecs: Option<Vec<Refspec>>,
+    /// Limit fetching to the specified number of commits from the tip of each remote ref
+    pub depth: Option<u32>,
+    /// Remove local refs which no longer exist on the remote
+    pub prune: bool,
+    /// Protocols like `https` of `packfile-uris` the server may offload packs to, none by default
+    pub uri_protocols: Vec<String>,
+    /// Receiver of server progress, received bytes and unpacking progress
+    pub progress: Option<Arc<dyn Progress>>,
+    /// Token to abort fetching, also checked while unpacking
+    pub cancel: Option<CancellationToken>,
+    /// HTTP settings like proxy and extra headers
+    pub client: ClientBuilder,
+}
+
+/// A local ref handled by [Repository::fetch]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefUpdate {
+    /// Local ref name
+    pub name: String,
+    /// Object id before fetch, `None` for new refs
+    pub old: Option<ObjectId>,
+    /// Object id after fetch, `None` for deleted refs
+    pub new: Option<ObjectId>,
+    pub kind: UpdateKind,
+}
+
+/// A bare git repository
+#[derive(Debug)]
+pub struct Repository {
+    path: PathBuf,
+    algorithm: HashAlgorithm,
+}
+
+impl Repository {
+    /// Create an empty bare repository at `path`
+    ///
+    /// `path` must not exist, or be an empty directory.
+    pub fn init_bare<P: AsRef<Path>>(path: P) -> Result<Self, RepoError> {
+        Self::init_bare_with(path, HashAlgorithm::Sha1)
+    }
+
+    /// Create an empty bare repository at `path` with object ids of `algorithm`
+    ///
+    /// Repositories other than SHA-1 are written with `extensions.objectFormat`, which needs format version 1.
+    pub fn init_bare_with<P: AsRef<Path>>(path: P, algorithm: HashAlgorithm) -> Result<Self, RepoError> {
+        let path = path.as_ref();
+        if path.exists() && (!path.is_dir() || fs::read_dir(path)?.next().is_some()) {
+            return Err(RepoError::AlreadyExists(path.to_owned()));
+        }
+
+        for dir in ["objects/info", "objects/pack", "refs/heads", "refs/tags", "info"].iter() {
+            fs::create_dir_all(path.join(dir))?;
+        }
+        fs::write(path.join("HEAD"), "ref: refs/heads/master\n")?;
+        let config = match algorithm {
+            HashAlgorithm::Sha1 => "[core]\n\trepositoryformatversion = 0\n\tfilemode = true\n\tbare = true\n".to_owned(),
+            _ => format!("[core]\n\trepositoryformatversion = 1\n\tfilemode = true\n\tbare = true\n[extensions]\n\tobjectformat = {}\n", algorithm.name()),
+        };
+        fs::write(path.join("config"), config)?;
+        fs::write(path.join("description"), "Unnamed repository; edit this file 'description' to name the repository.\n")?;
+        Ok(Self { path: path.to_owned(), algorithm })
+    }
+
+    /// Open an existing bare repository at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, RepoError> {
+        let path = path.as_ref();
+        if !path.join("HEAD").is_file() || !path.join("objects").is_dir() || !path.join("refs").is_dir() {
+            return Err(RepoError::NotRepository(path.to_owned()));
+        }
+        let mut repo = Self { path: path.to_owned(), algorithm: HashAlgorithm::Sha1 };
+        if let Some(format) = repo.config("extensions", "objectformat")?.pop() {
+            repo.algorithm = HashAlgorithm::from_name(&format.to_ascii_lowercase())
+                .ok_or(RepoError::UnsupportedObjectFormat(format))?;
+        }
+        Ok(repo)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Hash algorithm of object ids, from `extensions.objectFormat` in `config`
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Parse hex object id `id` read from a ref, which must be of [Repository::algorithm]
+    pub(crate) fn parse_id(&self, id: &str) -> Result<ObjectId, RepoError> {
+        unhex_with(id, self.algorithm).ok_or_else(|| ObjectIdError::InvalidId(id.to_owned()).into())
+    }
+
+    /// Append `[remote "{name}"]` section to `config`
+    pub fn add_remote(&self, name: &str, url: &str, refspecs: &[Refspec]) -> Result<(), RepoError> {
+        let mut config = fs::OpenOptions::new().append(true).open(self.path.join("config"))?;
+        write!(config, "[remote \"{}\"]\n\turl = {}\n", name, url)?;
+        for spec in refspecs {
+            writeln!(config, "\tfetch = {}", spec)?;
+        }
+        Ok(())
+    }
+
+    /// Url of remote `name` in `config`
+    pub fn remote_url(&self, name: &str) -> Result<String, RepoError> {
+        self.config(&format!("remote \"{}\"", name), "url")?
+            .pop()
+            .ok_or_else(|| RepoError::RemoteNotFound(name.to_owned()))
+    }
+
+    /// Fetch refspecs of remote `name` in `config`
+    pub fn remote_refspecs(&self, name: &str) -> Result<Vec<Refspec>, RepoError> {
+        self.config(&format!("remote \"{}\"", name), "fetch")?
+            .iter()
+            .map(|s| Ok(s.parse()?))
+            .collect()
+    }
+
+    /// Values of `key` in `[section]` of `config`
+    ///
+    /// Only the subset of git config syntax written by this crate and `git config` is supported.
+    fn config(&self, section: &str, key: &str) -> Result<Vec<String>, RepoError> {
+        let content = fs::read_to_string(self.path.join("config"))?;
+        let mut current = "";
+        let mut result = Vec::new();
+        for line in content.lines().map(|l| l.trim()) {
+            if line.starts_with('#') || line.starts_with(';') {
+                continue;
+            } else if line.starts_with('[') && line.ends_with(']') {
+                current = line[1..line.len() - 1].trim();
+            } else if current == section {
+                if let Some((k, v)) = line.split_once('=') {
+                    if k.trim().eq_ignore_ascii_case(key) {
+                        result.push(v.trim().to_owned());
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Open all packs in `objects/pack`, whose objects are inflated when requested
+    pub fn packs(&self) -> Result<Vec<MappedPackFile>, RepoError> {
+        let mut result = Vec::new();
+        for entry in fs::read_dir(self.path.join("objects/pack"))? {
+            let path = entry?.path();
+            // a pack without its index is still being written, or was left by a crash
+            if path.extension().is_some_and(|e| e == "pack") && path.with_extension("idx").is_file() {
+                result.push(self.open_pack(&path)?);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Memory-map pack at `path`, with ids and offsets read from its `.idx` file
+    fn open_pack(&self, path: &Path) -> Result<MappedPackFile, RepoError> {
+        let index = index::read_index(&fs::read(path.with_extension("idx"))?, self.algorithm)?;
+        let options = PackFileOptions { algorithm: self.algorithm, ..Default::default() };
+        // SAFETY: packs in `objects/pack` are only ever written to a temporary file and renamed,
+        // and never changed in place, by this crate or by git
+        let mapped = unsafe { packfile::map(path)? };
+        Ok(PackFile::from_index(Cursor::new(mapped), &index, options)?)
+    }
+
+    /// Store `pack` and its index in `objects/pack`, and return the path of pack file
+    ///
+    /// `raw` is the pack file `pack` was read from.
+    pub fn write_pack(&self, raw: &[u8], pack: &Pack) -> Result<PathBuf, RepoError> {
+        let name = format!("pack-{}", hex(&pack.checksum));
+        let dir = self.path.join("objects/pack");
+        let pack_path = dir.join(format!("{}.pack", name));
+        write_atomic(&pack_path, raw)?;
+
+        // index is written last, so that git never sees a pack without its index
+        let mut index = Vec::new();
+        crate::index::write_index(&mut index, pack)?;
+        write_atomic(&dir.join(format!("{}.idx", name)), &index)?;
+        Ok(pack_path)
+    }
+
+    /// Point ref `name` to object `id`
+    pub fn update_ref(&self, name: &str, id: &str) -> Result<(), RepoError> {
+        let path = self.ref_path(name)?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        write_atomic(&path, format!("{}\n", id).as_bytes())?;
+        Ok(())
+    }
+
+    /// Remove ref `name`, from loose refs and `packed-refs`
+    pub fn delete_ref(&self, name: &str) -> Result<(), RepoError> {
+        match fs::remove_file(self.ref_path(name)?) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        let packed = self.path.join("packed-refs");
+        if let Ok(content) = fs::read_to_string(&packed) {
+            let mut result = String::with_capacity(content.len());
+            let mut skip = false;
+            for line in content.lines() {
+                // peeled line `^id` belongs to the ref before it
+                if line.starts_with('^') && skip {
+                    continue;
+                }
+                skip = line.split_once(' ').is_some_and(|(_, n)| n == name);
+                if !skip {
+                    result.push_str(line);
+                    result.push('\n');
+                }
+            }
+            write_atomic(&packed, result.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Point `HEAD` to ref `target`
+    pub fn set_head(&self, target: &str) -> Result<(), RepoError> {
+        self.ref_path(target)?;
+        write_atomic(&self.path.join("HEAD"), format!("ref: {}\n", target).as_bytes())?;
+        Ok(())
+    }
+
+    /// Ref `HEAD` points to, or `None` if `HEAD` is detached
+    pub fn head(&self) -> Result<Option<String>, RepoError> {
+        let content = fs::read_to_string(self.path.join("HEAD"))?;
+        Ok(content.trim_end().strip_prefix("ref: ").map(|target| target.to_owned()))
+    }
+
+    /// Read object id of ref `name`, from loose refs or `packed-refs`
+    ///
+    /// Symbolic refs like `HEAD` are followed.
+    pub fn read_ref(&self, name: &str) -> Result<Option<String>, RepoError> {
+        let mut name = name.to_owned();
+        // limit depth of symbolic refs like git does
+        for _ in 0..5 {
+            let content = match fs::read_to_string(self.ref_path(&name)?) {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Ok(self.packed_refs()?.into_iter().find(|(n, _)| n == &name).map(|(_, id)| id));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            match content.trim_end().strip_prefix("ref: ") {
+                Some(target) => name = target.to_owned(),
+                None => return Ok(Some(content.trim_end().to_owned())),
+            }
+        }
+        Ok(None)
+    }
+
+    /// List all refs under `refs/` as `(name, id)`, sorted by name
+    ///
+    /// Symbolic refs, like `refs/remotes/origin/HEAD`, are listed with the id of their target,
+    /// and left out if it does not exist.
+    pub fn refs(&self) -> Result<Vec<(String, ObjectId)>, RepoError> {
+        let mut result = Vec::new();
+        for (name, id) in self.packed_refs()? {
+            result.push((name, self.parse_id(&id)?));
+        }
+        let mut dirs = vec![self.path.join("refs")];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().is_none_or(|e| e != "lock") {
+                    let name = path.strip_prefix(&self.path).unwrap().to_string_lossy().replace('\\', "/");
+                    let content = fs::read_to_string(&path)?;
+                    let id = match content.trim_end().strip_prefix("ref: ") {
+                        Some(_) => match self.read_ref(&name)? {
+                            Some(id) => self.parse_id(&id)?,
+                            None => continue,
+                        },
+                        None => self.parse_id(content.trim_end())?,
+                    };
+                    // loose refs take precedence over packed refs
+                    result.retain(|(n, _)| n != &name);
+                    result.push((name, id));
+                }
+            }
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    /// Fetch refs of remote `name` selected by refspecs, and update local refs.
+    ///
+    /// Local refs are sent as haves, so that only new objects are transferred.
+    /// The received thin pack is completed with local objects before it is stored.
+    /// Refs which are not fast-forward and not forced by `+` are not updated, and
+    /// reported as [UpdateKind::Rejected]. Refs already up to date are not reported.
+    ///
+    /// Pack data is saved while it is received. If a previous fetch or clone was interrupted,
+    /// its complete objects are stored first, and commits whose history is complete are sent
+    /// as haves, so that only the missing objects are transferred again.
+    pub fn fetch(&self, name: &str, options: &FetchOptions) -> Result<Vec<RefUpdate>, RepoError> {
+        let url = self.remote_url(name)?;
+        let refspecs = match &options.refspecs {
+            Some(refspecs) => refspecs.clone(),
+            None => self.remote_refspecs(name)?,
+        };
+
+        let mut client = options.client.build(&url)?.algorithm(self.algorithm);
+        if let Some(token) = &options.cancel {
+            client = client.cancellation(token.clone());
+        }
+        let refs = client.ls_refs(&refspec::ref_prefixes(&refspecs))?;
+        let mappings = refspec::map_refs(&refspecs, &refs)?;
+
+        let _lock = FetchLock::acquire(&self.path)?;
+        let local_refs: HashMap<String, ObjectId> = self.refs()?.into_iter().collect();
+        let mut packs = self.packs()?;
+        let resumed = self.resume_partial(&mut packs, local_refs.values())?;
+        let wants: Vec<ObjectId> = refspec::wants(&mappings)
+            .into_iter()
+            .filter(|id| packs.object(id).is_none())
+            .collect();
+
+        if !wants.is_empty() {
+            let shallow = self.shallow()?;
+            let haves = haves(&packs, local_refs.values().chain(resumed.iter()), &shallow);
+
+            let fetched = fetch_pack(&client, &FetchRequest {
+                wants: &wants,
+                haves: &haves,
+                shallow: &shallow,
+                depth: options.depth,
+                thin: true,
+                uri_protocols: &options.uri_protocols,
+                progress: options.progress.as_deref(),
+                partial: Some(&self.path),
+            })?;
+            let unpack = PackFileOptions {
+                algorithm: self.algorithm,
+                progress: options.progress.clone(),
+                cancel: options.cancel.clone(),
+                ..Default::default()
+            };
+            self.write_fetched(&client, fetched.pack, &fetched.packfile_uris, &mut packs, &unpack)?;
+            self.remove_partial()?;
+
+            if !fetched.shallow.is_empty() || !fetched.unshallow.is_empty() {
+                let unshallow = fetched.unshallow;
+                let mut shallow: Vec<ObjectId> = shallow.into_iter()
+                    .filter(|id| !unshallow.contains(id))
+                    .collect();
+                for id in fetched.shallow {
+                    if !shallow.contains(&id) {
+                        shallow.push(id);
+                    }
+                }
+                self.write_shallow(&shallow)?;
+            }
+        }
+
+        self.check_connected(&packs, &mappings, local_refs.values())?;
+        let shallow = self.shallow()?;
+        let mut result = Vec::new();
+        for mapping in mappings.iter() {
+            let local = match &mapping.local {
+                Some(local) => local,
+                None => continue,
+            };
+            let old = local_refs.get(local);
+            let fast_forward = old.is_some_and(|old| is_ancestor(&packs, &shallow, old, &mapping.id));
+            let kind = mapping.classify(old, fast_forward);
+            match kind {
+                UpdateKind::UpToDate => continue,
+                UpdateKind::New | UpdateKind::FastForward | UpdateKind::Forced => self.update_ref(local, &hex(&mapping.id))?,
+                _ => {}
+            }
+            result.push(RefUpdate {
+                name: local.clone(),
+                old: old.copied(),
+                new: Some(mapping.id),
+                kind,
+            });
+        }
+
+        if options.prune {
+            let mut stale: Vec<_> = local_refs.iter()
+                .filter(|(n, _)| refspecs.iter().any(|r| !r.negative && r.matches_local(n)))
+                .filter(|(n, _)| !mappings.iter().any(|m| m.local.as_ref() == Some(n)))
+                .collect();
+            stale.sort();
+            for (name, id) in stale {
+                self.delete_ref(name)?;
+                result.push(RefUpdate {
+                    name: name.clone(),
+                    old: Some(*id),
+                    new: None,
+                    kind: UpdateKind::Deleted,
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    /// Check that all objects reachable from local refs of `mappings` are in `packs`, before refs are updated
+    ///
+    /// Objects reachable from `complete` are not walked, as they are known to be connected.
+    fn check_connected<'a, I: Iterator<Item = &'a ObjectId>>(&self, packs: &[MappedPackFile], mappings: &[RefMapping], complete: I) -> Result<(), RepoError> {
+        let tips: Vec<ObjectId> = mappings.iter().filter(|m| m.local.is_some()).map(|m| m.id).collect();
+        let mut check = Connectivity::new(packs);
+        check.set_shallow(self.shallow()?);
+        check.set_complete(complete.copied());
+        match check.check(&tips)?.missing.first() {
+            Some(id) => Err(RepoError::MissingObject(hex(id))),
+            None => Ok(()),
+        }
+    }
+
+    /// Store packs of a `fetch` response and add them to `packs`
+    ///
+    /// Packs advertised by `packfile-uris` are downloaded and checked against their hash first,
+    /// as the inline pack may have deltas against their objects.
+    /// The inline thin pack is completed with objects from `packs`.
+    fn write_fetched(&self, client: &Client, file: fs::File, uris: &[(String, String)], packs: &mut Vec<MappedPackFile>, options: &PackFileOptions) -> Result<(), RepoError> {
+        for (hash, uri) in uris {
+            let mut download = tempfile::tempfile()?;
+            client.download_to(uri, &mut download)?;
+            let pack = read_pack(download, 0, options)?;
+            if hex(&pack.checksum) != *hash {
+                return Err(RepoError::PackHashMismatch(uri.clone(), hash.clone()));
+            }
+            self.write_complete(pack, 0, packs)?;
+        }
+        let pack = read_pack(file, 0, options)?;
+        self.write_complete(pack, 0, packs)
+    }
+
+    /// Complete thin `pack` starting at `start` in its file with objects from `packs`,
+    /// store it and add it to `packs`
+    ///
+    /// The pack is copied to a temporary file in `objects/pack`, which is renamed once complete.
+    fn write_complete(&self, pack: PackFile<BufReader<fs::File>>, start: u64, packs: &mut Vec<MappedPackFile>) -> Result<(), RepoError> {
+        let bases = thin_bases(&pack, packs);
+        let dir = self.path.join("objects/pack");
+        let mut temp = tempfile::NamedTempFile::new_in(&dir)?;
+        let mut index = Vec::new();
+        let checksum = if bases.is_empty() {
+            check_resolved(&pack)?;
+            pack.write_index(&mut index)?;
+            let checksum = pack.checksum;
+            let mut reader = pack.into_inner();
+            reader.seek(SeekFrom::Start(start))?;
+            std::io::copy(&mut reader, temp.as_file_mut())?;
+            checksum
+        } else {
+            let mut reader = pack.into_inner();
+            let length = reader.get_ref().metadata()?.len() - start;
+            reader.seek(SeekFrom::Start(start))?;
+            let mut writer = BufWriter::new(temp.as_file_mut());
+            let checksum = pack::complete_thin(&mut reader, length, &bases, self.algorithm, &mut writer)?;
+            writer.flush()?;
+            drop(writer);
+
+            let mut file = temp.reopen()?;
+            file.seek(SeekFrom::Start(0))?;
+            let completed = read_pack(file, 0, &PackFileOptions { algorithm: self.algorithm, ..Default::default() })?;
+            check_resolved(&completed)?;
+            completed.write_index(&mut index)?;
+            checksum
+        };
+
+        let name = format!("pack-{}", hex(&checksum));
+        let pack_path = dir.join(format!("{}.pack", name));
+        temp.persist(&pack_path).map_err(|e| e.error)?;
+        // index is written last, so that git never sees a pack without its index
+        write_atomic(&dir.join(format!("{}.idx", name)), &index)?;
+        packs.push(self.open_pack(&pack_path)?);
+        Ok(())
+    }
+
+    /// Store complete objects of a fetch which was interrupted, and return commits to send as haves
+    ///
+    /// Local refs `tips` are known to be complete. Saved pack data is removed afterwards.
+    fn resume_partial<'a, I: Iterator<Item = &'a ObjectId>>(&self, packs: &mut Vec<MappedPackFile>, tips: I) -> Result<Vec<ObjectId>, RepoError> {
+        let mut file = match fs::OpenOptions::new().read(true).write(true).open(self.path.join(PARTIAL_PACK)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let shallow = fs::read_to_string(self.path.join(PARTIAL_SHALLOW)).unwrap_or_default();
+        let shallow: Vec<ObjectId> = shallow.lines().filter_map(|l| unhex_with(l, self.algorithm)).collect();
+        let haves = match packfile::salvage(&mut file, self.algorithm)? {
+            0 => Vec::new(),
+            _ => self.store_partial(file, &shallow, packs, tips)?,
+        };
+        self.remove_partial()?;
+        Ok(haves)
+    }
+
+    /// Store objects of salvaged pack `file`, except commits whose history is incomplete and their tags
+    ///
+    /// Refs are never updated to incomplete commits, as wants found locally are not fetched.
+    /// Trees and blobs are kept, so that they are not sent again if the server deltifies against them.
+    fn store_partial<'a, I: Iterator<Item = &'a ObjectId>>(&self, file: fs::File, received_shallow: &[ObjectId], packs: &mut Vec<MappedPackFile>, tips: I) -> Result<Vec<ObjectId>, RepoError> {
+        // the pack was cut anywhere: salvage kept only entries with a valid header and a complete
+        // zlib stream, checked against its Adler-32 and length, and gave it a new trailer, so
+        // entries are as trustworthy as those of a pack received whole
+        let options = PackFileOptions { algorithm: self.algorithm, ..Default::default() };
+        let mut pack = read_pack(file, 0, &options)?;
+        let bases = thin_bases(&pack, packs);
+        if !bases.is_empty() {
+            let mut reader = pack.into_inner();
+            let length = reader.seek(SeekFrom::End(0))?;
+            reader.seek(SeekFrom::Start(0))?;
+            let mut writer = BufWriter::new(tempfile::tempfile()?);
+            pack::complete_thin(&mut reader, length, &bases, self.algorithm, &mut writer)?;
+            pack = read_pack(writer.into_inner().map_err(|e| e.into_error())?, 0, &options)?;
+        }
+
+        let mut shallow = self.shallow()?;
+        let store = (&pack, &*packs);
+        let mut check = Connectivity::new(&store);
+        check.set_shallow(shallow.iter().chain(received_shallow.iter()).copied());
+        check.set_complete(tips.copied());
+        let commits: Vec<_> = pack.ids()
+            .filter(|id| pack.object_type(id) == Some(ObjectType::Commit))
+            .copied()
+            .collect();
+        let complete = check.complete(&commits)?;
+
+        // deltas whose base is missing are not resolved and never kept
+        let keep = |offset| {
+            let id = match pack.id_at(offset) {
+                Some(id) => id,
+                None => return false,
+            };
+            match pack.object_type(id) {
+                Some(ObjectType::Commit) => complete.contains(id),
+                Some(ObjectType::Tag) => pack.object(id).ok().flatten().and_then(|o| o.tag().ok())
+                    .is_some_and(|tag| complete.contains(&tag.object) || packs.object(&tag.object).is_some()),
+                Some(ObjectType::Tree) | Some(ObjectType::Blob) => true,
+                _ => false,
+            }
+        };
+        if !pack.offsets().any(keep) {
+            return Ok(Vec::new());
+        }
+        let mut writer = BufWriter::new(tempfile::tempfile()?);
+        pack.copy_entries(keep, &mut writer)?;
+        let kept = read_pack(writer.into_inner().map_err(|e| e.into_error())?, 0, &options)?;
+        self.write_complete(kept, 0, packs)?;
+
+        let boundary: Vec<ObjectId> = received_shallow.iter()
+            .filter(|id| complete.contains(id) && !shallow.contains(id))
+            .copied()
+            .collect();
+        if !boundary.is_empty() {
+            shallow.extend(boundary);
+            self.write_shallow(&shallow)?;
+        }
+        Ok(complete.into_iter().collect())
+    }
+
+    /// Remove pack data saved by a fetch
+    fn remove_partial(&self) -> Result<(), RepoError> {
+        for name in [PARTIAL_PACK, PARTIAL_SHALLOW] {
+            match fs::remove_file(self.path.join(name)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Store bundles listed by `bundle-uri` of the server, and return ids of their refs
+    ///
+    /// Relative uris are resolved against the remote url, which the list is received from.
+    /// Each bundle is downloaded to a temporary file before it is read.
+    /// Like git, bundles which can not be downloaded or applied are skipped,
+    /// as the following fetch gets the missing objects anyway.
+    fn fetch_bundles(&self, client: &Client, packs: &mut Vec<MappedPackFile>) -> Result<Vec<ObjectId>, RepoError> {
+        let list = BundleList::parse(&client.bundle_uri()?)?;
+        let mut tips = Vec::new();
+        for bundle in list.bundles.iter() {
+            let uri = match bundle.resolve(client.url()) {
+                Some(uri) => uri,
+                None => continue,
+            };
+            let mut file = tempfile::tempfile()?;
+            if client.download_to(&uri, &mut file).is_err() {
+                continue;
+            }
+            let (header, pack, start) = match read_bundle(file) {
+                Ok(bundle) => bundle,
+                Err(_) => continue,
+            };
+            let satisfied = pack.algorithm == self.algorithm && header.prerequisites.iter()
+                .all(|(id, _)| packs.object(id).is_some());
+            if !satisfied {
+                continue;
+            }
+            self.write_complete(pack, start, packs)?;
+            tips.extend(header.refs.iter().map(|(_, id)| *id));
+            if list.mode == BundleMode::Any {
+                break;
+            }
+        }
+        Ok(tips)
+    }
+
+    fn packed_refs(&self) -> Result<Vec<(String, String)>, RepoError> {
+        let content = match fs::read_to_string(self.path.join("packed-refs")) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(content.lines()
+            .filter(|l| !l.starts_with('#') && !l.starts_with('^'))
+            .filter_map(|l| {
+                let (id, name) = l.split_at(l.find(' ')?);
+                Some((name[1..].to_owned(), id.to_owned()))
+            })
+            .collect())
+    }
+
+    /// Commits whose parents are not available because of a shallow fetch
+    pub fn shallow(&self) -> Result<Vec<ObjectId>, RepoError> {
+        match fs::read_to_string(self.path.join("shallow")) {
+            Ok(content) => content.lines().map(|l| self.parse_id(l)).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_shallow(&self, shallow: &[ObjectId]) -> Result<(), RepoError> {
+        if shallow.is_empty() {
+            return match fs::remove_file(self.path.join("shallow")) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let mut content = String::new();
+        for id in shallow {
+            content.push_str(&hex(id));
+            content.push('\n');
+        }
+        write_atomic(&self.path.join("shallow"), content.as_bytes())?;
+        Ok(())
+    }
+
+    fn ref_path(&self, name: &str) -> Result<PathBuf, RepoError> {
+        if name != "HEAD" && !name.starts_with("refs/")
+            || name.contains('*')
+            || name.split('/').any(|c| c.starts_with('.'))
+            || !refspec::is_valid_ref_part(name) {
+            return Err(RepoError::InvalidRefName(name.to_owned()));
+        }
+        Ok(self.path.join(name))
+    }
+}
+
+/// Lock file of a repository fetch, removed when dropped
+///
+/// It is left behind only if the process is killed, and has to be removed by hand then, like
+/// `index.lock` of git. Pack data saved by the killed fetch is still resumed afterwards.
+struct FetchLock(PathBuf);
+
+impl FetchLock {
+    fn acquire(repo: &Path) -> Result<Self, RepoError> {
+        let path = repo.join(FETCH_LOCK);
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => Ok(Self(path)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(RepoError::Locked(path)),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for FetchLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Write `data` to `path.lock` and rename it to `path`
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut lock = path.as_os_str().to_owned();
+    lock.push(".lock");
+    fs::write(&lock, data)?;
+    fs::rename(&lock, path)
+}
+
+/// Read a bundle downloaded to `file`, and return its header, its pack and where the pack starts
+fn read_bundle(file: fs::File) -> Result<(BundleHeader, PackFile<BufReader<fs::File>>, u64), BundleError> {
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(0))?;
+    let header = BundleHeader::read(&mut reader)?;
+    let algorithm = header.algorithm()?;
+    let start = reader.stream_position()?;
+    let pack = PackFile::new(reader, PackFileOptions { algorithm, ..Default::default() })?;
+    Ok((header, pack, start))
+}
+
+/// Read the pack starting at `start` in `file`
+fn read_pack(file: fs::File, start: u64, options: &PackFileOptions) -> Result<PackFile<BufReader<fs::File>>, RepoError> {
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(PackFile::new(reader, options.clone())?)
+}
+
+/// Objects of `packs` which unresolved deltas of `pack` are based on, to complete it as a thin pack
+fn thin_bases<R>(pack: &PackFile<R>, packs: &[MappedPackFile]) -> Vec<(ObjectType, Vec<u8>)> {
+    let mut bases = Vec::new();
+    let mut seen = HashSet::new();
+    for (_, object_type) in pack.unresolved() {
+        // deltas against other deltas of the pack are resolved once their base is appended
+        if let ObjectType::RefDelta(id) = object_type {
+            if let Some(base) = packs.object(id) {
+                if seen.insert(*id) {
+                    bases.push((base.object_type.clone(), base.data.clone()));
+                }
+            }
+        }
+    }
+    bases
+}
+
+/// Fail with the base of a delta of `pack` which could not be resolved
+fn check_resolved<R>(pack: &PackFile<R>) -> Result<(), RepoError> {
+    match pack.unresolved().next() {
+        Some((_, ObjectType::RefDelta(id))) => Err(RepoError::MissingObject(hex(id))),
+        Some((offset, _)) => Err(RepoError::MissingObject(format!("at offset {}", offset))),
+        None => Ok(()),
+    }
+}
+
+/// Pack and shallow boundary received from `fetch` command
+pub(crate) struct FetchResult {
+    /// File the pack is written to while it is received
+    pub pack: fs::File,
+    /// `(hash, uri)` of packs to download in addition to [FetchResult::pack]
+    pub packfile_uris: Vec<(String, String)>,
+    pub shallow: Vec<ObjectId>,
+    pub unshallow: Vec<ObjectId>,
+}
+
+/// Arguments of [fetch_pack]
+pub(crate) struct FetchRequest<'a> {
+    pub wants: &'a [ObjectId],
+    pub haves: &'a [ObjectId],
+    /// Shallow commits of the local repository
+    pub shallow: &'a [ObjectId],
+    pub depth: Option<u32>,
+    pub thin: bool,
+    /// With `uri_protocols`, the server may send some objects as packs to download instead
+    pub uri_protocols: &'a [String],
+    /// Receiver of server progress and received bytes, `no-progress` is sent without it
+    pub progress: Option<&'a dyn Progress>,
+    /// Repository where pack data is saved while it is received, to resume an interrupted fetch
+    pub partial: Option<&'a Path>,
+}
+
+/// Send `fetch` command with `wants` and `haves`, and collect the response
+pub(crate) fn fetch_pack(client: &Client, request: &FetchRequest) -> Result<FetchResult, RepoError> {
+    let mut builder = client.request_builder(true)
+        .command("fetch")
+        .argument("ofs-delta");
+    if request.progress.is_none() {
+        builder = builder.argument("no-progress");
+    }
+    if request.thin {
+        builder = builder.argument("thin-pack");
+    }
+    if let Some(depth) = request.depth {
+        builder = builder.argument(&format!("deepen {}", depth));
+    }
+    for want in request.wants {
+        builder = builder.want(want);
+    }
+    for have in request.haves {
+        builder = builder.have(have);
+    }
+    for id in request.shallow {
+        builder = builder.argument(&format!("shallow {}", hex(id)));
+    }
+    if !request.uri_protocols.is_empty() {
+        builder = builder.argument(&format!("packfile-uris {}", request.uri_protocols.join(",")));
+    }
+    let body = builder.argument("done").build();
+
+    let pack = match request.partial {
+        Some(path) => fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path.join(PARTIAL_PACK))?,
+        None => tempfile::tempfile()?,
+    };
+    let mut result = FetchResult { pack, packfile_uris: Vec::new(), shallow: Vec::new(), unshallow: Vec::new() };
+    let mut section = String::new();
+    let mut transfer = Transfer::new();
+    let mut received = false;
+    let mut packets = client.request(body)?;
+    for msg in &mut packets {
+        match msg {
+            Message::Normal(line) => {
+                let line = String::from_utf8(line).map_err(ClientError::from)?;
+                if !line.contains(' ') {
+                    section = line.trim_end().to_owned();
+                } else if section == "packfile-uris" {
+                    // packfile-uri = PKT-LINE(40*(HEXDIGIT) SP *%x20-ff LF), 64 digits with SHA-256
+                    if let Some((hash, uri)) = line.trim_end().split_once(' ') {
+                        result.packfile_uris.push((hash.to_owned(), uri.to_owned()));
+                    }
+                } else if let Some(id) = line.trim_end().strip_prefix("shallow ") {
+                    let id = parse_response_id(client, id)?;
+                    if !result.shallow.contains(&id) {
+                        result.shallow.push(id);
+                    }
+                } else if let Some(id) = line.trim_end().strip_prefix("unshallow ") {
+                    result.unshallow.push(parse_response_id(client, id)?);
+                }
+            }
+            Message::PackData(data) => {
+                if let Some(path) = request.partial {
+                    // shallow-info section is sent before the pack
+                    if !received {
+                        fs::write(path.join(PARTIAL_SHALLOW), result.shallow.iter().map(|id| format!("{}\n", hex(id))).collect::<String>())?;
+                    }
+                }
+                received = true;
+                result.pack.write_all(&data)?;
+                if let Some(progress) = request.progress {
+                    transfer.received(progress, data.len());
+                }
+            }
+            Message::PackProgress(text) => {
+                if let Some(progress) = request.progress {
+                    for event in progress::parse_remote(&text) {
+                        progress.event(&event);
+                    }
+                }
+            }
+            Message::PackError(e) => return Err(RepoError::RemoteError(e)),
+            _ => {}
+        }
+    }
+    packets.finish()?;
+    Ok(result)
+}
+
+/// Id in a `fetch` response of `client`, in its object format
+fn parse_response_id(client: &Client, id: &str) -> Result<ObjectId, RepoError> {
+    unhex_with(id, client.hash_algorithm()).ok_or_else(|| ObjectIdError::InvalidId(id.to_owned()).into())
+}
+
+/// Local ref tips and their most recent history, to be sent as haves
+fn haves<'a, I: Iterator<Item = &'a ObjectId>>(packs: &[MappedPackFile], tips: I, shallow: &[ObjectId]) -> Vec<ObjectId> {
+    let mut walk = RevWalk::new(packs);
+    walk.set_shallow(shallow.iter().copied());
+    let mut haves = Vec::new();
+    for tip in tips {
+        let _ = walk.push(tip);
+        haves.push(*tip);
+    }
+    // stop at the first commit missing locally
+    haves.extend(walk.map_while(Result::ok).take(MAX_HAVES).map(|(id, _)| id));
+    haves.sort();
+    haves.dedup();
+    haves
+}
+
+/// Whether commit `ancestor` is reachable from commit `descendant`
+///
+/// Like `git merge-base --is-ancestor`, `descendant..ancestor` is walked by commit date, which
+/// stops once the history of `descendant` is walked past `ancestor`, instead of walking it all.
+/// Commits missing from `packs`, other than parents of `shallow` commits, are treated as unreachable.
+fn is_ancestor(packs: &[MappedPackFile], shallow: &[ObjectId], ancestor: &ObjectId, descendant: &ObjectId) -> bool {
+    let mut walk = RevWalk::new(packs);
+    walk.set_shallow(shallow.iter().copied());
+    if walk.push(ancestor).is_err() || walk.hide(descendant).is_err() {
+        return false;
+    }
+    walk.next().is_none()
+}
+
+/// Local ref `HEAD` should point to, mapped from the symref target of remote `HEAD`
+pub(crate) fn head_target<'a>(refs: &[Ref], mappings: &'a [RefMapping]) -> Option<&'a str> {
+    let target = refs.iter().find(|r| r.name == "HEAD")?.symref_target.as_ref()?;
+    mappings.iter()
+        .find(|m| &m.remote == target)
+        .and_then(|m| m.local.as_deref())
+}
+
+/// Clone remote repository at `url` into a new bare repository at `path`
+///
+/// Refs selected by [CloneOptions::refspecs] are fetched in a single pack,
+/// which is stored in `objects/pack` with its index.
+/// `HEAD` is set from the symref target advertised by the server.
+/// The repository uses the `object-format` advertised by the server.
+///
+/// If the transfer is interrupted, the repository is left with its remote, its `HEAD` and the
+/// data received so far, and [Repository::fetch] of the remote resumes the clone.
+pub fn clone_bare<P: AsRef<Path>>(url: &str, path: P, options: &CloneOptions) -> Result<Repository, RepoError> {
+    let mut client = options.client.build(url)?;
+    if let Some(token) = &options.cancel {
+        client = client.cancellation(token.clone());
+    }
+    let capabilities = client.capabilities()?;
+    let algorithm = client::object_format(&capabilities)?;
+    let client = client.algorithm(algorithm);
+
+    let repo = Repository::init_bare_with(path, algorithm)?;
+    repo.add_remote(&options.remote, url, &options.refspecs)?;
+
+    let mut prefixes = refspec::ref_prefixes(&options.refspecs);
+    prefixes.push("HEAD".to_owned());
+    let refs = client.ls_refs(&prefixes)?;
+    let mappings = refspec::map_refs(&options.refspecs, &refs)?;
+    // set before objects are received, as a resumed clone does not list `HEAD` again
+    if let Some(target) = head_target(&refs, &mappings) {
+        repo.set_head(target)?;
+    }
+
+    let _lock = FetchLock::acquire(repo.path())?;
+    let mut packs = Vec::new();
+    let mut haves = Vec::new();
+    if options.bundle_uri && options.depth.is_none() && capabilities.iter().any(|c| c == "bundle-uri") {
+        haves = repo.fetch_bundles(&client, &mut packs)?;
+    }
+
+    let wants: Vec<ObjectId> = refspec::wants(&mappings)
+        .into_iter()
+        .filter(|id| packs.object(id).is_none())
+        .collect();
+    if !wants.is_empty() {
+        let fetched = fetch_pack(&client, &FetchRequest {
+            wants: &wants,
+            haves: &haves,
+            shallow: &[],
+            depth: options.depth,
+            thin: !haves.is_empty(),
+            uri_protocols: &options.uri_protocols,
+            progress: options.progress.as_deref(),
+            partial: Some(repo.path()),
+        })?;
+        let unpack = PackFileOptions {
+            algorithm,
+            progress: options.progress.clone(),
+            cancel: options.cancel.clone(),
+            ..Default::default()
+        };
+        repo.write_fetched(&client, fetched.pack, &fetched.packfile_uris, &mut packs, &unpack)?;
+        repo.remove_partial()?;
+        if !fetched.shallow.is_empty() {
+            repo.write_shallow(&fetched.shallow)?;
+        }
+    }
+    repo.check_connected(&packs, &mappings, [].iter())?;
+    for mapping in mappings.iter() {
+        if let Some(local) = &mapping.local {
+            repo.update_ref(local, &hex(&mapping.id))?;
+        }
+    }
+    Ok(repo)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repo::{clone_bare, CloneOptions, FetchOptions, Repository, RepoError, RefUpdate};
+    use crate::refspec::UpdateKind;
+    use crate::server::UploadPack;
+    use crate::oid::HashAlgorithm;
+    use crate::progress::Event;
+    use std::sync::{Arc, Mutex};
+    use crate::pack::{ObjectType, PackWriter};
+    use crate::testing::{commit, fixture, git, git_at, http_backend, publish, serve, serve_with, TestResponse};
+    use crate::utils::hex;
+    use std::fs;
+
+    #[test]
+    fn test_init_bare() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init_bare(dir.path().join("a.git")).unwrap();
+        repo.update_ref("refs/heads/main", "9192b5e5f2941fd76aa5a08043dc8aa6a31831a2").unwrap();
+        repo.set_head("refs/heads/main").unwrap();
+        assert_eq!(repo.head().unwrap().as_deref(), Some("refs/heads/main"));
+        assert_eq!(repo.read_ref("HEAD").unwrap().as_deref(), Some("9192b5e5f2941fd76aa5a08043dc8aa6a31831a2"));
+        assert_eq!(repo.refs().unwrap(), vec![
+            ("refs/heads/main".to_owned(), "9192b5e5f2941fd76aa5a08043dc8aa6a31831a2".parse().unwrap()),
+        ]);
+        assert!(matches!(repo.update_ref("refs/heads/../x", "0"), Err(RepoError::InvalidRefName(_))));
+        assert!(matches!(Repository::init_bare(dir.path()), Err(RepoError::AlreadyExists(_))));
+        Repository::open(dir.path().join("a.git")).unwrap();
+    }
+
+    #[test]
+    fn test_clone_bare() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let url = format!("{}/repo.git", serve(dir.path()));
+
+        let repo = clone_bare(&url, dir.path().join("clone.git"), &CloneOptions::default()).unwrap();
+        let work = dir.path().join("work");
+        assert_eq!(repo.read_ref("HEAD").unwrap().unwrap(), git(&work, &["rev-parse", "HEAD"]).trim());
+        assert_eq!(repo.read_ref("refs/tags/v1").unwrap().unwrap(), git(&work, &["rev-parse", "v1"]).trim());
+
+        let clone = repo.path();
+        git(clone, &["fsck", "--full", "--strict"]);
+        assert_eq!(git(clone, &["symbolic-ref", "HEAD"]).trim(), "refs/heads/master");
+        assert_eq!(git(clone, &["config", "--get-all", "remote.origin.fetch"]), "+refs/heads/*:refs/heads/*\n+refs/tags/*:refs/tags/*\n");
+        assert_eq!(git(clone, &["show", "HEAD:album/b.toml"]), "title = \"b\"\n");
+
+        // a pack whose index has not been written yet is ignored
+        let pack = fs::read_dir(clone.join("objects/pack")).unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|e| e == "pack"))
+            .unwrap();
+        fs::copy(&pack, clone.join("objects/pack/pack-partial.pack")).unwrap();
+        assert_eq!(repo.packs().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_clone_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let url = format!("{}/repo.git", serve(dir.path()));
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let received = events.clone();
+        let progress = move |event: &Event| received.lock().unwrap().push(event.clone());
+        let options = CloneOptions { progress: Some(Arc::new(progress)), ..Default::default() };
+        clone_bare(&url, dir.path().join("clone.git"), &options).unwrap();
+
+        let events = events.lock().unwrap();
+        assert!(events.iter().any(|e| matches!(e, Event::Remote(stage) if stage.name.ends_with("objects") && stage.finished)));
+        assert!(events.iter().any(|e| matches!(e, Event::Received { bytes, .. } if *bytes > 0)));
+        let inflated = events.iter().rev().find_map(|e| match e {
+            Event::Inflated { done, total } => Some((*done, *total)),
+            _ => None,
+        });
+        assert!(matches!(inflated, Some((done, total)) if done == total && total > 0));
+    }
+
+    #[test]
+    fn test_clone_shallow() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let url = format!("{}/repo.git", serve(dir.path()));
+
+        let options = CloneOptions { depth: Some(1), ..Default::default() };
+        let repo = clone_bare(&url, dir.path().join("clone.git"), &options).unwrap();
+        let head = repo.read_ref("HEAD").unwrap().unwrap();
+        assert_eq!(std::fs::read_to_string(repo.path().join("shallow")).unwrap(), format!("{}\n", head));
+        assert_eq!(git(repo.path(), &["rev-list", "--count", "HEAD"]).trim(), "1");
+    }
+
+    #[test]
+    fn test_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let url = format!("{}/repo.git", serve(dir.path()));
+        let repo = clone_bare(&url, dir.path().join("clone.git"), &CloneOptions::default()).unwrap();
+        // symbolic refs are listed with their target, or not at all if it is missing
+        git(repo.path(), &["symbolic-ref", "refs/remotes/origin/HEAD", "refs/heads/master"]);
+        git(repo.path(), &["symbolic-ref", "refs/remotes/origin/gone", "refs/heads/gone"]);
+        let master = repo.read_ref("refs/heads/master").unwrap().unwrap().parse().unwrap();
+        assert!(repo.refs().unwrap().contains(&("refs/remotes/origin/HEAD".to_owned(), master)));
+        assert!(!repo.refs().unwrap().iter().any(|(name, _)| name == "refs/remotes/origin/gone"));
+        git(repo.path(), &["symbolic-ref", "--delete", "refs/remotes/origin/gone"]);
+        UploadPack::from_repository(&repo).unwrap();
+        assert_eq!(repo.fetch("origin", &FetchOptions::default()).unwrap(), vec![]);
+
+        // fast-forward master, add a branch and rewrite it later
+        let work = dir.path().join("work");
+        let content: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        let old = commit(&work, &[("album/a.toml", &content)], "Update a");
+        publish(dir.path());
+        assert_eq!(repo.fetch("origin", &FetchOptions::default()).unwrap()[0].kind, UpdateKind::FastForward);
+
+        // blob of album/a.toml is sent as a delta based on the local one
+        let new = commit(&work, &[("album/a.toml", &format!("{}line 100\n", content))], "Update a again");
+        git(&work, &["branch", "feature"]);
+        publish(dir.path());
+
+        let updates = repo.fetch("origin", &FetchOptions::default()).unwrap();
+        assert_eq!(updates, vec![
+            RefUpdate {
+                name: "refs/heads/feature".to_owned(),
+                old: None,
+                new: Some(new.parse().unwrap()),
+                kind: UpdateKind::New,
+            },
+            RefUpdate {
+                name: "refs/heads/master".to_owned(),
+                old: Some(old.parse().unwrap()),
+                new: Some(new.parse().unwrap()),
+                kind: UpdateKind::FastForward,
+            },
+        ]);
+        git(repo.path(), &["fsck", "--full", "--strict"]);
+        assert_eq!(git(repo.path(), &["show", "master:album/a.toml"]), format!("{}line 100\n", content));
+
+        // rewrite feature, and remove it afterwards
+        git(&work, &["checkout", "-q", "feature"]);
+        git(&work, &["reset", "-q", "--hard", "HEAD~1"]);
+        let rewritten = commit(&work, &[("c.toml", "c")], "Rewrite feature");
+        publish(dir.path());
+        let options = FetchOptions {
+            refspecs: Some(vec!["refs/heads/*:refs/heads/*".parse().unwrap()]),
+            ..Default::default()
+        };
+        let updates = repo.fetch("origin", &options).unwrap();
+        assert_eq!(updates[0].kind, UpdateKind::Rejected);
+        assert_eq!(repo.read_ref("refs/heads/feature").unwrap().unwrap(), new);
+        let updates = repo.fetch("origin", &FetchOptions::default()).unwrap();
+        assert_eq!(updates[0].kind, UpdateKind::Forced);
+        assert_eq!(repo.read_ref("refs/heads/feature").unwrap().unwrap(), rewritten);
+
+        git(&work, &["checkout", "-q", "master"]);
+        git(&dir.path().join("repo.git"), &["branch", "-D", "feature"]);
+        let options = FetchOptions { prune: true, ..Default::default() };
+        let updates = repo.fetch("origin", &options).unwrap();
+        assert_eq!(updates, vec![RefUpdate {
+            name: "refs/heads/feature".to_owned(),
+            old: Some(rewritten.parse().unwrap()),
+            new: None,
+            kind: UpdateKind::Deleted,
+        }]);
+        assert_eq!(repo.read_ref("refs/heads/feature").unwrap(), None);
+        git(repo.path(), &["fsck", "--full", "--strict"]);
+    }
+
+    #[test]
+    fn test_clone_sha256() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path().join("work");
+        std::fs::create_dir(&work).unwrap();
+        git(&work, &["init", "-q", "--object-format=sha256"]);
+        let content: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        commit(&work, &[("README.md", &content), ("album/a.toml", "title = \"a\"\n")], "Initial commit");
+        git(&work, &["tag", "-a", "v1", "-m", "v1"]);
+        git(dir.path(), &["clone", "-q", "--bare", "work", "repo.git"]);
+        let url = format!("{}/repo.git", serve(dir.path()));
+
+        let repo = clone_bare(&url, dir.path().join("clone.git"), &CloneOptions::default()).unwrap();
+        assert_eq!(repo.algorithm(), HashAlgorithm::Sha256);
+        assert_eq!(repo.read_ref("refs/tags/v1").unwrap().unwrap(), git(&work, &["rev-parse", "v1"]).trim());
+        git(repo.path(), &["fsck", "--full", "--strict"]);
+
+        // README.md is sent as a delta against the local blob
+        let head = commit(&work, &[("README.md", &format!("{}line 100\n", content))], "Update README");
+        publish(dir.path());
+        let repo = Repository::open(repo.path()).unwrap();
+        let updates = repo.fetch("origin", &FetchOptions::default()).unwrap();
+        assert_eq!(updates[0].new.map(|id| id.to_string()), Some(head.clone()));
+        assert_eq!(head.len(), 64);
+        git(repo.path(), &["fsck", "--full", "--strict"]);
+        assert_eq!(git(repo.path(), &["show", "HEAD:README.md"]), format!("{}line 100\n", content));
+    }
+
+    #[test]
+    fn test_resume_clone() {
+        // two unrelated branches with incompressible files, `a` being the most recent
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path().join("work");
+        std::fs::create_dir(&work).unwrap();
+        git(&work, &["init", "-q"]);
+        let mut seed = 1u64;
+        let mut random = || -> String {
+            (0..2000).map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                format!("{:x}", seed >> 33)
+            }).collect()
+        };
+        git(&work, &["checkout", "-q", "--orphan", "b"]);
+        std::fs::write(work.join("b.txt"), random()).unwrap();
+        git_at(&work, 1615876429, &["add", "-A"]);
+        git_at(&work, 1615876429, &["commit", "-q", "-m", "b"]);
+        git(&work, &["checkout", "-q", "--orphan", "master"]);
+        git(&work, &["rm", "-rfq", "."]);
+        std::fs::write(work.join("a.txt"), random()).unwrap();
+        git_at(&work, 1615876500, &["add", "-A"]);
+        git_at(&work, 1615876500, &["commit", "-q", "-m", "a"]);
+        git(dir.path(), &["clone", "-q", "--bare", "work", "repo.git"]);
+        git(&dir.path().join("repo.git"), &["symbolic-ref", "HEAD", "refs/heads/b"]);
+        let a = git(&work, &["rev-parse", "master"]).trim().to_owned();
+        let b = git(&work, &["rev-parse", "b"]).trim().to_owned();
+
+        // the first fetch response is cut in the last object of its pack
+        let root = dir.path().to_owned();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        let url = serve_with(move |req| {
+            let fetch = req.body.windows(13).any(|w| w == b"command=fetch");
+            let body = req.body.clone();
+            let mut response = http_backend(&root, req);
+            if fetch {
+                let mut requests = received.lock().unwrap();
+                if requests.is_empty() {
+                    let len = response.body.len();
+                    response.body.truncate(len - 40);
+                }
+                requests.push(body);
+            }
+            response
+        });
+
+        let path = dir.path().join("clone.git");
+        assert!(clone_bare(&format!("{}/repo.git", url), &path, &CloneOptions::default()).is_err());
+        assert!(path.join("objects/pack/tmp_fetch_pack").exists());
+        assert!(!path.join("objects/pack/tmp_fetch.lock").exists());
+
+        // a fetch holding the lock is not disturbed
+        let repo = Repository::open(&path).unwrap();
+        fs::write(path.join("objects/pack/tmp_fetch.lock"), "").unwrap();
+        assert!(matches!(repo.fetch("origin", &FetchOptions::default()), Err(RepoError::Locked(_))));
+        assert!(path.join("objects/pack/tmp_fetch_pack").exists());
+        fs::remove_file(path.join("objects/pack/tmp_fetch.lock")).unwrap();
+
+        let updates = repo.fetch("origin", &FetchOptions::default()).unwrap();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(repo.read_ref("refs/heads/master").unwrap(), Some(a.clone()));
+        assert_eq!(repo.read_ref("refs/heads/b").unwrap(), Some(b.clone()));
+        assert!(!path.join("objects/pack/tmp_fetch_pack").exists());
+        assert_eq!(repo.head().unwrap().as_deref(), Some("refs/heads/b"));
+        git(&path, &["fsck", "--full", "--strict"]);
+
+        // the salvaged commit is sent as have instead of want
+        let requests = requests.lock().unwrap();
+        let resumed = String::from_utf8_lossy(&requests[1]);
+        assert!(resumed.contains(&format!("have {}", a)), "{}", resumed);
+        assert!(!resumed.contains(&format!("want {}", a)));
+        assert!(resumed.contains(&format!("want {}", b)));
+    }
+
+    #[test]
+    fn test_clone_packfile_uris() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let remote = dir.path().join("repo.git");
+        let blob = git(&remote, &["rev-parse", "HEAD:album/a.toml"]).trim().to_owned();
+        let mut writer = PackWriter::new();
+        writer.add(ObjectType::Blob, git(&remote, &["cat-file", "blob", &blob]).into_bytes());
+        let mut offloaded = Vec::new();
+        let hash = hex(&writer.write(&mut offloaded).unwrap());
+
+        // the section is added to the response of git, so that the test does not depend
+        // on server side support of uploadpack.blobPackfileUri
+        let root = dir.path().to_owned();
+        let uri_hash = Arc::new(Mutex::new(hash.clone()));
+        let served_hash = uri_hash.clone();
+        let url = serve_with(move |req| {
+            if req.path == "/a.pack" {
+                return TestResponse { status: 200, headers: Vec::new(), body: offloaded.clone() };
+            }
+            let base = format!("http://{}", req.header("Host").unwrap());
+            let uris = req.body.windows(18).any(|w| w == b"packfile-uris http");
+            let mut response = http_backend(&root, req);
+            if uris {
+                let start = response.body.windows(13).position(|w| w == b"000dpackfile\n").unwrap();
+                let mut section = Vec::new();
+                crate::io::write_pktline(&mut section, "packfile-uris").unwrap();
+                crate::io::write_pktline(&mut section, &format!("{} {}/a.pack", served_hash.lock().unwrap(), base)).unwrap();
+                section.extend_from_slice(b"0001");
+                response.body.splice(start..start, section);
+            }
+            response
+        });
+
+        let options = CloneOptions { uri_protocols: vec!["http".to_owned()], ..Default::default() };
+        let repo = clone_bare(&format!("{}/repo.git", url), dir.path().join("clone.git"), &options).unwrap();
+        assert_eq!(repo.packs().unwrap().len(), 2);
+        assert_eq!(git(repo.path(), &["cat-file", "-t", &blob]).trim(), "blob");
+        git(repo.path(), &["fsck", "--full", "--strict"]);
+
+        *uri_hash.lock().unwrap() = "0".repeat(40);
+        let result = clone_bare(&format!("{}/repo.git", url), dir.path().join("mismatch.git"), &options);
+        assert!(matches!(result, Err(RepoError::PackHashMismatch(..))));
+    }
+
+    #[test]
+    fn test_clone_bundle_uri() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let work = dir.path().join("work");
+        git(&work, &["branch", "first", "HEAD~"]);
+        git(&work, &["bundle", "create", "-q", "first.bundle", "first"]);
+        let bundle = std::fs::read(work.join("first.bundle")).unwrap();
+
+        let root = dir.path().to_owned();
+        let url = serve_with(move |req| {
+            if req.path == "/bundles/first.bundle" {
+                return TestResponse { status: 200, headers: Vec::new(), body: bundle.clone() };
+            } else if req.body.windows(18).any(|w| w == b"command=bundle-uri") {
+                let mut body = Vec::new();
+                for line in ["bundle.version=1", "bundle.mode=all", "bundle.first.uri=bundles/first.bundle"] {
+                    crate::io::write_pktline(&mut body, line).unwrap();
+                }
+                body.extend_from_slice(b"0000");
+                let headers = vec![("Content-Type".to_owned(), "application/x-git-upload-pack-result".to_owned())];
+                return TestResponse { status: 200, headers, body };
+            }
+            let advertise = req.path.ends_with("/info/refs");
+            let mut response = http_backend(&root, req);
+            if advertise {
+                // advertise bundle-uri, which needs a newer git
+                let flush = response.body.len() - 4;
+                response.body.splice(flush..flush, b"000fbundle-uri\n".iter().copied());
+            }
+            response
+        });
+
+        let options = CloneOptions { bundle_uri: true, ..Default::default() };
+        let repo = clone_bare(&format!("{}/repo.git", url), dir.path().join("clone.git"), &options).unwrap();
+        assert_eq!(repo.packs().unwrap().len(), 2);
+        assert_eq!(repo.read_ref("HEAD").unwrap(), Some(git(&work, &["rev-parse", "HEAD"]).trim().to_owned()));
+        git(repo.path(), &["fsck", "--full", "--strict"]);
+    }
+}
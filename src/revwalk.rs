@@ -0,0 +1,433 @@
+//! Walk commit history, like `git rev-list`.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::vec::IntoIter;
+use thiserror::Error;
+use crate::object::{Commit, ObjectError};
+use crate::oid::ObjectId;
+use crate::pack::{ObjectStore, ObjectType};
+use crate::utils::hex;
+
+#[derive(Debug, Error)]
+pub enum RevWalkError {
+    #[error("object {0} not found")]
+    MissingObject(String),
+    #[error("object {0} is not a commit")]
+    NotCommit(String),
+
+    #[error(transparent)]
+    ObjectError(#[from] ObjectError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sorting {
+    /// Newest committer date first, like `git rev-list`
+    #[default]
+    Date,
+    /// Children before parents, without intermixing lines of history, like `git rev-list --topo-order`
+    Topo,
+}
+
+/// Iterator over commits reachable from tips
+///
+/// ```no_run
+/// use anni_fetch::revwalk::{RevWalk, Sorting};
+/// # fn example(pack: &anni_fetch::Pack, old: &anni_fetch::ObjectId, new: &anni_fetch::ObjectId) -> Result<(), Box<dyn std::error::Error>> {
+/// // old..new
+/// let mut walk = RevWalk::new(pack);
+/// walk.push(new)?;
+/// walk.hide(old)?;
+/// walk.set_sorting(Sorting::Topo);
+/// for commit in walk {
+///     let (_id, commit) = commit?;
+///     println!("{}", String::from_utf8_lossy(&commit.message));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct RevWalk<'a, S: ObjectStore + ?Sized> {
+    store: &'a S,
+    tips: Vec<ObjectId>,
+    hidden: Vec<ObjectId>,
+    shallow: HashSet<ObjectId>,
+    sorting: Sorting,
+    first_parent: bool,
+    state: Option<State>,
+}
+
+enum State {
+    Date(DateWalk),
+    /// Commits already walked to the end, when some are hidden or sorted in topological order
+    List(IntoIter<(ObjectId, Commit)>),
+    Done,
+}
+
+impl<'a, S: ObjectStore + ?Sized> RevWalk<'a, S> {
+    pub fn new(store: &'a S) -> Self {
+        Self {
+            store,
+            tips: Vec::new(),
+            hidden: Vec::new(),
+            shallow: HashSet::new(),
+            sorting: Sorting::default(),
+            first_parent: false,
+            state: None,
+        }
+    }
+
+    /// Start walking from commit `id`, peeling annotated tags
+    pub fn push(&mut self, id: &ObjectId) -> Result<(), RevWalkError> {
+        let id = peel(self.store, id)?;
+        self.tips.push(id);
+        Ok(())
+    }
+
+    /// Exclude commits reachable from commit `id`, like `^id`
+    ///
+    /// Hidden history is only walked as far as needed to exclude commits of tips,
+    /// and hidden commits missing in store, like in a shallow clone, are ignored.
+    pub fn hide(&mut self, id: &ObjectId) -> Result<(), RevWalkError> {
+        let id = match peel(self.store, id) {
+            Err(RevWalkError::MissingObject(_)) => *id,
+            result => result?,
+        };
+        self.hidden.push(id);
+        Ok(())
+    }
+
+    pub fn set_sorting(&mut self, sorting: Sorting) {
+        self.sorting = sorting;
+    }
+
+    /// Follow only the first parent of merge commits
+    pub fn set_first_parent(&mut self, first_parent: bool) {
+        self.first_parent = first_parent;
+    }
+
+    /// Treat `shallow` commits as roots, as their parents were not fetched
+    pub fn set_shallow<I: IntoIterator<Item = ObjectId>>(&mut self, shallow: I) {
+        self.shallow = shallow.into_iter().collect();
+    }
+
+    fn start(&mut self) -> Result<State, RevWalkError> {
+        let mut walk = DateWalk {
+            seen: HashSet::new(),
+            uninteresting: HashSet::new(),
+            queue: BinaryHeap::new(),
+            commits: HashMap::new(),
+            counter: 0,
+            error: None,
+        };
+        for id in self.hidden.iter() {
+            walk.mark_uninteresting(self.store, id, &self.shallow);
+        }
+        for tip in self.tips.iter() {
+            walk.add(self.store, tip)?;
+        }
+        if self.hidden.is_empty() && self.sorting == Sorting::Date {
+            return Ok(State::Date(walk));
+        }
+
+        let commits = if self.hidden.is_empty() {
+            let mut commits = Vec::new();
+            while let Some(commit) = walk.next(self.store, &self.shallow, self.first_parent)? {
+                commits.push(commit);
+            }
+            commits
+        } else {
+            walk.limit(self.store, &self.shallow, self.first_parent)?
+        };
+        match self.sorting {
+            Sorting::Date => Ok(State::List(commits.into_iter())),
+            Sorting::Topo => Ok(State::List(topo_sort(commits, &self.tips, self.first_parent).into_iter())),
+        }
+    }
+}
+
+impl<'a, S: ObjectStore + ?Sized> Iterator for RevWalk<'a, S> {
+    type Item = Result<(ObjectId, Commit), RevWalkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.state.is_none() {
+            match self.start() {
+                Ok(state) => self.state = Some(state),
+                Err(e) => {
+                    self.state = Some(State::Done);
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        let result = match self.state.as_mut()? {
+            State::Date(walk) => walk.next(self.store, &self.shallow, self.first_parent).transpose(),
+            State::List(commits) => return commits.next().map(Ok),
+            State::Done => return None,
+        };
+        if let Some(Err(_)) = result {
+            self.state = Some(State::Done);
+        }
+        result
+    }
+}
+
+/// Commits to be shown ordered by committer date, then by insertion order
+///
+/// Hidden commits are queued like others but marked uninteresting, and pass the mark
+/// on to their parents, as `git rev-list` does.
+struct DateWalk {
+    seen: HashSet<ObjectId>,
+    uninteresting: HashSet<ObjectId>,
+    queue: BinaryHeap<(i64, Reverse<usize>, ObjectId)>,
+    commits: HashMap<ObjectId, Commit>,
+    counter: usize,
+    error: Option<RevWalkError>,
+}
+
+impl DateWalk {
+    fn add<S: ObjectStore + ?Sized>(&mut self, store: &S, id: &ObjectId) -> Result<(), RevWalkError> {
+        if !self.seen.insert(*id) {
+            return Ok(());
+        }
+        let commit = read_commit(store, id)?;
+        self.enqueue(*id, commit);
+        Ok(())
+    }
+
+    fn enqueue(&mut self, id: ObjectId, commit: Commit) {
+        self.queue.push((commit.committer.time, Reverse(self.counter), id));
+        self.commits.insert(id, commit);
+        self.counter += 1;
+    }
+
+    /// Mark `id` uninteresting, and the parents of commits already walked
+    ///
+    /// Commits missing in store are boundaries of hidden history.
+    fn mark_uninteresting<S: ObjectStore + ?Sized>(&mut self, store: &S, id: &ObjectId, shallow: &HashSet<ObjectId>) {
+        let mut pending = vec![*id];
+        while let Some(id) = pending.pop() {
+            if !self.uninteresting.insert(id) {
+                continue;
+            }
+            if self.seen.insert(id) {
+                if let Ok(commit) = read_commit(store, &id) {
+                    self.enqueue(id, commit);
+                }
+            } else if let Some(commit) = self.commits.get(&id).filter(|_| !shallow.contains(&id)) {
+                pending.extend(commit.parents.iter().copied());
+            }
+        }
+    }
+
+    /// Whether all queued commits are uninteresting, so that no more commits can be shown
+    fn everybody_uninteresting(&self) -> bool {
+        self.queue.iter().all(|(_, _, id)| self.uninteresting.contains(id))
+    }
+
+    fn next<S: ObjectStore + ?Sized>(&mut self, store: &S, shallow: &HashSet<ObjectId>, first_parent: bool) -> Result<Option<(ObjectId, Commit)>, RevWalkError> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let (_, _, id) = match self.queue.pop() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let commit = self.commits.remove(&id).unwrap();
+        if !shallow.contains(&id) {
+            for parent in parents(&commit, first_parent) {
+                if let Err(e) = self.add(store, parent) {
+                    // report after the commit itself
+                    self.error = Some(e);
+                    break;
+                }
+            }
+        }
+        Ok(Some((id, commit)))
+    }
+
+    /// Walk until only uninteresting commits are queued, and return interesting commits in date order
+    fn limit<S: ObjectStore + ?Sized>(&mut self, store: &S, shallow: &HashSet<ObjectId>, first_parent: bool) -> Result<Vec<(ObjectId, Commit)>, RevWalkError> {
+        let mut shown = Vec::new();
+        let mut missing = Vec::new();
+        // missing parents are only allowed if hidden, so hidden history is walked further to find them
+        while !self.everybody_uninteresting() || missing.iter().any(|id| !self.uninteresting.contains(id)) {
+            let (_, _, id) = match self.queue.pop() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if shallow.contains(&id) {
+                shown.push(id);
+                continue;
+            }
+            let commit = &self.commits[&id];
+            if self.uninteresting.contains(&id) {
+                for parent in commit.parents.clone() {
+                    self.mark_uninteresting(store, &parent, shallow);
+                }
+            } else {
+                for parent in parents(commit, first_parent).copied().collect::<Vec<_>>() {
+                    match self.add(store, &parent) {
+                        Err(RevWalkError::MissingObject(_)) => missing.push(parent),
+                        result => result?,
+                    }
+                }
+                shown.push(id);
+            }
+        }
+        if let Some(id) = missing.iter().find(|id| !self.uninteresting.contains(*id)) {
+            return Err(RevWalkError::MissingObject(hex(id)));
+        }
+        // commits may be marked uninteresting after being walked
+        let (uninteresting, commits) = (&self.uninteresting, &mut self.commits);
+        Ok(shown.into_iter()
+            .filter(|id| !uninteresting.contains(id))
+            .map(|id| (id, commits.remove(&id).unwrap()))
+            .collect())
+    }
+}
+
+/// Order `commits` so that no parent comes before its children, following tips in order
+fn topo_sort(commits: Vec<(ObjectId, Commit)>, tips: &[ObjectId], first_parent: bool) -> Vec<(ObjectId, Commit)> {
+    let mut children: HashMap<ObjectId, usize> = commits.iter().map(|(id, _)| (*id, 0)).collect();
+    for (_, commit) in commits.iter() {
+        for parent in parents(commit, first_parent) {
+            if let Some(count) = children.get_mut(parent) {
+                *count += 1;
+            }
+        }
+    }
+
+    let mut commits: HashMap<ObjectId, Commit> = commits.into_iter().collect();
+    let mut stack: Vec<ObjectId> = tips.iter().rev()
+        .filter(|id| children.get(*id) == Some(&0))
+        .cloned()
+        .collect();
+    let mut result = Vec::with_capacity(commits.len());
+    while let Some(id) = stack.pop() {
+        let commit = match commits.remove(&id) {
+            Some(commit) => commit,
+            None => continue,
+        };
+        for parent in parents(&commit, first_parent).rev() {
+            if let Some(count) = children.get_mut(parent) {
+                *count -= 1;
+                if *count == 0 {
+                    stack.push(*parent);
+                }
+            }
+        }
+        result.push((id, commit));
+    }
+    result
+}
+
+fn parents(commit: &Commit, first_parent: bool) -> std::slice::Iter<'_, ObjectId> {
+    let count = if first_parent { commit.parents.len().min(1) } else { commit.parents.len() };
+    commit.parents[..count].iter()
+}
+
+fn peel<S: ObjectStore + ?Sized>(store: &S, id: &ObjectId) -> Result<ObjectId, RevWalkError> {
+    let mut id = *id;
+    loop {
+        let object = store.object(&id).ok_or_else(|| RevWalkError::MissingObject(hex(&id)))?;
+        match object.object_type {
+            ObjectType::Commit => return Ok(id),
+            ObjectType::Tag => id = object.tag()?.object,
+            _ => return Err(RevWalkError::NotCommit(hex(&id))),
+        }
+    }
+}
+
+fn read_commit<S: ObjectStore + ?Sized>(store: &S, id: &ObjectId) -> Result<Commit, RevWalkError> {
+    let object = store.object(id).ok_or_else(|| RevWalkError::MissingObject(hex(id)))?;
+    if object.object_type != ObjectType::Commit {
+        return Err(RevWalkError::NotCommit(hex(id)));
+    }
+    Ok(object.commit()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::revwalk::{RevWalk, RevWalkError, Sorting};
+    use crate::testing::{git, git_at, pack_objects};
+    use crate::utils::{hex, unhex};
+    use crate::Pack;
+
+    fn messages(walk: RevWalk<Pack>) -> Vec<String> {
+        walk.map(|c| String::from_utf8(c.unwrap().1.message).unwrap().trim().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_revwalk() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path();
+        git(work, &["init", "-q"]);
+        // a - b - c ----- m
+        //      \         /
+        //       d ----- e
+        for (message, time) in [("a", 1), ("b", 2), ("c", 5)] {
+            git_at(work, 1615876429 + time, &["commit", "-q", "--allow-empty", "-m", message]);
+        }
+        git(work, &["checkout", "-q", "-b", "side", "HEAD~"]);
+        for (message, time) in [("d", 3), ("e", 6)] {
+            git_at(work, 1615876429 + time, &["commit", "-q", "--allow-empty", "-m", message]);
+        }
+        git(work, &["checkout", "-q", "master"]);
+        git_at(work, 1615876429 + 7, &["merge", "-q", "--no-ff", "-m", "m", "side"]);
+        git(work, &["tag", "-a", "v1", "-m", "v1", "HEAD~"]);
+
+        let pack = Pack::from_reader(&mut Cursor::new(pack_objects(work, "master\nv1"))).unwrap();
+        let id = |rev: &str| unhex(git(work, &["rev-parse", rev]).trim()).unwrap();
+        let walk = |tips: &[&str], hidden: &[&str], sorting: Sorting, first_parent: bool| {
+            let mut walk = RevWalk::new(&pack);
+            for tip in tips {
+                walk.push(&id(tip)).unwrap();
+            }
+            for tip in hidden {
+                walk.hide(&id(tip)).unwrap();
+            }
+            walk.set_sorting(sorting);
+            walk.set_first_parent(first_parent);
+            messages(walk)
+        };
+
+        assert_eq!(walk(&["master"], &[], Sorting::Date, false), vec!["m", "e", "c", "d", "b", "a"]);
+        assert_eq!(walk(&["master"], &[], Sorting::Topo, false), vec!["m", "c", "e", "d", "b", "a"]);
+        assert_eq!(walk(&["master"], &[], Sorting::Date, true), vec!["m", "c", "b", "a"]);
+        assert_eq!(walk(&["master"], &["side"], Sorting::Date, false), vec!["m", "c"]);
+        assert_eq!(walk(&["v1"], &["master~2"], Sorting::Topo, false), vec!["c"]);
+        assert_eq!(walk(&["side", "master~"], &[], Sorting::Date, false), vec!["e", "c", "d", "b", "a"]);
+
+        // shallow boundary
+        let mut shallow = RevWalk::new(&pack);
+        shallow.push(&id("master")).unwrap();
+        shallow.set_shallow([id("master~"), id("side")]);
+        assert_eq!(messages(shallow), vec!["m", "e", "c"]);
+
+        let mut tree = RevWalk::new(&pack);
+        assert!(matches!(tree.push(&id("master^{tree}")), Err(RevWalkError::NotCommit(_))));
+
+        // parents of `a` are missing when only `c` is packed
+        let partial = Pack::from_reader(&mut Cursor::new(pack_objects(work, &format!("master~\n^{}", hex(&id("master~2")))))).unwrap();
+        let mut missing = RevWalk::new(&partial);
+        missing.push(&id("master~")).unwrap();
+        let result: Vec<_> = missing.collect();
+        assert_eq!(result.len(), 2);
+        assert!(matches!(result[1], Err(RevWalkError::MissingObject(_))));
+
+        // hidden history may be missing, like in a shallow clone
+        let partial = Pack::from_reader(&mut Cursor::new(pack_objects(work, &format!("master\n^{}", hex(&id("master~2")))))).unwrap();
+        let hidden = |hidden: &[&str]| {
+            let mut walk = RevWalk::new(&partial);
+            walk.push(&id("master")).unwrap();
+            for tip in hidden {
+                walk.hide(&id(tip)).unwrap();
+            }
+            messages(walk)
+        };
+        assert_eq!(hidden(&["master~2"]), vec!["m", "e", "c", "d"]);
+        assert_eq!(hidden(&["side"]), vec!["m", "c"]);
+        assert_eq!(hidden(&["side", "master~"]), vec!["m"]);
+    }
+}
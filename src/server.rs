@@ -0,0 +1,944 @@
+//! Serve repositories with protocol v2 `git-upload-pack`.
+//!
+//! https://git-scm.com/docs/protocol-v2
+//! https://git-scm.com/docs/http-protocol
+
+use std::borrow::Cow;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use thiserror::Error;
+use crate::client::Ref;
+use crate::io;
+use crate::object::{self, ObjectError, TreeEntry};
+use crate::oid::{HashAlgorithm, ObjectId};
+use crate::pack::{Object, ObjectStore, ObjectType, PackWriter};
+use crate::packfile::MappedPackFile;
+use crate::repo::{RepoError, Repository};
+use crate::utils::{hex, unhex_with};
+
+const AGENT: &str = concat!("anni-fetch/", env!("CARGO_PKG_VERSION"));
+/// Number of haves after which `ready` is sent even if some want has no common commit,
+/// like the limit of `have` lines git clients send in vain
+const MAX_HAVES: usize = 256;
+/// Pack data in a sideband packet
+const SIDEBAND_LEN: usize = 65515;
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("unknown command {0}")]
+    UnknownCommand(String),
+    #[error("unsupported argument {0}")]
+    UnsupportedArgument(String),
+    #[error("unsupported filter {0}")]
+    UnsupportedFilter(String),
+    #[error("not our ref {0}")]
+    NotOurRef(String),
+    #[error("object {0} not found")]
+    MissingObject(String),
+
+    #[error(transparent)]
+    ObjectError(#[from] ObjectError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// Server side of `git-upload-pack` with protocol v2, which serves `ls-refs` and `fetch`
+///
+/// Packs are generated from objects in `store` on every `fetch`, with deltas if the client supports `ofs-delta`.
+/// Only ref tips and objects their annotated tags point to can be wanted.
+///
+/// ```no_run
+/// use anni_fetch::server::{HttpRequest, UploadPack};
+/// use anni_fetch::Repository;
+///
+/// let server = UploadPack::from_repository(&Repository::open("repo.git").unwrap()).unwrap();
+/// // in a web handler of `/repo.git/*path`
+/// let response = server.handle_http(&HttpRequest {
+///     method: "GET",
+///     path: "/info/refs",
+///     query: "service=git-upload-pack",
+///     git_protocol: Some("version=2"),
+///     content_encoding: None,
+///     body: &[],
+/// });
+/// assert_eq!(response.status, 200);
+/// ```
+pub struct UploadPack<S: ObjectStore> {
+    store: S,
+    refs: Vec<Ref>,
+    algorithm: HashAlgorithm,
+}
+
+/// An HTTP request to a repository, for [UploadPack::handle_http]
+pub struct HttpRequest<'a> {
+    pub method: &'a str,
+    /// Path relative to the repository url, like `/info/refs` or `/git-upload-pack`
+    pub path: &'a str,
+    pub query: &'a str,
+    /// Value of `Git-Protocol` header
+    pub git_protocol: Option<&'a str>,
+    /// Value of `Content-Encoding` header, git sends large requests with `gzip`
+    pub content_encoding: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+/// Response to an [HttpRequest]
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers: vec![
+                ("Content-Type", content_type.to_owned()),
+                ("Cache-Control", "no-cache".to_owned()),
+            ],
+            body,
+        }
+    }
+
+    fn text(status: u16, text: &str) -> Self {
+        Self::new(status, "text/plain", text.as_bytes().to_vec())
+    }
+}
+
+/// A command request
+///
+/// ```text
+/// request = command capability-list [command-args] flush-pkt
+/// ```
+struct Request {
+    command: String,
+    arguments: Vec<String>,
+}
+
+enum Filter {
+    BlobNone,
+    BlobLimit(usize),
+    TreeDepth(usize),
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Result<Self, ServerError> {
+        let unsupported = || ServerError::UnsupportedFilter(spec.to_owned());
+        if spec == "blob:none" {
+            Ok(Filter::BlobNone)
+        } else if let Some(limit) = spec.strip_prefix("blob:limit=") {
+            let (number, unit) = match limit.char_indices().last() {
+                Some((i, 'k')) => (&limit[..i], 1 << 10),
+                Some((i, 'm')) => (&limit[..i], 1 << 20),
+                Some((i, 'g')) => (&limit[..i], 1 << 30),
+                _ => (limit, 1),
+            };
+            let number: usize = number.parse().map_err(|_| unsupported())?;
+            Ok(Filter::BlobLimit(number.saturating_mul(unit)))
+        } else if let Some(depth) = spec.strip_prefix("tree:") {
+            Ok(Filter::TreeDepth(depth.parse().map_err(|_| unsupported())?))
+        } else {
+            Err(unsupported())
+        }
+    }
+}
+
+#[derive(Default)]
+struct FetchArgs {
+    wants: Vec<ObjectId>,
+    haves: Vec<ObjectId>,
+    shallow: HashSet<ObjectId>,
+    deepen: Option<usize>,
+    deepen_relative: bool,
+    deepen_since: Option<i64>,
+    deepen_not: Vec<ObjectId>,
+    filter: Option<Filter>,
+    done: bool,
+    ofs_delta: bool,
+    include_tag: bool,
+    no_progress: bool,
+}
+
+impl UploadPack<Vec<MappedPackFile>> {
+    /// Serve objects and refs of bare repository `repo`, as they are now
+    pub fn from_repository(repo: &Repository) -> Result<Self, RepoError> {
+        let mut refs = Vec::new();
+        if let Some(id) = repo.read_ref("HEAD")? {
+            refs.push(Ref { name: "HEAD".to_owned(), id: repo.parse_id(&id)?, symref_target: repo.head()?, peeled: None });
+        }
+        for (name, id) in repo.refs()? {
+            refs.push(Ref { name, id, symref_target: None, peeled: None });
+        }
+        Ok(Self::new(repo.packs()?, refs).algorithm(repo.algorithm()))
+    }
+}
+
+impl<S: ObjectStore> UploadPack<S> {
+    /// Serve `refs` with objects in `store`
+    ///
+    /// [Ref::peeled] is filled in from `store` if it is not set.
+    pub fn new(store: S, refs: Vec<Ref>) -> Self {
+        let refs = refs.into_iter()
+            .map(|mut r| {
+                if r.peeled.is_none() {
+                    r.peeled = peel_tag(&store, &r.id).ok().filter(|peeled| *peeled != r.id);
+                }
+                r
+            })
+            .collect();
+        Self { store, refs, algorithm: HashAlgorithm::Sha1 }
+    }
+
+    /// Set hash algorithm of objects in store, advertised as `object-format`, SHA-1 by default
+    pub fn algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Write capability advertisement
+    ///
+    /// ```text
+    /// capability-advertisement = protocol-version capability-list flush-pkt
+    /// protocol-version = PKT-LINE("version 2" LF)
+    /// ```
+    pub fn advertise<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        io::write_pktline(writer, "version 2")?;
+        io::write_pktline(writer, &format!("agent={}", AGENT))?;
+        io::write_pktline(writer, "ls-refs")?;
+        io::write_pktline(writer, "fetch=shallow filter")?;
+        io::write_pktline(writer, "server-option")?;
+        io::write_pktline(writer, &format!("object-format={}", self.algorithm.name()))?;
+        io::write_packet(writer, 0)
+    }
+
+    /// Read a command request from `reader`, and write response to `writer`
+    ///
+    /// The pack of `fetch` is streamed to `writer` as it is compressed.
+    /// On error, an `ERR` packet is written so that the client can show the message,
+    /// and the error is returned.
+    pub fn serve<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<(), ServerError> {
+        let result = read_request(reader, self.algorithm).and_then(|request| match request.command.as_str() {
+            "ls-refs" => self.ls_refs(&request.arguments, writer),
+            "fetch" => self.fetch(&request.arguments, writer),
+            command => Err(ServerError::UnknownCommand(command.to_owned())),
+        });
+        if let Err(e) = &result {
+            io::write_pktline(writer, &format!("ERR {}", e))?;
+        }
+        result
+    }
+
+    /// Handle a smart HTTP request
+    ///
+    /// Only protocol v2 is supported, so `Git-Protocol` must contain `version=2`.
+    /// The response is kept in memory; to stream large packs, answer `POST /git-upload-pack`
+    /// with status 200 and `Content-Type: application/x-git-upload-pack-result`,
+    /// and write the body with [UploadPack::serve].
+    pub fn handle_http(&self, request: &HttpRequest) -> HttpResponse {
+        if !request.git_protocol.is_some_and(|p| p.split(':').any(|v| v == "version=2")) {
+            return HttpResponse::text(400, "only protocol v2 is supported");
+        }
+
+        match (request.method, request.path.trim_end_matches('/')) {
+            ("GET", "/info/refs") => {
+                if !request.query.split('&').any(|q| q == "service=git-upload-pack") {
+                    return HttpResponse::text(403, "service not enabled");
+                }
+                let mut body = Vec::new();
+                io::write_pktline(&mut body, "# service=git-upload-pack").unwrap();
+                io::write_packet(&mut body, 0).unwrap();
+                self.advertise(&mut body).unwrap();
+                HttpResponse::new(200, "application/x-git-upload-pack-advertisement", body)
+            }
+            ("POST", "/git-upload-pack") => {
+                let body = match request.content_encoding {
+                    None | Some("identity") => request.body.to_vec(),
+                    Some("gzip") | Some("x-gzip") => match gunzip(request.body) {
+                        Some(body) => body,
+                        None => return HttpResponse::text(400, "invalid gzip body"),
+                    },
+                    Some(_) => return HttpResponse::text(415, "unsupported content encoding"),
+                };
+                let mut response = Vec::new();
+                // errors are reported to client with ERR packet
+                let _ = self.serve(&mut body.as_slice(), &mut response);
+                HttpResponse::new(200, "application/x-git-upload-pack-result", response)
+            }
+            (_, "/info/refs") | (_, "/git-upload-pack") => HttpResponse::text(405, "method not allowed"),
+            _ => HttpResponse::text(404, "not found"),
+        }
+    }
+
+    /// ```text
+    /// ls-refs-args = ["symrefs"] ["peel"] *("ref-prefix" SP prefix)
+    /// output = *(PKT-LINE(obj-id SP refname *(SP ref-attribute) LF)) flush-pkt
+    /// ```
+    fn ls_refs<W: Write>(&self, arguments: &[String], out: &mut W) -> Result<(), ServerError> {
+        let mut symrefs = false;
+        let mut peel = false;
+        let mut prefixes = Vec::new();
+        for arg in arguments {
+            match arg.as_str() {
+                "symrefs" => symrefs = true,
+                "peel" => peel = true,
+                "unborn" => {}
+                arg => match arg.strip_prefix("ref-prefix ") {
+                    Some(prefix) => prefixes.push(prefix),
+                    None => return Err(ServerError::UnsupportedArgument(arg.to_owned())),
+                },
+            }
+        }
+
+        for r in self.refs.iter() {
+            if !prefixes.is_empty() && !prefixes.iter().any(|p| r.name.starts_with(p)) {
+                continue;
+            }
+            let mut line = format!("{} {}", r.id, r.name);
+            if let (true, Some(target)) = (symrefs, &r.symref_target) {
+                line.push_str(&format!(" symref-target:{}", target));
+            }
+            if let (true, Some(peeled)) = (peel, &r.peeled) {
+                line.push_str(&format!(" peeled:{}", peeled));
+            }
+            io::write_pktline(out, &line)?;
+        }
+        io::write_packet(out, 0)?;
+        Ok(())
+    }
+
+    /// ```text
+    /// output = acknowledgements flush-pkt |
+    ///          [acknowledgments delim-pkt] [shallow-info delim-pkt] packfile flush-pkt
+    /// ```
+    fn fetch<W: Write>(&self, arguments: &[String], out: &mut W) -> Result<(), ServerError> {
+        let args = self.parse_fetch(arguments)?;
+
+        let common: Vec<ObjectId> = args.haves.iter()
+            .filter(|id| self.store.object(id).is_some_and(|o| o.object_type == ObjectType::Commit))
+            .copied()
+            .collect();
+        if !args.done {
+            io::write_pktline(out, "acknowledgments")?;
+            for id in common.iter() {
+                io::write_pktline(out, &format!("ACK {}", hex(id)))?;
+            }
+            if common.is_empty() {
+                io::write_pktline(out, "NAK")?;
+            }
+            let ready = args.haves.len() >= MAX_HAVES || (!common.is_empty() && self.ready(&args.wants, &common)?);
+            if !ready {
+                // let client send more haves
+                io::write_packet(out, 0)?;
+                return Ok(());
+            }
+            io::write_pktline(out, "ready")?;
+            io::write_packet(out, 1)?;
+        }
+
+        let (writer, shallow, unshallow) = self.pack_objects(&args, &common)?;
+        if args.deepen.is_some() || args.deepen_since.is_some() || !args.deepen_not.is_empty() || !shallow.is_empty() || !unshallow.is_empty() {
+            io::write_pktline(out, "shallow-info")?;
+            for id in shallow.iter() {
+                io::write_pktline(out, &format!("shallow {}", hex(id)))?;
+            }
+            for id in unshallow.iter() {
+                io::write_pktline(out, &format!("unshallow {}", hex(id)))?;
+            }
+            io::write_packet(out, 1)?;
+        }
+
+        io::write_pktline(out, "packfile")?;
+        if !args.no_progress {
+            io::write_sideband(out, 2, format!("Enumerating objects: {}, done.\n", writer.len()).as_bytes())?;
+        }
+        let mut pack = writer.reader();
+        let mut buf = vec![0; SIDEBAND_LEN];
+        loop {
+            let len = pack.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            io::write_sideband(out, 1, &buf[..len])?;
+        }
+        io::write_packet(out, 0)?;
+        Ok(())
+    }
+
+    /// Whether every want has a common commit as ancestor, so that the client can stop sending haves
+    ///
+    /// Commits older than all common commits are not walked, as git does.
+    fn ready(&self, wants: &[ObjectId], common: &[ObjectId]) -> Result<bool, ServerError> {
+        let common_set: HashSet<ObjectId> = common.iter().copied().collect();
+        let mut oldest = i64::MAX;
+        for id in common {
+            oldest = oldest.min(self.object(id)?.commit()?.committer.time);
+        }
+        for want in wants {
+            let mut found = false;
+            let mut visited = HashSet::new();
+            let mut pending = vec![peel_tag(&self.store, want)?];
+            while let Some(id) = pending.pop() {
+                if common_set.contains(&id) {
+                    found = true;
+                    break;
+                }
+                if !visited.insert(id) {
+                    continue;
+                }
+                match self.store.object(&id).filter(|o| o.object_type == ObjectType::Commit) {
+                    Some(object) => {
+                        let commit = object.commit()?;
+                        if commit.committer.time >= oldest {
+                            pending.extend(commit.parents);
+                        }
+                    }
+                    // trees and blobs have no history to share
+                    None if visited.len() == 1 => found = true,
+                    None => {}
+                }
+            }
+            if !found {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn parse_fetch(&self, arguments: &[String]) -> Result<FetchArgs, ServerError> {
+        let parse_id = |id: &str| unhex_with(id, self.algorithm).ok_or_else(|| ServerError::InvalidRequest(format!("invalid object id {}", id)));
+        let mut args = FetchArgs::default();
+        for arg in arguments {
+            let (key, value) = arg.split_once(' ').unwrap_or((arg, ""));
+            match key {
+                "want" => {
+                    let id = parse_id(value)?;
+                    if !self.refs.iter().any(|r| r.id == id || r.peeled == Some(id)) {
+                        return Err(ServerError::NotOurRef(value.to_owned()));
+                    }
+                    args.wants.push(id);
+                }
+                "have" => args.haves.push(parse_id(value)?),
+                "shallow" => {
+                    args.shallow.insert(parse_id(value)?);
+                }
+                "deepen" => {
+                    let depth = value.parse().ok().filter(|d| *d > 0)
+                        .ok_or_else(|| ServerError::InvalidRequest(arg.clone()))?;
+                    args.deepen = Some(depth);
+                }
+                "deepen-relative" => args.deepen_relative = true,
+                "deepen-since" => {
+                    let since = value.parse().map_err(|_| ServerError::InvalidRequest(arg.clone()))?;
+                    args.deepen_since = Some(since);
+                }
+                "deepen-not" => {
+                    let id = ["", "refs/heads/", "refs/tags/"].iter()
+                        .find_map(|prefix| self.refs.iter().find(|r| r.name == format!("{}{}", prefix, value)))
+                        .map(|r| r.peeled.unwrap_or(r.id))
+                        .ok_or_else(|| ServerError::InvalidRequest(format!("unknown ref {}", value)))?;
+                    args.deepen_not.push(id);
+                }
+                "filter" => args.filter = Some(Filter::parse(value)?),
+                "done" => args.done = true,
+                "ofs-delta" => args.ofs_delta = true,
+                "include-tag" => args.include_tag = true,
+                "no-progress" => args.no_progress = true,
+                // thin packs are never sent, which is allowed
+                "thin-pack" => {}
+                _ => return Err(ServerError::UnsupportedArgument(arg.clone())),
+            }
+        }
+        Ok(args)
+    }
+
+    /// Collect objects the client needs, and return them with the new shallow boundary
+    #[allow(clippy::type_complexity)]
+    fn pack_objects(&self, args: &FetchArgs, common: &[ObjectId]) -> Result<(PackWriter, Vec<ObjectId>, Vec<ObjectId>), ServerError> {
+        let mut writer = PackWriter::new().algorithm(self.algorithm).window(if args.ofs_delta { 10 } else { 0 });
+
+        let mut known = Known::new(&self.store, common, &args.shallow)?;
+
+        let mut commits = Vec::new();
+        let mut trees = Vec::new();
+        let mut blobs = Vec::new();
+        for want in args.wants.iter() {
+            let mut id = *want;
+            loop {
+                let object = self.object(&id)?;
+                match object.object_type {
+                    ObjectType::Tag => {
+                        writer.add(ObjectType::Tag, object.data.clone());
+                        id = object.tag()?.object;
+                    }
+                    ObjectType::Commit => break commits.push(id),
+                    ObjectType::Tree => break trees.push((id, String::new(), 0)),
+                    _ => break blobs.push(id),
+                }
+            }
+        }
+
+        // commits excluded by deepen-not
+        let mut excluded = HashSet::new();
+        let mut pending = args.deepen_not.clone();
+        while let Some(id) = pending.pop() {
+            if excluded.insert(id) {
+                if let Some(commit) = self.store.object(&id).map(|o| o.commit()).transpose()? {
+                    pending.extend(commit.parents);
+                }
+            }
+        }
+        let is_excluded = |id: &ObjectId| -> Result<bool, ServerError> {
+            if excluded.contains(id) {
+                return Ok(true);
+            }
+            match (args.deepen_since, self.store.object(id)) {
+                (Some(since), Some(object)) => Ok(object.commit()?.committer.time < since),
+                _ => Ok(false),
+            }
+        };
+        let limited = args.deepen.is_some() || args.deepen_since.is_some() || !args.deepen_not.is_empty();
+
+        // walk commits breadth first to count depth, which is unlimited before
+        // reaching shallow commits of the client with deepen-relative
+        let initial = if args.deepen_relative { None } else { Some(1) };
+        let mut queue: VecDeque<(ObjectId, Option<usize>)> = commits.iter().map(|id| (*id, initial)).collect();
+        let mut visited = HashSet::new();
+        let mut edges = HashSet::new();
+        let mut shallow = Vec::new();
+        let mut unshallow = Vec::new();
+        let mut sent = HashSet::new();
+        while let Some((id, mut depth)) = queue.pop_front() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let object = match self.store.object(&id) {
+                Some(object) => object,
+                // parent behind shallow boundary of this repository
+                None => continue,
+            };
+            let commit = object.commit()?;
+            if args.deepen_relative && args.shallow.contains(&id) {
+                depth = Some(0);
+            }
+            if known.contains(&id, commit.committer.time)? {
+                edges.insert(commit.tree);
+                // deepening needs to walk across commits the client has
+                if !limited {
+                    continue;
+                }
+            } else {
+                writer.add(ObjectType::Commit, object.data.clone());
+                trees.push((commit.tree, String::new(), 0));
+                sent.insert(id);
+            }
+
+            if commit.parents.is_empty() {
+                continue;
+            }
+            let mut cut = matches!((args.deepen, depth), (Some(deepen), Some(depth)) if depth >= deepen);
+            for parent in commit.parents.iter() {
+                cut = cut || is_excluded(parent)?;
+            }
+            if cut {
+                shallow.push(id);
+                continue;
+            }
+            if args.shallow.contains(&id) {
+                if !limited {
+                    continue;
+                }
+                unshallow.push(id);
+            }
+            queue.extend(commit.parents.iter().map(|p| (*p, depth.map(|d| d + 1))));
+        }
+
+        // objects reachable from commits the client has are not sent
+        let mut seen = HashSet::new();
+        let mut pending: Vec<ObjectId> = edges.into_iter().collect();
+        while let Some(id) = pending.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            if let Some(object) = self.store.object(&id).filter(|o| o.object_type == ObjectType::Tree) {
+                pending.extend(object.tree()?.entries.into_iter().filter(|e| !e.is_gitlink()).map(|e| e.id));
+            }
+        }
+
+        while let Some((id, path, depth)) = trees.pop() {
+            if let Some(Filter::TreeDepth(limit)) = args.filter {
+                if depth >= limit {
+                    continue;
+                }
+            }
+            if !seen.insert(id) {
+                continue;
+            }
+            let object = self.object(&id)?;
+            let tree = object.tree()?;
+            writer.add_with_path(ObjectType::Tree, object.data.clone(), &path);
+            for entry in tree.entries.into_iter().rev() {
+                let name = String::from_utf8_lossy(&entry.name);
+                let path = if path.is_empty() { name.into_owned() } else { format!("{}/{}", path, name) };
+                if entry.is_tree() {
+                    trees.push((entry.id, path, depth + 1));
+                } else if entry.is_blob() && !seen.contains(&entry.id) {
+                    self.add_blob(&mut writer, &entry, &path, depth + 1, args.filter.as_ref(), &mut seen)?;
+                }
+            }
+        }
+        for id in blobs {
+            let object = self.object(&id)?;
+            writer.add(ObjectType::Blob, object.data.clone());
+        }
+
+        if args.include_tag {
+            for r in self.refs.iter().filter(|r| r.name.starts_with("refs/tags/")) {
+                if let Some(peeled) = r.peeled {
+                    if sent.contains(&peeled) && !writer.contains(&r.id) {
+                        writer.add(ObjectType::Tag, self.object(&r.id)?.data.clone());
+                    }
+                }
+            }
+        }
+        Ok((writer, shallow, unshallow))
+    }
+
+    fn add_blob(&self, writer: &mut PackWriter, entry: &TreeEntry, path: &str, depth: usize, filter: Option<&Filter>, seen: &mut HashSet<ObjectId>) -> Result<(), ServerError> {
+        let object = self.object(&entry.id)?;
+        let skip = match filter {
+            Some(Filter::BlobNone) => true,
+            Some(Filter::BlobLimit(limit)) => object.data.len() > *limit,
+            Some(Filter::TreeDepth(limit)) => depth >= *limit,
+            None => false,
+        };
+        if !skip {
+            seen.insert(entry.id);
+            writer.add_with_path(ObjectType::Blob, object.data.clone(), path);
+        }
+        Ok(())
+    }
+
+    fn object(&self, id: &ObjectId) -> Result<Cow<'_, Object>, ServerError> {
+        self.store.object(id).ok_or_else(|| ServerError::MissingObject(hex(id)))
+    }
+}
+
+/// Commits the client has: common commits and their ancestors, without parents of its shallow commits
+///
+/// Ancestors are walked lazily in date order, only down to the date of the commits asked about,
+/// so that a fetch of recent commits does not walk the whole history.
+struct Known<'a, S: ObjectStore> {
+    store: &'a S,
+    shallow: &'a HashSet<ObjectId>,
+    queue: BinaryHeap<(i64, ObjectId)>,
+    known: HashSet<ObjectId>,
+}
+
+impl<'a, S: ObjectStore> Known<'a, S> {
+    fn new(store: &'a S, common: &[ObjectId], shallow: &'a HashSet<ObjectId>) -> Result<Self, ServerError> {
+        let mut known = Self { store, shallow, queue: BinaryHeap::new(), known: HashSet::new() };
+        for id in common {
+            known.add(id)?;
+        }
+        Ok(known)
+    }
+
+    fn add(&mut self, id: &ObjectId) -> Result<(), ServerError> {
+        if self.known.insert(*id) {
+            // parents behind the shallow boundary of this repository are missing
+            if let Some(object) = self.store.object(id) {
+                self.queue.push((object.commit()?.committer.time, *id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether commit `id`, committed at `time`, is known by the client
+    ///
+    /// With clock skew an ancestor of a common commit may be missed, which only costs sending it again.
+    fn contains(&mut self, id: &ObjectId, time: i64) -> Result<bool, ServerError> {
+        while self.queue.peek().is_some_and(|(t, _)| *t >= time) && !self.known.contains(id) {
+            let (_, next) = self.queue.pop().unwrap();
+            if !self.shallow.contains(&next) {
+                let (_, parents) = object::commit_links(&self.store.object(&next).unwrap().data)?;
+                for parent in parents.iter() {
+                    self.add(parent)?;
+                }
+            }
+        }
+        Ok(self.known.contains(id))
+    }
+}
+
+fn read_request<R: Read>(reader: &mut R, algorithm: HashAlgorithm) -> Result<Request, ServerError> {
+    let mut command = None;
+    let mut arguments = Vec::new();
+    let mut in_arguments = false;
+    loop {
+        let (data, len) = io::read_pktline(reader)?;
+        match len {
+            0 if data.is_empty() => return Err(ServerError::InvalidRequest("unexpected end of request".to_owned())),
+            0 => break,
+            1 if !in_arguments => in_arguments = true,
+            1..=3 => return Err(ServerError::InvalidRequest(format!("unexpected packet {:04x}", len))),
+            _ => {
+                let line = String::from_utf8(data).map_err(|_| ServerError::InvalidRequest("invalid utf-8".to_owned()))?;
+                let line = line.strip_suffix('\n').unwrap_or(&line);
+                if in_arguments {
+                    arguments.push(line.to_owned());
+                } else if let Some(c) = line.strip_prefix("command=") {
+                    command = Some(c.to_owned());
+                } else if let Some(format) = line.strip_prefix("object-format=") {
+                    if format != algorithm.name() {
+                        return Err(ServerError::InvalidRequest(format!("unsupported object format {}", format)));
+                    }
+                }
+                // other capabilities like agent and server-option are ignored
+            }
+        }
+    }
+    Ok(Request {
+        command: command.ok_or_else(|| ServerError::InvalidRequest("missing command".to_owned()))?,
+        arguments,
+    })
+}
+
+fn peel_tag<S: ObjectStore>(store: &S, id: &ObjectId) -> Result<ObjectId, ServerError> {
+    let mut id = *id;
+    while let Some(object) = store.object(&id).filter(|o| o.object_type == ObjectType::Tag) {
+        id = object.tag()?.object;
+    }
+    Ok(id)
+}
+
+/// Decompress a gzip member
+///
+/// https://www.rfc-editor.org/rfc/rfc1952
+fn gunzip(data: &[u8]) -> Option<Vec<u8>> {
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+    const FHCRC: u8 = 2;
+
+    if data.len() < 18 || data[..3] != [0x1f, 0x8b, 8] {
+        return None;
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let len = u16::from_le_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+        pos += 2 + len;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            pos += data.get(pos..)?.iter().position(|b| *b == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    miniz_oxide::inflate::decompress_to_vec(data.get(pos..data.len().checked_sub(8)?)?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::Path;
+    use crate::client::{Message, RequestBuilder};
+    use crate::pack::ObjectType;
+    use crate::repo::{clone_bare, CloneOptions};
+    use crate::server::{HttpRequest, UploadPack};
+    use crate::testing::{commit, fixture, git, publish, serve_with, TestResponse};
+    use crate::{Client, Pack, Repository};
+
+    /// Serve `root/repo.git` with [UploadPack], reading the repository again for every request
+    fn serve(root: &Path) -> String {
+        let repo = root.join("repo.git");
+        let url = serve_with(move |req| {
+            let server = UploadPack::from_repository(&Repository::open(&repo).unwrap()).unwrap();
+            let response = server.handle_http(&HttpRequest {
+                method: &req.method,
+                path: req.path.strip_prefix("/repo.git").unwrap_or(&req.path),
+                query: &req.query,
+                git_protocol: req.header("Git-Protocol"),
+                content_encoding: req.header("Content-Encoding"),
+                body: &req.body,
+            });
+            TestResponse {
+                status: response.status,
+                headers: response.headers.into_iter().map(|(k, v)| (k.to_owned(), v)).collect(),
+                body: response.body,
+            }
+        });
+        format!("{}/repo.git", url)
+    }
+
+    fn setup(root: &Path) -> String {
+        fixture(root);
+        git(&root.join("repo.git"), &["repack", "-q", "-a", "-d"]);
+        serve(root)
+    }
+
+    #[test]
+    fn test_ls_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = setup(dir.path());
+        let repo = dir.path().join("repo.git");
+
+        let refs = Client::new(&url).ls_refs::<&str>(&[]).unwrap();
+        let names: Vec<_> = refs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["HEAD", "refs/heads/master", "refs/tags/v1"]);
+        assert_eq!(refs[0].symref_target.as_deref(), Some("refs/heads/master"));
+        assert_eq!(refs[2].id.to_string(), git(&repo, &["rev-parse", "v1"]).trim());
+        assert_eq!(refs[2].peeled.map(|id| id.to_string()).as_deref(), Some(git(&repo, &["rev-parse", "v1^{}"]).trim()));
+
+        let tags = Client::new(&url).ls_refs(&["refs/tags/"]).unwrap();
+        assert_eq!(tags.len(), 1);
+
+        let response = ureq::get(&format!("{}/info/refs?service=git-upload-pack", url)).call().unwrap_err();
+        assert!(matches!(response, ureq::Error::Status(400, _)));
+    }
+
+    #[test]
+    fn test_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = setup(dir.path());
+
+        let repo = clone_bare(&url, dir.path().join("clone.git"), &CloneOptions::default()).unwrap();
+        git(repo.path(), &["fsck", "--full", "--strict"]);
+        assert_eq!(git(repo.path(), &["rev-parse", "HEAD"]), git(&dir.path().join("repo.git"), &["rev-parse", "HEAD"]));
+
+        let options = CloneOptions { depth: Some(1), ..Default::default() };
+        let shallow = clone_bare(&url, dir.path().join("shallow.git"), &options).unwrap();
+        assert_eq!(shallow.shallow().unwrap(), vec![git(repo.path(), &["rev-parse", "HEAD"]).trim().parse().unwrap()]);
+        git(shallow.path(), &["fsck"]);
+
+        // filter and errors
+        let client = Client::new(&url);
+        let head = client.ls_ref("HEAD").unwrap();
+        let request = RequestBuilder::new(true)
+            .command("fetch")
+            .want(&head)
+            .argument("filter blob:none")
+            .argument("no-progress")
+            .argument("done")
+            .build();
+        let mut raw = Vec::new();
+        let mut packets = client.request(request).unwrap();
+        for msg in &mut packets {
+            assert!(!matches!(msg, Message::PackProgress(_)));
+            if let Message::PackData(mut data) = msg {
+                raw.append(&mut data);
+            }
+        }
+        packets.finish().unwrap();
+        let pack = Pack::from_reader(&mut Cursor::new(raw)).unwrap();
+        assert!(pack.objects.values().all(|o| o.object_type != ObjectType::Blob));
+        assert_eq!(pack.objects.values().filter(|o| o.object_type == ObjectType::Commit).count(), 2);
+
+        let request = RequestBuilder::new(true)
+            .command("fetch")
+            .want(&"9192b5e5f2941fd76aa5a08043dc8aa6a31831a2".parse().unwrap())
+            .argument("done")
+            .build();
+        let mut packets = client.request(request).unwrap();
+        let messages: Vec<_> = packets.by_ref().collect();
+        packets.finish().unwrap();
+        assert_eq!(messages, vec![Message::Normal(b"ERR not our ref 9192b5e5f2941fd76aa5a08043dc8aa6a31831a2\n".to_vec())]);
+    }
+
+    #[test]
+    fn test_git_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = setup(dir.path());
+        let root = dir.path();
+
+        git(root, &["-c", "protocol.version=2", "clone", "-q", &url, "cli"]);
+        let cli = root.join("cli");
+        assert_eq!(std::fs::read_to_string(cli.join("album/b.toml")).unwrap(), "title = \"b\"\n");
+
+        // negotiate with haves
+        let id = commit(&root.join("work"), &[("album/c.toml", "title = \"c\"\n")], "Add c");
+        publish(root);
+        git(&root.join("repo.git"), &["repack", "-q", "-a", "-d"]);
+        git(&cli, &["-c", "protocol.version=2", "pull", "-q", "--ff-only"]);
+        assert_eq!(git(&cli, &["rev-parse", "HEAD"]).trim(), id);
+        git(&cli, &["fsck", "--full", "--strict"]);
+
+        git(root, &["-c", "protocol.version=2", "clone", "-q", "--depth", "1", &url, "shallow"]);
+        git(&root.join("shallow"), &["-c", "protocol.version=2", "fetch", "-q", "--deepen", "1"]);
+        assert_eq!(git(&root.join("shallow"), &["rev-list", "--count", "HEAD"]).trim(), "2");
+        git(root, &["-c", "protocol.version=2", "clone", "-q", "--shallow-exclude", "v1", &url, "exclude"]);
+        assert_eq!(git(&root.join("exclude"), &["rev-list", "--count", "HEAD"]).trim(), "1");
+        git(root, &["-c", "protocol.version=2", "clone", "-q", "--shallow-since", "1615876429", &url, "since"]);
+        assert_eq!(git(&root.join("since"), &["rev-list", "--count", "HEAD"]).trim(), "3");
+
+        git(&root.join("shallow"), &["-c", "protocol.version=2", "fetch", "-q", "--unshallow"]);
+        assert_eq!(git(&root.join("shallow"), &["rev-list", "--count", "HEAD"]).trim(), "3");
+        git(&root.join("shallow"), &["fsck", "--full"]);
+    }
+
+    #[test]
+    fn test_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let repo = dir.path().join("repo.git");
+        let tree = git(&repo, &["hash-object", "-t", "tree", "-w", "/dev/null"]);
+        let orphan = git(&repo, &["commit-tree", "-m", "Orphan", tree.trim()]);
+        git(&repo, &["update-ref", "refs/heads/orphan", orphan.trim()]);
+        git(&repo, &["repack", "-q", "-a", "-d"]);
+        let server = UploadPack::from_repository(&Repository::open(&repo).unwrap()).unwrap();
+
+        let head = git(&repo, &["rev-parse", "HEAD"]).trim().parse().unwrap();
+        let parent = git(&repo, &["rev-parse", "HEAD~"]).trim().parse().unwrap();
+        let orphan = orphan.trim().parse().unwrap();
+        let negotiate = |wants: &[&_]| {
+            let mut request = RequestBuilder::new(true).command("fetch");
+            for want in wants {
+                request = request.want(want);
+            }
+            let mut response = Vec::new();
+            server.serve(&mut Cursor::new(request.have(&parent).build()), &mut response).unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        };
+        let response = negotiate(&[&head]);
+        assert!(response.contains("ready\n") && response.contains("packfile\n"));
+        // the orphan has no common ancestor, the client must send more haves
+        let response = negotiate(&[&head, &orphan]);
+        assert!(response.contains(&format!("ACK {}", parent)));
+        assert!(!response.contains("ready\n") && !response.contains("packfile\n"));
+    }
+
+    #[test]
+    fn test_gzip_request() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let repo = dir.path().join("repo.git");
+        git(&repo, &["repack", "-q", "-a", "-d"]);
+        let server = UploadPack::from_repository(&Repository::open(&repo).unwrap()).unwrap();
+
+        let body = RequestBuilder::new(true).command("ls-refs").build();
+        let mut gzip = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        gzip.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(&body, 6));
+        gzip.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        gzip.extend_from_slice(&(body.len() as u32).to_le_bytes());
+
+        let request = |content_encoding, body| HttpRequest {
+            method: "POST",
+            path: "/git-upload-pack",
+            query: "",
+            git_protocol: Some("version=2"),
+            content_encoding,
+            body,
+        };
+        let plain = server.handle_http(&request(None, &body));
+        let compressed = server.handle_http(&request(Some("gzip"), &gzip));
+        assert_eq!(compressed.status, 200);
+        assert_eq!(compressed.body, plain.body);
+        assert_eq!(server.handle_http(&request(Some("gzip"), &body)).status, 400);
+    }
+}
@@ -0,0 +1,207 @@
+//! Helpers for tests which need a real git repository or a smart HTTP server.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+/// Run git in `dir` with fixed identity and dates, and return its stdout
+pub(crate) fn git(dir: &Path, args: &[&str]) -> String {
+    git_at(dir, 1615876429, args)
+}
+
+/// Run git in `dir` with fixed identity and author and committer date `time`
+pub(crate) fn git_at(dir: &Path, time: i64, args: &[&str]) -> String {
+    let date = format!("{} +0800", time);
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(["-c", "user.name=anni", "-c", "user.email=anni@example.com", "-c", "init.defaultBranch=master"])
+        .args(args)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_AUTHOR_DATE", &date)
+        .env("GIT_COMMITTER_DATE", &date)
+        .output()
+        .expect("failed to run git");
+    assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Write `files` into work tree `work`, commit them and return the commit id
+pub(crate) fn commit(work: &Path, files: &[(&str, &str)], message: &str) -> String {
+    for (path, content) in files {
+        let path = work.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+    git(work, &["add", "-A"]);
+    git(work, &["commit", "-q", "-m", message]);
+    git(work, &["rev-parse", "HEAD"]).trim().to_owned()
+}
+
+/// Pack objects reachable from `revs` (one per line) in repository `dir` with `git pack-objects`
+pub(crate) fn pack_objects(dir: &Path, revs: &str) -> Vec<u8> {
+    let mut child = Command::new("git")
+        .current_dir(dir)
+        .args(["pack-objects", "--stdout", "--revs", "--delta-base-offset"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to run git pack-objects");
+    child.stdin.take().unwrap().write_all(format!("{}\n", revs).as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    output.stdout
+}
+
+/// Create a work tree at `root/work` with two commits and a tag,
+/// and publish it as bare repository `root/repo.git`
+pub(crate) fn fixture(root: &Path) {
+    let work = root.join("work");
+    std::fs::create_dir_all(&work).unwrap();
+    git(&work, &["init", "-q"]);
+    commit(&work, &[("README.md", "# Test\n"), ("album/a.toml", "title = \"a\"\n")], "Initial commit");
+    commit(&work, &[("album/b.toml", "title = \"b\"\n"), ("README.md", "# Test\n\nMore\n")], "Add b");
+    git(&work, &["tag", "-a", "v1", "-m", "v1"]);
+    git(root, &["clone", "-q", "--bare", "work", "repo.git"]);
+}
+
+/// Publish new commits of `root/work` to `root/repo.git`
+pub(crate) fn publish(root: &Path) {
+    git(&root.join("work"), &["push", "-q", "--force", "--tags", "../repo.git", "+refs/heads/*:refs/heads/*"]);
+}
+
+pub(crate) struct TestRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub(crate) struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Serve HTTP requests with `handler` on a random local port, and return `http://127.0.0.1:port`
+pub(crate) fn serve_with<F>(handler: F) -> String
+    where F: Fn(TestRequest) -> TestResponse + Send + Sync + 'static {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handler = Arc::new(handler);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let handler = handler.clone();
+            std::thread::spawn(move || handle(stream, handler.as_ref()));
+        }
+    });
+    url
+}
+
+/// Serve repositories under `root` with `git http-backend`
+pub(crate) fn serve(root: &Path) -> String {
+    let root = root.to_owned();
+    serve_with(move |req| http_backend(&root, req))
+}
+
+fn handle<F: Fn(TestRequest) -> TestResponse>(stream: TcpStream, handler: &F) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let target = parts.next().unwrap_or_default().to_owned();
+    let (path, query) = match target.find('?') {
+        Some(i) => (target[..i].to_owned(), target[i + 1..].to_owned()),
+        None => (target, String::new()),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        let l = line.trim_end();
+        if l.is_empty() {
+            break;
+        }
+        if let Some(i) = l.find(':') {
+            headers.push((l[..i].trim().to_owned(), l[i + 1..].trim().to_owned()));
+        }
+    }
+    let mut request = TestRequest { method, path, query, headers, body: Vec::new() };
+    if let Some(len) = request.header("Content-Length") {
+        let len: u64 = len.parse().unwrap();
+        reader.take(len).read_to_end(&mut request.body).unwrap();
+    } else if request.header("Transfer-Encoding") == Some("chunked") {
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            let len = u64::from_str_radix(line.trim_end(), 16).unwrap();
+            (&mut reader).take(len).read_to_end(&mut request.body).unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if len == 0 {
+                break;
+            }
+        }
+    }
+
+    let response = handler(request);
+    let mut stream = stream;
+    let mut out = format!("HTTP/1.1 {} Status\r\n", response.status);
+    for (k, v) in response.headers.iter() {
+        out.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    out.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", response.body.len()));
+    stream.write_all(out.as_bytes()).unwrap();
+    stream.write_all(&response.body).unwrap();
+}
+
+/// Run `git http-backend` for repositories under `root`
+pub(crate) fn http_backend(root: &Path, request: TestRequest) -> TestResponse {
+    let mut child = Command::new("git")
+        .arg("http-backend")
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_PROJECT_ROOT", root)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        .env("REQUEST_METHOD", &request.method)
+        .env("PATH_INFO", &request.path)
+        .env("QUERY_STRING", &request.query)
+        .env("CONTENT_TYPE", request.header("Content-Type").unwrap_or_default())
+        .env("CONTENT_LENGTH", request.body.len().to_string())
+        .env("GIT_PROTOCOL", request.header("Git-Protocol").unwrap_or_default())
+        .env("REMOTE_ADDR", "127.0.0.1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to run git http-backend");
+    child.stdin.take().unwrap().write_all(&request.body).unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    let split = output.stdout.windows(4).position(|w| w == b"\r\n\r\n").expect("invalid cgi response");
+    let mut status = 200;
+    let mut headers = Vec::new();
+    for l in String::from_utf8_lossy(&output.stdout[..split]).split("\r\n") {
+        if let Some(i) = l.find(':') {
+            let (k, v) = (l[..i].trim(), l[i + 1..].trim());
+            if k.eq_ignore_ascii_case("Status") {
+                status = v[..3].parse().unwrap();
+            } else {
+                headers.push((k.to_owned(), v.to_owned()));
+            }
+        }
+    }
+    TestResponse { status, headers, body: output.stdout[split + 4..].to_vec() }
+}
@@ -1,8 +1,7 @@
-use sha1::Digest;
-use std::io::Write;
-use std::convert::TryInto;
+use crate::oid::{HashAlgorithm, ObjectId};
 
-pub(crate) fn hex(input: &[u8]) -> String {
+pub(crate) fn hex<T: AsRef<[u8]> + ?Sized>(input: &T) -> String {
+    let input = input.as_ref();
     let mut result = String::with_capacity(input.len() * 2);
     for v in input {
         result.push_str(&format!("{:02x}", v));
@@ -10,19 +9,38 @@ pub(crate) fn hex(input: &[u8]) -> String {
     result
 }
 
-pub(crate) fn git_sha1(prefix: &str, input: &[u8]) -> [u8; 20] {
-    let mut hasher = sha1::Sha1::new();
-    hasher.write_all(prefix.as_bytes()).unwrap();
-    hasher.write_all(b" ").unwrap();
-    hasher.write_all(format!("{}", input.len()).as_bytes()).unwrap();
-    hasher.write_all(&[0]).unwrap();
-    hasher.write_all(input).unwrap();
-    hasher.finalize().try_into().unwrap()
+/// Parse a 40 or 64 characters hex string into object id
+pub(crate) fn unhex(input: &str) -> Option<ObjectId> {
+    if (input.len() != 40 && input.len() != 64) || !input.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut result = [0u8; 32];
+    for (i, r) in result[..input.len() / 2].iter_mut().enumerate() {
+        *r = u8::from_str_radix(&input[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    ObjectId::from_bytes(&result[..input.len() / 2])
+}
+
+/// Parse a hex string into object id of `algorithm`, rejecting ids of the other length
+pub(crate) fn unhex_with(input: &str, algorithm: HashAlgorithm) -> Option<ObjectId> {
+    unhex(input).filter(|id| id.algorithm() == algorithm)
+}
+
+/// Id of object with type `prefix` and `input` as data
+pub(crate) fn git_hash(algorithm: HashAlgorithm, prefix: &str, input: &[u8]) -> ObjectId {
+    let mut hasher = algorithm.hasher();
+    hasher.update(prefix.as_bytes());
+    hasher.update(b" ");
+    hasher.update(format!("{}", input.len()).as_bytes());
+    hasher.update(&[0]);
+    hasher.update(input);
+    hasher.finalize()
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::{hex, git_sha1};
+    use crate::oid::HashAlgorithm;
+    use crate::utils::{hex, unhex, unhex_with, git_hash};
 
     #[test]
     fn test_hex() {
@@ -30,7 +48,22 @@ mod tests {
     }
 
     #[test]
-    fn test_git_sha1() {
-        assert_eq!(git_sha1("blob", &[]), [0xe6, 0x9d, 0xe2, 0x9b, 0xb2, 0xd1, 0xd6, 0x43, 0x4b, 0x8b, 0x29, 0xae, 0x77, 0x5a, 0xd8, 0xc2, 0xe4, 0x8c, 0x53, 0x91]);
+    fn test_unhex() {
+        let id = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+        assert_eq!(hex(&unhex(id).unwrap()), id);
+        let id = "473a0f4c3be8a93681a267e3b1e9a7dcda1185436fe141f7749120a303721813";
+        assert_eq!(unhex(id).unwrap().algorithm(), HashAlgorithm::Sha256);
+        assert_eq!(hex(&unhex(id).unwrap()), id);
+        assert_eq!(unhex("e69de29b"), None);
+        assert_eq!(unhex("x69de29bb2d1d6434b8b29ae775ad8c2e48c5391"), None);
+        assert_eq!(unhex("+69de29bb2d1d6434b8b29ae775ad8c2e48c5391"), None);
+        assert_eq!(unhex_with(id, HashAlgorithm::Sha1), None);
+        assert!(unhex_with(id, HashAlgorithm::Sha256).is_some());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_git_hash() {
+        assert_eq!(git_hash(HashAlgorithm::Sha1, "blob", &[]).as_bytes(), [0xe6, 0x9d, 0xe2, 0x9b, 0xb2, 0xd1, 0xd6, 0x43, 0x4b, 0x8b, 0x29, 0xae, 0x77, 0x5a, 0xd8, 0xc2, 0xe4, 0x8c, 0x53, 0x91]);
+        assert_eq!(hex(&git_hash(HashAlgorithm::Sha256, "blob", &[])), "473a0f4c3be8a93681a267e3b1e9a7dcda1185436fe141f7749120a303721813");
+    }
+}
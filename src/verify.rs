@@ -0,0 +1,464 @@
+//! Integrity check of pack files, like `git verify-pack -v` and the pack part of `git fsck`.
+//!
+//! Unlike [Pack::from_reader](crate::Pack::from_reader), which stops at the first error,
+//! [verify_pack] reads as much as possible and collects every problem in a [VerifyReport].
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+use miniz_oxide::inflate::TINFLStatus;
+use miniz_oxide::inflate::core::{decompress, DecompressorOxide, inflate_flags};
+use thiserror::Error;
+use crate::object::{Commit, ObjectError, Tag, Tree};
+use crate::oid::{HashAlgorithm, ObjectId};
+use crate::pack::{apply_delta, ofs_from_reader, vint_from_reader, ObjectType};
+use crate::utils::git_hash;
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error("invalid pack signature")]
+    InvalidSignature,
+    #[error("unsupported pack version {0}")]
+    UnsupportedVersion(u32),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// A problem found by [verify_pack]
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum Problem {
+    #[error("invalid entry at offset {0}")]
+    InvalidEntry(usize),
+    #[error("header declares {expected} objects, found {found}")]
+    CountMismatch { expected: u32, found: u32 },
+    #[error("pack checksum mismatch, expected {expected}, got {actual}")]
+    ChecksumMismatch { expected: ObjectId, actual: ObjectId },
+    #[error("pack checksum is missing")]
+    MissingChecksum,
+    #[error("{0} bytes of garbage after pack checksum")]
+    TrailingData(usize),
+    #[error("base of delta at offset {0} not found")]
+    MissingBase(usize),
+    #[error("invalid delta at offset {0}")]
+    InvalidDelta(usize),
+    #[error("duplicate object {0}")]
+    DuplicateObject(ObjectId),
+    #[error("invalid object {0}: {1}")]
+    InvalidObject(ObjectId, ObjectError),
+}
+
+/// An entry of a verified pack, like a line of `git verify-pack -v`
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryReport {
+    pub offset: usize,
+    /// `None` if the entry is a delta which could not be resolved
+    pub id: Option<ObjectId>,
+    /// Type of the resolved object, or type of the entry if it could not be resolved
+    pub object_type: ObjectType,
+    /// Size of object data
+    pub size: usize,
+    /// Size of the entry in pack, including its header
+    pub packed_size: usize,
+    /// CRC32 of the raw entry, as stored in pack index
+    pub crc32: u32,
+    /// Length of delta chain, 0 for whole objects
+    pub depth: usize,
+    /// Offset of delta base in pack
+    pub base: Option<usize>,
+}
+
+/// Result of [verify_pack]
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyReport {
+    pub version: u32,
+    /// Number of objects declared in header
+    pub count: u32,
+    /// Trailing checksum stored in pack
+    pub checksum: Option<ObjectId>,
+    pub entries: Vec<EntryReport>,
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Number of deltas by chain length, like the histogram of `git verify-pack -v`
+    pub fn chain_lengths(&self) -> BTreeMap<usize, usize> {
+        let mut result = BTreeMap::new();
+        for entry in self.entries.iter().filter(|e| e.depth > 0) {
+            *result.entry(entry.depth).or_insert(0) += 1;
+        }
+        result
+    }
+}
+
+/// An entry being verified, with its data before delta resolution
+struct Entry {
+    offset: usize,
+    end: usize,
+    object_type: ObjectType,
+    data: Vec<u8>,
+}
+
+/// Verify pack read from `reader` till its end, whose objects are hashed with `algorithm`
+///
+/// Only an unreadable header is an error, other problems are listed in [VerifyReport::problems].
+pub fn verify_pack<R: Read>(reader: &mut R, algorithm: HashAlgorithm) -> Result<VerifyReport, VerifyError> {
+    let mut raw = Vec::new();
+    reader.read_to_end(&mut raw)?;
+    verify_pack_slice(&raw, algorithm)
+}
+
+/// Verify pack file at `path`, which is memory-mapped instead of read into memory
+///
+/// # Safety
+///
+/// The file must not be modified while it is verified, see [crate::packfile::PackFile::map].
+pub unsafe fn verify_pack_file<P: AsRef<Path>>(path: P, algorithm: HashAlgorithm) -> Result<VerifyReport, VerifyError> {
+    verify_pack_slice(&crate::packfile::map(path)?, algorithm)
+}
+
+/// Verify pack in `raw`, like [verify_pack]
+pub fn verify_pack_slice(raw: &[u8], algorithm: HashAlgorithm) -> Result<VerifyReport, VerifyError> {
+    if raw.len() < 12 || &raw[..4] != b"PACK" {
+        return Err(VerifyError::InvalidSignature);
+    }
+    let version = u32::from_be_bytes([raw[4], raw[5], raw[6], raw[7]]);
+    if version != 2 && version != 3 {
+        return Err(VerifyError::UnsupportedVersion(version));
+    }
+    let count = u32::from_be_bytes([raw[8], raw[9], raw[10], raw[11]]);
+    let trailer = algorithm.size();
+
+    let mut problems = Vec::new();
+    // every entry takes at least a header byte and some zlib data, so a bogus count is not allocated
+    let mut entries = Vec::with_capacity((count as usize).min(raw.len() / 2));
+    let mut decompressor = Box::<DecompressorOxide>::default();
+    let mut offset = 12;
+    let mut broken = false;
+    while entries.len() < count as usize {
+        if raw.len() - offset == trailer {
+            problems.push(Problem::CountMismatch { expected: count, found: entries.len() as u32 });
+            break;
+        }
+        match read_entry(raw, offset, algorithm, &mut decompressor) {
+            Some(entry) => {
+                offset = entry.end;
+                entries.push(entry);
+            }
+            None => {
+                problems.push(Problem::InvalidEntry(offset));
+                broken = true;
+                break;
+            }
+        }
+    }
+
+    // more entries than declared, if they end right before a checksum
+    if !broken && entries.len() == count as usize && raw.len() > offset + trailer {
+        let mut extra = Vec::new();
+        let mut end = offset;
+        while raw.len() > end + trailer {
+            match read_entry(raw, end, algorithm, &mut decompressor) {
+                Some(entry) => {
+                    end = entry.end;
+                    extra.push(entry);
+                }
+                None => break,
+            }
+        }
+        if raw.len() == end + trailer {
+            problems.push(Problem::CountMismatch { expected: count, found: (entries.len() + extra.len()) as u32 });
+            entries.extend(extra);
+            offset = end;
+        }
+    }
+
+    // checksum is expected after the last entry, or at the end if entries could not be read
+    let end = if broken { raw.len().saturating_sub(trailer).max(12) } else { offset };
+    let checksum = raw.get(end..end + trailer).and_then(ObjectId::from_bytes);
+    match checksum {
+        Some(expected) => {
+            let actual = algorithm.digest(&raw[..end]);
+            if actual != expected {
+                problems.push(Problem::ChecksumMismatch { expected, actual });
+            }
+            if !broken && raw.len() > end + trailer {
+                problems.push(Problem::TrailingData(raw.len() - end - trailer));
+            }
+        }
+        None => problems.push(Problem::MissingChecksum),
+    }
+
+    let entries = resolve(raw, entries, algorithm, &mut problems);
+    Ok(VerifyReport { version, count, checksum, entries, problems })
+}
+
+/// Keep the complete entries of a pack which was partially received or written
+///
+/// The pack is cut after the last entry which could be read, and gets a header with
+/// the count of these entries and a new checksum. `None` if no entry is complete.
+pub fn salvage_pack(raw: &[u8], algorithm: HashAlgorithm) -> Option<Vec<u8>> {
+    let report = verify_pack_slice(raw, algorithm).ok()?;
+    let end = report.entries.iter().map(|e| e.offset + e.packed_size).max()?;
+    let mut salvaged = raw[..end].to_vec();
+    salvaged[8..12].copy_from_slice(&(report.entries.len() as u32).to_be_bytes());
+    let checksum = algorithm.digest(&salvaged);
+    salvaged.extend_from_slice(checksum.as_bytes());
+    Some(salvaged)
+}
+
+/// Decode the entry at `offset`, `None` if it is invalid
+fn read_entry(raw: &[u8], offset: usize, algorithm: HashAlgorithm, decompressor: &mut DecompressorOxide) -> Option<Entry> {
+    let mut reader = raw.get(offset..)?;
+    let (object_type, size, used) = vint_from_reader(&mut reader).ok()?;
+    let mut start = offset + used;
+    let object_type = match object_type {
+        1 => ObjectType::Commit,
+        2 => ObjectType::Tree,
+        3 => ObjectType::Blob,
+        4 => ObjectType::Tag,
+        6 => {
+            let (distance, used) = ofs_from_reader(&mut reader).ok()?;
+            start += used;
+            ObjectType::OfsDelta(distance)
+        }
+        7 => {
+            let id = ObjectId::from_bytes(raw.get(start..start + algorithm.size())?)?;
+            start += algorithm.size();
+            ObjectType::RefDelta(id)
+        }
+        _ => return None,
+    };
+
+    // the declared size may be anything in a corrupted pack
+    if size > raw.len().saturating_mul(1032) {
+        return None;
+    }
+    let mut data = vec![0; size];
+    decompressor.init();
+    let flags = inflate_flags::TINFL_FLAG_PARSE_ZLIB_HEADER
+        | inflate_flags::TINFL_FLAG_COMPUTE_ADLER32
+        | inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+    let (status, consumed, written) = decompress(decompressor, &raw[start..], &mut data, 0, flags);
+    if status != TINFLStatus::Done || written != size {
+        return None;
+    }
+    Some(Entry { offset, end: start + consumed, object_type, data })
+}
+
+/// Type and data of a resolved entry, shared by the deltas based on it
+type Resolved = Rc<(ObjectType, Vec<u8>)>;
+
+/// Resolve deltas of `entries`, check objects and build their reports
+///
+/// Deltas are indexed by their base, and resolved in a walk from each whole object,
+/// so that every entry is inflated and hashed once.
+fn resolve(raw: &[u8], entries: Vec<Entry>, algorithm: HashAlgorithm, problems: &mut Vec<Problem>) -> Vec<EntryReport> {
+    let mut reports: Vec<EntryReport> = entries.iter()
+        .map(|e| EntryReport {
+            offset: e.offset,
+            id: None,
+            object_type: e.object_type.clone(),
+            size: e.data.len(),
+            packed_size: e.end - e.offset,
+            crc32: crc32fast::hash(&raw[e.offset..e.end]),
+            depth: 0,
+            base: None,
+        })
+        .collect();
+    let mut by_offset: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut by_id: HashMap<ObjectId, Vec<usize>> = HashMap::new();
+    for (i, entry) in entries.iter().enumerate() {
+        match &entry.object_type {
+            ObjectType::OfsDelta(distance) => {
+                if let Some(base) = entry.offset.checked_sub(*distance) {
+                    by_offset.entry(base).or_default().push(i);
+                }
+            }
+            ObjectType::RefDelta(id) => by_id.entry(*id).or_default().push(i),
+            _ => {}
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut done = vec![false; entries.len()];
+    for root in (0..entries.len()).filter(|i| !entries[*i].object_type.is_delta()) {
+        // (entry, index and object of its base), the data of a base is dropped once its deltas are resolved
+        let mut stack: Vec<(usize, Option<(usize, Resolved)>)> = vec![(root, None)];
+        while let Some((i, base)) = stack.pop() {
+            let entry = &entries[i];
+            if std::mem::replace(&mut done[i], true) {
+                continue;
+            }
+            let (object_type, data) = match &base {
+                None => (entry.object_type.clone(), entry.data.clone()),
+                Some((index, base)) => match apply_delta(&base.1, &entry.data) {
+                    Ok(data) => {
+                        reports[i].depth = reports[*index].depth + 1;
+                        reports[i].base = Some(entries[*index].offset);
+                        (base.0.clone(), data)
+                    }
+                    Err(_) => {
+                        problems.push(Problem::InvalidDelta(entry.offset));
+                        continue;
+                    }
+                },
+            };
+
+            let id = git_hash(algorithm, object_type.name(), &data);
+            if !seen.insert(id) {
+                problems.push(Problem::DuplicateObject(id));
+            }
+            if let Err(e) = check_object(&object_type, &data, algorithm) {
+                problems.push(Problem::InvalidObject(id, e));
+            }
+            reports[i].id = Some(id);
+            reports[i].object_type = object_type.clone();
+            reports[i].size = data.len();
+
+            let object = Rc::new((object_type, data));
+            let children = by_offset.get(&entry.offset).into_iter().chain(by_id.get(&id)).flatten();
+            stack.extend(children.filter(|c| !done[**c]).map(|c| (*c, Some((i, object.clone())))));
+        }
+    }
+
+    for (i, entry) in entries.iter().enumerate() {
+        if !done[i] {
+            problems.push(Problem::MissingBase(entry.offset));
+        }
+    }
+    reports
+}
+
+/// Check data of commit, tree and tag objects can be parsed
+fn check_object(object_type: &ObjectType, data: &[u8], algorithm: HashAlgorithm) -> Result<(), ObjectError> {
+    match object_type {
+        ObjectType::Commit => Commit::parse(data).map(|_| ()),
+        ObjectType::Tree => Tree::parse_with(data, algorithm).map(|_| ()),
+        ObjectType::Tag => Tag::parse(data).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::oid::{HashAlgorithm, ObjectId};
+    use crate::pack::{vint_to_vec, ObjectType, PackWriter};
+    use crate::testing::{commit, git, pack_objects};
+    use crate::verify::{salvage_pack, verify_pack, verify_pack_file, Problem, VerifyError};
+    use crate::utils::hex;
+
+    /// Replace pack checksum of `raw` after it is modified
+    fn rehash(mut raw: Vec<u8>) -> Vec<u8> {
+        raw.truncate(raw.len() - 20);
+        let checksum = HashAlgorithm::Sha1.digest(&raw);
+        raw.extend_from_slice(checksum.as_bytes());
+        raw
+    }
+
+    #[test]
+    fn test_verify_pack() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path().join("work");
+        std::fs::create_dir(&work).unwrap();
+        git(&work, &["init", "-q"]);
+        let content: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        for i in 0..4 {
+            commit(&work, &[("a.txt", &format!("{}{}\n", content, i))], &format!("Commit {}", i));
+        }
+        let raw = pack_objects(&work, "HEAD");
+        let report = verify_pack(&mut Cursor::new(&raw), HashAlgorithm::Sha1).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.entries.len(), report.count as usize);
+        assert!(!report.chain_lengths().is_empty());
+
+        // compare with `git verify-pack -v`, whose lines are
+        // id type size size-in-pack offset [depth base-id]
+        std::fs::write(dir.path().join("test.pack"), &raw).unwrap();
+        git(dir.path(), &["index-pack", "test.pack"]);
+        let output = git(dir.path(), &["verify-pack", "-v", "test.pack"]);
+        let mut expected = 0;
+        for line in output.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 5 || fields[0].len() != 40 {
+                continue;
+            }
+            expected += 1;
+            let offset: usize = fields[4].parse().unwrap();
+            let entry = report.entries.iter().find(|e| e.offset == offset).unwrap();
+            assert_eq!(entry.id.map(|id| hex(&id)).as_deref(), Some(fields[0]));
+            assert_eq!(entry.object_type.name(), fields[1]);
+            assert_eq!(entry.packed_size.to_string(), fields[3]);
+            assert_eq!(entry.depth, fields.get(5).map_or(0, |d| d.parse().unwrap()));
+        }
+        assert_eq!(expected, report.entries.len());
+        // SAFETY: the file is not changed by the test while it is mapped
+        let mapped = unsafe { verify_pack_file(dir.path().join("test.pack"), HashAlgorithm::Sha1) }.unwrap();
+        assert_eq!(mapped.entries, report.entries);
+
+        // trailing garbage
+        let mut garbage = raw.clone();
+        garbage.extend_from_slice(b"garbage");
+        let report = verify_pack(&mut Cursor::new(&garbage), HashAlgorithm::Sha1).unwrap();
+        assert_eq!(report.problems, vec![Problem::TrailingData(7)]);
+
+        // header with wrong object counts
+        for (count, found) in [(report.count + 1, report.count), (report.count - 1, report.count), (u32::MAX, report.count)].iter() {
+            let mut wrong = raw.clone();
+            wrong[8..12].copy_from_slice(&count.to_be_bytes());
+            let report = verify_pack(&mut Cursor::new(rehash(wrong)), HashAlgorithm::Sha1).unwrap();
+            assert_eq!(report.problems, vec![Problem::CountMismatch { expected: *count, found: *found }]);
+            assert_eq!(report.entries.len(), *found as usize);
+        }
+
+        // corrupted data of the last entry
+        let mut corrupted = raw.clone();
+        let len = corrupted.len();
+        corrupted[len - 22] ^= 0xff;
+        let report = verify_pack(&mut Cursor::new(&corrupted), HashAlgorithm::Sha1).unwrap();
+        assert!(matches!(report.problems[0], Problem::InvalidEntry(_)));
+        assert!(matches!(report.problems[1], Problem::ChecksumMismatch { .. }));
+
+        // partially received pack, cut in its last entry
+        let salvaged = salvage_pack(&raw[..raw.len() - 22], HashAlgorithm::Sha1).unwrap();
+        let report = verify_pack(&mut Cursor::new(&salvaged), HashAlgorithm::Sha1).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.count as usize, mapped.entries.len() - 1);
+        assert_eq!(salvage_pack(&raw, HashAlgorithm::Sha1).unwrap(), raw);
+        assert_eq!(salvage_pack(&raw[..14], HashAlgorithm::Sha1), None);
+
+        assert!(matches!(verify_pack(&mut Cursor::new(b"PACK\0\0\0\x04\0\0\0\0"), HashAlgorithm::Sha1), Err(VerifyError::UnsupportedVersion(4))));
+    }
+
+    #[test]
+    fn test_verify_objects() {
+        let mut writer = PackWriter::new();
+        let commit = writer.add(ObjectType::Commit, b"tree 1234\n\nmessage\n".to_vec());
+        writer.add(ObjectType::Blob, b"blob".to_vec());
+        let mut raw = Vec::new();
+        writer.write(&mut raw).unwrap();
+
+        // append a REF_DELTA against a missing object
+        raw.truncate(raw.len() - 20);
+        raw[8..12].copy_from_slice(&3u32.to_be_bytes());
+        let offset = raw.len();
+        let delta = b"\x04\x04\x90\x04";
+        raw.extend_from_slice(&vint_to_vec(ObjectType::RefDelta(ObjectId::Sha1([0; 20])).code(), delta.len()));
+        raw.extend_from_slice(&[1; 20]);
+        raw.extend_from_slice(&miniz_oxide::deflate::compress_to_vec_zlib(delta, 6));
+        raw.extend_from_slice(&[0; 20]);
+        let raw = rehash(raw);
+
+        let report = verify_pack(&mut Cursor::new(&raw), HashAlgorithm::Sha1).unwrap();
+        assert_eq!(report.problems.len(), 2);
+        assert!(matches!(&report.problems[0], Problem::InvalidObject(id, _) if *id == commit));
+        assert_eq!(report.problems[1], Problem::MissingBase(offset));
+        assert_eq!(report.entries[2].id, None);
+        assert_eq!(report.entries[2].object_type, ObjectType::RefDelta(ObjectId::Sha1([1; 20])));
+    }
+}
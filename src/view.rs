@@ -0,0 +1,223 @@
+//! Read files and directories of a commit by path.
+
+use std::borrow::Cow;
+use thiserror::Error;
+use crate::checkout::{resolve_tree, CheckoutError};
+use crate::object::{ObjectError, Tree, TreeEntry};
+use crate::oid::ObjectId;
+use crate::pack::ObjectStore;
+use crate::utils::hex;
+
+#[derive(Debug, Error)]
+pub enum ViewError {
+    #[error("path {0} not found")]
+    NotFound(String),
+    #[error("path {0} is not a directory")]
+    NotADirectory(String),
+    #[error("path {0} is not a file")]
+    NotAFile(String),
+    #[error("object {0} not found")]
+    MissingObject(String),
+
+    #[error(transparent)]
+    CheckoutError(#[from] CheckoutError),
+    #[error(transparent)]
+    ObjectError(#[from] ObjectError),
+}
+
+/// Read-only view of the tree of a commit
+///
+/// Paths are separated by `/`, and the root directory is `""`.
+///
+/// ```no_run
+/// use anni_fetch::view::TreeView;
+/// # fn example(pack: &anni_fetch::Pack, head: &anni_fetch::ObjectId) -> Result<(), Box<dyn std::error::Error>> {
+/// let view = TreeView::new(pack, head)?;
+/// let album = view.read("album/xxx.toml")?;
+/// for file in view.files() {
+///     let (path, entry) = file?;
+///     println!("{:o} {}", entry.mode, path);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct TreeView<'a, S: ObjectStore + ?Sized> {
+    store: &'a S,
+    tree: ObjectId,
+}
+
+impl<'a, S: ObjectStore + ?Sized> TreeView<'a, S> {
+    /// Create a view of commit, tag or tree `id`
+    pub fn new(store: &'a S, id: &ObjectId) -> Result<Self, ViewError> {
+        Ok(Self {
+            store,
+            tree: resolve_tree(store, id)?,
+        })
+    }
+
+    /// Id of the root tree
+    pub fn tree_id(&self) -> &ObjectId {
+        &self.tree
+    }
+
+    /// Find the tree entry at `path`, or `None` if it does not exist
+    pub fn entry(&self, path: &str) -> Result<Option<TreeEntry>, ViewError> {
+        let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+        let mut tree = self.tree(&self.tree)?;
+        let mut walked = String::new();
+        while let Some(name) = components.next() {
+            let entry = match tree.entries.into_iter().find(|e| e.name == name.as_bytes()) {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            if components.peek().is_none() {
+                return Ok(Some(entry));
+            }
+
+            walked.push_str(name);
+            if !entry.is_tree() {
+                return Err(ViewError::NotADirectory(walked));
+            }
+            walked.push('/');
+            tree = self.tree(&entry.id)?;
+        }
+        // path is root
+        Ok(None)
+    }
+
+    /// Read content of the file at `path`
+    ///
+    /// For a symlink, the content is its target.
+    /// The content is borrowed from stores keeping objects in memory.
+    pub fn read(&self, path: &str) -> Result<Cow<'a, [u8]>, ViewError> {
+        let entry = self.entry(path)?.ok_or_else(|| ViewError::NotFound(path.to_owned()))?;
+        if !entry.is_blob() {
+            return Err(ViewError::NotAFile(path.to_owned()));
+        }
+        let blob = self.store.object(&entry.id).ok_or_else(|| ViewError::MissingObject(hex(&entry.id)))?;
+        Ok(match blob {
+            Cow::Borrowed(blob) => Cow::Borrowed(&blob.data),
+            Cow::Owned(blob) => Cow::Owned(blob.data),
+        })
+    }
+
+    /// List entries of the directory at `path`
+    pub fn list(&self, path: &str) -> Result<Vec<TreeEntry>, ViewError> {
+        if path.split('/').all(|c| c.is_empty()) {
+            return Ok(self.tree(&self.tree)?.entries);
+        }
+        match self.entry(path)? {
+            Some(entry) if entry.is_tree() => Ok(self.tree(&entry.id)?.entries),
+            Some(_) => Err(ViewError::NotADirectory(path.to_owned())),
+            None => Err(ViewError::NotFound(path.to_owned())),
+        }
+    }
+
+    /// Iterate all files recursively as `(path, entry)`, in tree order
+    ///
+    /// Directories are not yielded, but submodules are.
+    pub fn files(&self) -> Files<'a, S> {
+        Files {
+            store: self.store,
+            stack: vec![(String::new(), Dir::Unread(self.tree))],
+        }
+    }
+
+    fn tree(&self, id: &ObjectId) -> Result<Tree, ViewError> {
+        read_tree(self.store, id)
+    }
+}
+
+fn read_tree<S: ObjectStore + ?Sized>(store: &S, id: &ObjectId) -> Result<Tree, ViewError> {
+    let object = store.object(id).ok_or_else(|| ViewError::MissingObject(hex(id)))?;
+    Ok(object.tree()?)
+}
+
+/// Iterator returned by [TreeView::files]
+pub struct Files<'a, S: ObjectStore + ?Sized> {
+    store: &'a S,
+    /// Directories being walked, with their path prefix
+    stack: Vec<(String, Dir)>,
+}
+
+enum Dir {
+    Unread(ObjectId),
+    /// Remaining entries in reverse order
+    Entries(Vec<TreeEntry>),
+}
+
+impl<'a, S: ObjectStore + ?Sized> Iterator for Files<'a, S> {
+    type Item = Result<(String, TreeEntry), ViewError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (prefix, entries) = self.stack.last_mut()?;
+            let entries = match entries {
+                Dir::Entries(entries) => entries,
+                Dir::Unread(id) => match read_tree(self.store, id) {
+                    Ok(tree) => {
+                        let mut entries = tree.entries;
+                        entries.reverse();
+                        *self.stack.last_mut().unwrap() = (prefix.clone(), Dir::Entries(entries));
+                        continue;
+                    }
+                    Err(e) => {
+                        self.stack.pop();
+                        return Some(Err(e));
+                    }
+                },
+            };
+
+            let entry = match entries.pop() {
+                Some(entry) => entry,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            let path = format!("{}{}", prefix, String::from_utf8_lossy(&entry.name));
+            if entry.is_tree() {
+                self.stack.push((format!("{}/", path), Dir::Unread(entry.id)));
+            } else {
+                return Some(Ok((path, entry)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::view::{TreeView, ViewError};
+    use crate::testing::{fixture, git, pack_objects};
+    use crate::utils::unhex;
+    use crate::Pack;
+
+    #[test]
+    fn test_view() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path());
+        let repo = dir.path().join("repo.git");
+        let pack = Pack::from_reader(&mut Cursor::new(pack_objects(&repo, "HEAD"))).unwrap();
+        let head = unhex(git(&repo, &["rev-parse", "HEAD"]).trim()).unwrap();
+
+        let view = TreeView::new(&pack, &head).unwrap();
+        assert_eq!(view.read("album/b.toml").unwrap(), &b"title = \"b\"\n"[..]);
+        assert_eq!(view.read("/album//a.toml").unwrap(), &b"title = \"a\"\n"[..]);
+        assert!(matches!(view.read("album"), Err(ViewError::NotAFile(_))));
+        assert!(matches!(view.read("album/c.toml"), Err(ViewError::NotFound(_))));
+        assert!(matches!(view.read("README.md/a"), Err(ViewError::NotADirectory(p)) if p == "README.md"));
+
+        let names: Vec<_> = view.list("").unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec![b"README.md".to_vec(), b"album".to_vec()]);
+        let album = view.list("album").unwrap();
+        assert_eq!(album.len(), 2);
+        assert!(album.iter().all(|e| e.is_blob()));
+        assert!(matches!(view.list("README.md"), Err(ViewError::NotADirectory(_))));
+
+        let files: Vec<_> = view.files().map(|f| f.unwrap().0).collect();
+        assert_eq!(files, vec!["README.md", "album/a.toml", "album/b.toml"]);
+        let entry = view.entry("album/a.toml").unwrap().unwrap();
+        assert_eq!(entry.id, unhex(git(&repo, &["rev-parse", "HEAD:album/a.toml"]).trim()).unwrap());
+    }
+}